@@ -0,0 +1,9 @@
+; Exits with code 7 and the carry flag set, exec.asm runs it
+%include "mz.inc"
+
+        mz_header
+start:
+        stc
+        mov ax, 0x4c07
+        int 0x21
+        mz_end
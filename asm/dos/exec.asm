@@ -0,0 +1,44 @@
+; Shrinks itself, runs CHILD.EXE and exits with the exit code of the child,
+; or with FFh when EXEC returns with the carry flag set
+%include "mz.inc"
+
+        mz_header
+start:
+        mov ax, cs
+        mov ds, ax
+        ; ES holds the PSP at entry, keep the PSP and the program
+        mov bx, 0x10 + program_paragraphs
+        mov ah, 0x4a
+        int 0x21
+        mov [params + 4], cs
+        mov [params + 8], cs
+        mov [params + 12], cs
+        push cs
+        pop es
+        mov bx, params
+        mov dx, child
+        mov ax, 0x4b00
+        int 0x21
+returned:
+        jc failed
+        mov ah, 0x4d            ; get the return code of the child
+        int 0x21
+        mov ah, 0x4c
+        int 0x21
+failed:
+        mov ax, 0x4cff
+        int 0x21
+
+child:
+        db "CHILD.EXE", 0
+tail:
+        db 0, 13
+fcb:
+        times 16 db 0
+; EXEC parameter block, the segments are filled in at runtime
+params:
+        dw 0                    ; the environment of the parent
+        dw tail, 0
+        dw fcb, 0
+        dw fcb, 0
+        mz_end
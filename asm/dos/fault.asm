@@ -0,0 +1,30 @@
+; Installs an INT 06h handler, runs an invalid opcode and then reads memory
+; above the HMA. The handler skips the invalid opcode and sets BX to 1
+%include "mz.inc"
+
+        mz_header
+start:
+        mov ax, cs
+        mov ds, ax
+        mov dx, invalid_opcode
+        mov ax, 0x2506          ; set the INT 06h vector to DS:DX
+        int 0x21
+        xor bx, bx
+fault:
+        ud2
+        ; Real mode doesn't check the offset against the segment limit, this
+        ; reads 2 MiB above DS
+        mov esi, 0x200000
+        mov ax, [esi]
+        mov ax, 0x4c00
+        int 0x21
+
+; Returns past the two byte UD2 instead of running it again
+invalid_opcode:
+        push bp
+        mov bp, sp
+        add word [bp + 2], 2
+        pop bp
+        mov bx, 1
+        iret
+        mz_end
@@ -0,0 +1,26 @@
+; Prints a line from a subroutine and exits with the DOS major version as its
+; exit code
+%include "mz.inc"
+
+        mz_header
+start:
+        mov ax, cs
+        mov ds, ax
+        call print
+        mov ah, 0x30            ; get DOS version, the major version in AL
+        int 0x21
+        mov ah, 0x4c
+        int 0x21
+
+print:
+        mov ah, 0x40
+        mov bx, 1               ; standard output
+        mov cx, message_len
+        mov dx, message
+        int 0x21
+        ret
+
+message:
+        db "Hello from DOS", 13, 10
+message_len equ $ - message
+        mz_end
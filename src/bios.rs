@@ -0,0 +1,171 @@
+use chrono::{NaiveTime, Timelike};
+
+/// Linear address of the BIOS data area, segment 0040h
+pub const BDA_ADDR: u64 = 0x400;
+
+/// Conventional memory reported by INT 12h and the CMOS
+pub const CONVENTIONAL_MEMORY_KB: u16 = 640;
+
+/// Floppy installed and initial video mode 80x25 color
+const EQUIPMENT: u16 = 0x0021;
+
+/// Keyboard buffer offsets are relative to the BDA segment
+const KEYBOARD_BUFFER_START: u16 = 0x1e;
+const KEYBOARD_BUFFER_END: u16 = 0x3e;
+
+/// BIOS data area at 0040:0000, the BIOS keeps its state here and
+/// plenty of programs read it directly instead of asking through interrupts
+#[repr(C)]
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct BiosDataArea {
+    com_ports: [u16; 4],
+    lpt_ports: [u16; 3],
+    /// Segment of the extended BIOS data area, we don't have one
+    ebda_segment: u16,
+    /// Same word INT 11h returns
+    pub equipment: u16,
+    post_flag: u8,
+    /// Same word INT 12h returns
+    pub memory_size: u16,
+    resv: [u8; 2],
+    keyboard_flags: [u8; 2],
+    alt_numpad: u8,
+    /// Offset of the next character in the keyboard buffer
+    keyboard_head: u16,
+    /// Offset where the next key press is stored, buffer is empty when head == tail
+    keyboard_tail: u16,
+    keyboard_buffer: [u8; 32],
+    floppy_recalibrate: u8,
+    floppy_motor: u8,
+    floppy_motor_timeout: u8,
+    floppy_status: u8,
+    floppy_controller: [u8; 7],
+    video_mode: u8,
+    video_columns: u16,
+    video_page_size: u16,
+    video_page_offset: u16,
+    cursor_pos: [u16; 8],
+    cursor_shape: u16,
+    video_page: u8,
+    crtc_port: u16,
+    video_mode_select: u8,
+    video_palette: u8,
+    post_reset: [u8; 5],
+    /// Timer ticks since midnight, 18.2 per second
+    pub tick_count: u32,
+    midnight_flag: u8,
+    break_flag: u8,
+    reset_flag: u16,
+    disk_status: u8,
+    disk_count: u8,
+    disk_control: u8,
+    disk_port: u8,
+    lpt_timeouts: [u8; 4],
+    com_timeouts: [u8; 4],
+    keyboard_buffer_start: u16,
+    keyboard_buffer_end: u16,
+    video_rows: u8,
+    char_height: u16,
+    ega_misc: u8,
+    ega_switches: u8,
+    vga_flags: u8,
+    display_combination: u8,
+}
+
+/// Timer ticks since midnight, the PIT runs at 1193182 Hz and ticks every 65536 counts
+pub fn ticks_since_midnight(time: NaiveTime) -> u32 {
+    (time.num_seconds_from_midnight() as u64 * 1_193_182 / 65536) as u32
+}
+
+impl BiosDataArea {
+    pub fn new(time: NaiveTime) -> Self {
+        Self {
+            com_ports: [0; 4],
+            lpt_ports: [0; 3],
+            ebda_segment: 0,
+            equipment: EQUIPMENT,
+            post_flag: 0,
+            memory_size: CONVENTIONAL_MEMORY_KB,
+            resv: [0; 2],
+            keyboard_flags: [0; 2],
+            alt_numpad: 0,
+            keyboard_head: KEYBOARD_BUFFER_START,
+            keyboard_tail: KEYBOARD_BUFFER_START,
+            keyboard_buffer: [0; 32],
+            floppy_recalibrate: 0,
+            floppy_motor: 0,
+            floppy_motor_timeout: 0,
+            floppy_status: 0,
+            floppy_controller: [0; 7],
+            video_mode: 0x03,
+            video_columns: 80,
+            video_page_size: 0x1000,
+            video_page_offset: 0,
+            cursor_pos: [0; 8],
+            cursor_shape: 0x0607,
+            video_page: 0,
+            crtc_port: 0x3d4,
+            video_mode_select: 0x29,
+            video_palette: 0x30,
+            post_reset: [0; 5],
+            tick_count: ticks_since_midnight(time),
+            midnight_flag: 0,
+            break_flag: 0,
+            reset_flag: 0,
+            disk_status: 0,
+            disk_count: 0,
+            disk_control: 0,
+            disk_port: 0,
+            lpt_timeouts: [0x14; 4],
+            com_timeouts: [0x01; 4],
+            keyboard_buffer_start: KEYBOARD_BUFFER_START,
+            keyboard_buffer_end: KEYBOARD_BUFFER_END,
+            video_rows: 24,
+            char_height: 16,
+            ega_misc: 0x60,
+            ega_switches: 0x09,
+            vga_flags: 0x11,
+            display_combination: 0x0b,
+        }
+    }
+}
+
+impl<'a> From<&'a BiosDataArea> for &'a [u8] {
+    fn from(value: &'a BiosDataArea) -> &'a [u8] {
+        unsafe {
+            ::core::slice::from_raw_parts(
+                (value as *const BiosDataArea) as *const u8,
+                ::core::mem::size_of::<BiosDataArea>(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::offset_of;
+
+    use chrono::NaiveTime;
+
+    use crate::bios::BiosDataArea;
+
+    #[test]
+    fn bda_layout() {
+        assert_eq!(offset_of!(BiosDataArea, equipment), 0x10);
+        assert_eq!(offset_of!(BiosDataArea, memory_size), 0x13);
+        assert_eq!(offset_of!(BiosDataArea, keyboard_head), 0x1a);
+        assert_eq!(offset_of!(BiosDataArea, video_mode), 0x49);
+        assert_eq!(offset_of!(BiosDataArea, tick_count), 0x6c);
+        assert_eq!(offset_of!(BiosDataArea, keyboard_buffer_start), 0x80);
+        assert_eq!(offset_of!(BiosDataArea, video_rows), 0x84);
+        assert_eq!(size_of::<BiosDataArea>(), 0x8b);
+
+        let bda = BiosDataArea::new(NaiveTime::from_hms_opt(1, 0, 0).unwrap());
+        let bytes: &[u8] = (&bda).into();
+        assert_eq!(&bytes[0x10..0x12], &[0x21, 0x00]);
+        assert_eq!(&bytes[0x13..0x15], &[0x80, 0x02]);
+        assert_eq!(&bytes[0x4a..0x4c], &[80, 0]);
+        assert_eq!(&bytes[0x6c..0x70], &65543u32.to_le_bytes());
+    }
+}
@@ -1,5 +1,8 @@
+use chrono::NaiveDateTime;
 use clap::Parser;
 
+use unicorn_debugger::clock::parse_fixed_time;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct CliArgs {
@@ -11,15 +14,59 @@ pub struct CliArgs {
     #[arg(short, long)]
     pub debug: bool,
 
+    /// Host directory mounted as drive C:, defaults to the directory of the program
+    #[arg(short = 'c', long)]
+    pub drive: Option<String>,
+
+    /// Freeze the guest clock at this time for reproducible runs, YYYY-MM-DD[THH:MM:SS]
+    #[arg(long, value_parser = parse_fixed_time)]
+    pub fixed_time: Option<NaiveDateTime>,
+
+    /// Run the LZEXE, PKLITE or EXEPACK stub until it reaches the original entry point
+    /// and write the unpacked executable to this file
+    #[arg(long, value_name = "OUTPUT")]
+    pub unpack: Option<String>,
+
+    /// Run debug scripts as tests and report their assertions, each script runs
+    /// the program named in a '# program: NAME.EXE' line or the .EXE with the same name
+    #[arg(long, num_args = 1.., value_name = "SCRIPT")]
+    pub test: Vec<String>,
+
+    /// Take JSON-RPC 2.0 requests on stdin and answer on stdout, one per line
+    #[arg(long)]
+    pub rpc: bool,
+
+    /// Take JSON-RPC 2.0 requests on this Unix socket instead of stdin
+    #[arg(long, value_name = "PATH")]
+    pub rpc_socket: Option<String>,
+
+    /// Speak the Debug Adapter Protocol on stdin and stdout, the program comes
+    /// with the launch request
+    #[arg(long)]
+    pub dap: bool,
+
+    /// Log every interrupt call with its decoded arguments and result, like strace
+    #[arg(long)]
+    pub log_syscalls: bool,
+
+    /// Run the program's INT 06h handler on invalid opcodes like a 286 does
+    #[arg(long)]
+    pub deliver_int6: bool,
+
     /// Enable verbose output
     #[arg(short, long)]
     pub verbose: bool,
 
     /// Path to executable MsDos EXE
-    pub program_path: String,
+    #[arg(required_unless_present_any = ["test", "rpc", "rpc_socket", "dap"])]
+    pub program_path: Option<String>,
 }
 
 impl CliArgs {
+    pub fn rpc_mode(&self) -> bool {
+        self.rpc || self.rpc_socket.is_some()
+    }
+
     pub fn debug_mode(&self) -> bool {
         self.debug || self.debug_file.is_some()
     }
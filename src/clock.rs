@@ -0,0 +1,111 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta};
+
+/// The time as the guest sees it, either following the host clock or frozen
+/// at a fixed point so runs are reproducible. Programs can set the date and
+/// time, that only moves the guest clock.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    /// Frozen time, None follows the host local time
+    fixed: Option<NaiveDateTime>,
+    /// Adjustment made by the guest setting the date or time
+    offset: TimeDelta,
+}
+
+impl Clock {
+    pub fn host() -> Self {
+        Self {
+            fixed: None,
+            offset: TimeDelta::zero(),
+        }
+    }
+
+    pub fn fixed(time: NaiveDateTime) -> Self {
+        Self {
+            fixed: Some(time),
+            offset: TimeDelta::zero(),
+        }
+    }
+
+    pub fn now(&self) -> NaiveDateTime {
+        let base = self.fixed.unwrap_or_else(|| Local::now().naive_local());
+        base + self.offset
+    }
+
+    /// INT 21h/2Bh, returns false for dates DOS can't represent
+    pub fn set_date(&mut self, year: i32, month: u32, day: u32) -> bool {
+        if !(1980..=2099).contains(&year) {
+            return false;
+        }
+        let Some(date) = NaiveDate::from_ymd_opt(year, month, day) else {
+            return false;
+        };
+
+        let now = self.now();
+        self.offset += date.and_time(now.time()) - now;
+        true
+    }
+
+    /// INT 21h/2Dh, returns false for invalid times
+    pub fn set_time(&mut self, hour: u32, minute: u32, second: u32, hundredths: u32) -> bool {
+        if hundredths > 99 {
+            return false;
+        }
+        let Some(time) = NaiveTime::from_hms_milli_opt(hour, minute, second, hundredths * 10)
+        else {
+            return false;
+        };
+
+        let now = self.now();
+        self.offset += now.date().and_time(time) - now;
+        true
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::host()
+    }
+}
+
+/// Parse the `--fixed-time` argument, either `YYYY-MM-DD` or `YYYY-MM-DDTHH:MM:SS`
+pub fn parse_fixed_time(value: &str) -> Result<NaiveDateTime, String> {
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return Ok(time);
+        }
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN))
+        .map_err(|_| format!("expected YYYY-MM-DD or YYYY-MM-DDTHH:MM:SS, got '{value}'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Datelike, Timelike};
+
+    use crate::clock::{Clock, parse_fixed_time};
+
+    #[test]
+    fn fixed_clock() {
+        let mut clock = Clock::fixed(parse_fixed_time("1994-03-01T12:30:00").unwrap());
+        assert_eq!(clock.now().year(), 1994);
+        assert_eq!(clock.now().hour(), 12);
+
+        assert!(clock.set_date(2001, 12, 24));
+        assert_eq!(clock.now().to_string(), "2001-12-24 12:30:00");
+        assert!(clock.set_time(8, 15, 30, 50));
+        assert_eq!(clock.now().to_string(), "2001-12-24 08:15:30.500");
+
+        assert!(!clock.set_date(1979, 1, 1));
+        assert!(!clock.set_date(1990, 2, 30));
+        assert!(!clock.set_time(24, 0, 0, 0));
+        assert_eq!(clock.now().to_string(), "2001-12-24 08:15:30.500");
+
+        assert_eq!(
+            parse_fixed_time("1990-01-02").unwrap().to_string(),
+            "1990-01-02 00:00:00"
+        );
+        assert!(parse_fixed_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,828 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::{Value, json};
+
+use crate::{
+    engine::{Cpu, Engine, FarPointer, MEMORY_SIZE},
+    error::Error,
+    script::{Env, Expr},
+};
+
+/// DOS runs one program at a time, so there's one thread
+const THREAD_ID: u64 = 1;
+/// variablesReference of the scopes
+const REGISTERS_REF: u64 = 1;
+const FLAGS_REF: u64 = 2;
+
+/// Bits of FLAGS shown in the Flags scope
+const FLAGS: [(&str, u32); 9] = [
+    ("cf", 0),
+    ("pf", 2),
+    ("af", 4),
+    ("zf", 6),
+    ("sf", 7),
+    ("tf", 8),
+    ("if", 9),
+    ("df", 10),
+    ("of", 11),
+];
+
+/// Read one message, None once the client has closed the stream
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("Content-Length")
+        {
+            len = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(len) = len else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message without Content-Length",
+        ));
+    };
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                text.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+/// Linear address from a memory or instruction reference, "0x1234" or plain hex
+fn parse_reference(text: &str) -> Option<u64> {
+    let hex = text.strip_prefix("0x").unwrap_or(text);
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn reference(addr: u64) -> String {
+    format!("0x{addr:x}")
+}
+
+/// Function breakpoints and evaluate take debugger expressions like `cs:1a0`
+struct EngineEnv<'e, 'a>(&'e Engine<'a>);
+
+impl Env for EngineEnv<'_, '_> {
+    fn cpu(&self) -> Result<Cpu, Error> {
+        self.0.read_cpu()
+    }
+
+    fn read_u16(&self, addr: u64) -> Result<u16, Error> {
+        self.0.read_mem(addr)
+    }
+
+    fn variable(&self, _name: &str) -> Option<i64> {
+        None
+    }
+}
+
+fn evaluate(engine: &Engine, text: &str) -> Result<i64, String> {
+    let expr = Expr::parse(text).map_err(|(_, message)| message)?;
+    expr.eval(&EngineEnv(engine)).map_err(|err| err.to_string())
+}
+
+#[derive(Clone, Copy)]
+enum Resume {
+    Continue,
+    Step,
+    StepOver,
+    StepOut,
+}
+
+/// Debug Adapter Protocol server, requests come in one at a time and
+/// everything runs to the next stop before the response goes out
+pub struct Server<'a, L, W> {
+    load: L,
+    writer: W,
+    engine: Option<Engine<'a>>,
+    seq: u64,
+    /// Events to send after the response to the current request
+    events: Vec<(&'static str, Value)>,
+    stop_on_entry: bool,
+    configured: bool,
+    started: bool,
+    instruction_breaks: Vec<Option<u64>>,
+    function_breaks: Vec<String>,
+    /// Addresses we set in the engine, replaced on every breakpoint request
+    breaks: Vec<u64>,
+    /// How much of the program output went out in output events
+    output_sent: usize,
+    done: bool,
+}
+
+impl<'a, L, W> Server<'a, L, W>
+where
+    L: FnMut(&str) -> Result<Engine<'a>, Error>,
+    W: Write,
+{
+    /// `load` runs for the launch request
+    pub fn new(load: L, writer: W) -> Self {
+        Self {
+            load,
+            writer,
+            engine: None,
+            seq: 0,
+            events: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            started: false,
+            instruction_breaks: Vec::new(),
+            function_breaks: Vec::new(),
+            breaks: Vec::new(),
+            output_sent: 0,
+            done: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        write_message(&mut self.writer, &message)
+    }
+
+    fn engine(&mut self) -> Result<&mut Engine<'a>, String> {
+        self.engine
+            .as_mut()
+            .ok_or_else(|| "no program launched".to_string())
+    }
+
+    /// Program output and emulator messages become output events
+    fn queue_output(&mut self) {
+        let output_sent = self.output_sent;
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        let output = String::from_utf8_lossy(&engine.output()[output_sent..]).into_owned();
+        self.output_sent = engine.output().len();
+        let messages = engine.take_messages();
+        if !output.is_empty() {
+            self.events
+                .push(("output", json!({ "category": "stdout", "output": output })));
+        }
+        for message in messages {
+            self.events.push((
+                "output",
+                json!({ "category": "console", "output": message + "\n" }),
+            ));
+        }
+    }
+
+    /// Addresses of the function breakpoints, they are evaluated when set
+    fn resolve_functions(&self) -> Vec<Result<u64, String>> {
+        self.function_breaks
+            .iter()
+            .map(|name| match &self.engine {
+                Some(engine) => evaluate(engine, name).map(|addr| addr as u64),
+                None => Err("set when the program is launched".into()),
+            })
+            .collect()
+    }
+
+    /// Replace the breakpoints in the engine with the ones the client asked for
+    fn apply_breakpoints(&mut self) {
+        let functions = self.resolve_functions();
+        let Some(engine) = self.engine.as_mut() else {
+            return;
+        };
+        for addr in self.breaks.drain(..) {
+            engine.remove_break(addr);
+        }
+        let wanted = self.instruction_breaks.iter().flatten().copied();
+        let functions = functions
+            .iter()
+            .filter_map(|addr| addr.as_ref().ok())
+            .copied();
+        for addr in wanted.chain(functions) {
+            engine.add_break(addr);
+            self.breaks.push(addr);
+        }
+    }
+
+    /// Run once the program is launched and the client is done configuring
+    fn start_if_ready(&mut self) -> Result<(), String> {
+        if self.started || !self.configured || self.engine.is_none() {
+            return Ok(());
+        }
+        self.started = true;
+        if self.stop_on_entry {
+            self.events.push((
+                "stopped",
+                json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            ));
+            return Ok(());
+        }
+        self.resume(Resume::Continue)
+    }
+
+    fn resume(&mut self, how: Resume) -> Result<(), String> {
+        let engine = self.engine()?;
+        if !engine.exited() {
+            let result = match how {
+                Resume::Continue => engine.cont(),
+                Resume::Step => engine.step(),
+                Resume::StepOver => engine.step_over(),
+                Resume::StepOut => engine.finish(),
+            };
+            result.map_err(|err| err.to_string())?;
+        }
+
+        self.queue_output();
+        let engine = self.engine()?;
+        if engine.exited() {
+            let code = engine.exit_status();
+            self.events.push(("exited", json!({ "exitCode": code })));
+            self.events.push(("terminated", json!({})));
+        } else {
+            let reason = match how {
+                Resume::Continue => "breakpoint",
+                Resume::Step | Resume::StepOver | Resume::StepOut => "step",
+            };
+            self.events.push((
+                "stopped",
+                json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }),
+            ));
+        }
+        Ok(())
+    }
+
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let engine = self.engine()?;
+        let ip = FarPointer::read_engine(engine.engine()).map_err(|err| err.to_string())?;
+        let name = engine
+            .program()
+            .path()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let calls = engine.call_stack();
+
+        // Innermost first, each frame is in the function the call below it went to
+        let locations = std::iter::once(ip).chain(calls.iter().rev().map(|frame| frame.return_to));
+        let functions = calls
+            .iter()
+            .rev()
+            .map(|frame| frame.function)
+            .chain(std::iter::once(None));
+        let frames: Vec<Value> = locations
+            .zip(functions)
+            .enumerate()
+            .map(|(id, (at, function))| {
+                let name = match function {
+                    Some(function) => format!("{function}"),
+                    None => name.clone(),
+                };
+                json!({
+                    "id": id,
+                    "name": format!("{name} [{at}]"),
+                    "line": 0,
+                    "column": 0,
+                    "instructionPointerReference": reference(at.address()),
+                })
+            })
+            .collect();
+        let total = frames.len();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables(&mut self, reference: u64) -> Result<Value, String> {
+        let engine = self.engine()?;
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => {
+                let cpu = engine.read_cpu().map_err(|err| err.to_string())?;
+                Cpu::REGISTERS
+                    .iter()
+                    .map(|name| {
+                        let value = cpu.register(name).unwrap_or_default();
+                        json!({ "name": name, "value": format!("{value:04x}"), "variablesReference": 0 })
+                    })
+                    .collect()
+            }
+            FLAGS_REF => {
+                let flags = engine.flags().map_err(|err| err.to_string())?;
+                FLAGS
+                    .iter()
+                    .map(|(name, bit)| {
+                        let value = flags >> bit & 1;
+                        json!({ "name": name, "value": value.to_string(), "variablesReference": 0 })
+                    })
+                    .collect()
+            }
+            _ => return Err(format!("no variables for reference {reference}")),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn disassemble(&mut self, args: &Value) -> Result<Value, String> {
+        let addr = args["memoryReference"]
+            .as_str()
+            .and_then(parse_reference)
+            .ok_or("bad memoryReference")?;
+        let addr = addr.saturating_add_signed(args["offset"].as_i64().unwrap_or(0));
+        let skip = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        // Every instruction takes at least a byte of memory
+        if count > MEMORY_SIZE || skip.unsigned_abs() > MEMORY_SIZE {
+            return Err(format!("memory ends at 0x{MEMORY_SIZE:x}"));
+        }
+        let count = count as usize;
+        let engine = self.engine()?;
+
+        // Instructions before the address can't be decoded backwards, decode from
+        // far enough before it and keep the ones that end up lined up with it
+        let mut before = Vec::new();
+        if skip < 0 {
+            let wanted = skip.unsigned_abs() as usize;
+            let mut at = addr.saturating_sub(wanted as u64 * 15);
+            while at < addr {
+                let (text, len) = engine.disassemble(at).map_err(|err| err.to_string())?;
+                before.push((at, text));
+                at += len as u64;
+            }
+            let start = before.len().saturating_sub(wanted);
+            before.drain(..start);
+            while before.len() < wanted {
+                before.insert(0, (0, "??".to_string()));
+            }
+        }
+
+        let mut instructions: Vec<Value> = before
+            .into_iter()
+            .map(|(at, text)| json!({ "address": reference(at), "instruction": text }))
+            .collect();
+        let mut at = addr;
+        for _ in 0..skip.max(0) {
+            at += engine.disassemble(at).map_err(|err| err.to_string())?.1 as u64;
+        }
+        while instructions.len() < count {
+            let (text, len) = engine.disassemble(at).map_err(|err| err.to_string())?;
+            let bytes = engine.read_memory(at, len).map_err(|err| err.to_string())?;
+            let bytes: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            instructions.push(json!({
+                "address": reference(at),
+                "instruction": text,
+                "instructionBytes": bytes,
+            }));
+            at += len as u64;
+        }
+        instructions.truncate(count);
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Result<Value, String> {
+        let addr = args["memoryReference"]
+            .as_str()
+            .and_then(parse_reference)
+            .ok_or("bad memoryReference")?;
+        let addr = addr.saturating_add_signed(args["offset"].as_i64().unwrap_or(0));
+        let count = args["count"].as_u64().unwrap_or(0);
+        if addr.checked_add(count).is_none_or(|end| end > MEMORY_SIZE) {
+            return Err(format!("memory ends at 0x{MEMORY_SIZE:x}"));
+        }
+        let data = self
+            .engine()?
+            .read_memory(addr, count as usize)
+            .map_err(|err| err.to_string())?;
+        Ok(json!({ "address": reference(addr), "data": base64(&data) }))
+    }
+
+    fn call(&mut self, command: &str, args: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                self.events.push(("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsFunctionBreakpoints": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsSteppingGranularity": true,
+                }))
+            }
+            "launch" => {
+                let program = args["program"].as_str().ok_or("launch needs a program")?;
+                let mut engine = (self.load)(program).map_err(|err| err.to_string())?;
+                if let Some(args) = args["args"].as_array() {
+                    // DOS puts the space after the program name in the tail
+                    let tail: String = args
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .flat_map(|arg| [" ", arg])
+                        .collect();
+                    engine
+                        .set_command_tail(&tail)
+                        .map_err(|err| err.to_string())?;
+                }
+                engine.collect_messages();
+                engine.track_calls();
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                self.engine = Some(engine);
+                self.output_sent = 0;
+                self.apply_breakpoints();
+                self.start_if_ready()?;
+                Ok(Value::Null)
+            }
+            "setBreakpoints" => {
+                let count = args["breakpoints"].as_array().map_or(0, Vec::len);
+                let breakpoints = vec![
+                    json!({
+                        "verified": false,
+                        "message": "DOS programs have no source, use function or instruction breakpoints",
+                    });
+                    count
+                ];
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setInstructionBreakpoints" => {
+                let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                self.instruction_breaks = requested
+                    .iter()
+                    .map(|bp| {
+                        let addr = bp["instructionReference"]
+                            .as_str()
+                            .and_then(parse_reference)?;
+                        Some(addr.saturating_add_signed(bp["offset"].as_i64().unwrap_or(0)))
+                    })
+                    .collect();
+                self.apply_breakpoints();
+                let breakpoints: Vec<Value> = self
+                    .instruction_breaks
+                    .iter()
+                    .map(|addr| match addr {
+                        Some(addr) => json!({
+                            "verified": self.engine.is_some(),
+                            "instructionReference": reference(*addr),
+                        }),
+                        None => json!({ "verified": false, "message": "bad instructionReference" }),
+                    })
+                    .collect();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "setFunctionBreakpoints" => {
+                let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+                self.function_breaks = requested
+                    .iter()
+                    .map(|bp| bp["name"].as_str().unwrap_or_default().to_string())
+                    .collect();
+                self.apply_breakpoints();
+                let breakpoints: Vec<Value> = self
+                    .resolve_functions()
+                    .into_iter()
+                    .map(|addr| match addr {
+                        Ok(addr) => json!({
+                            "verified": true,
+                            "instructionReference": reference(addr),
+                        }),
+                        Err(message) => json!({ "verified": false, "message": message }),
+                    })
+                    .collect();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "configurationDone" => {
+                self.configured = true;
+                self.start_if_ready()?;
+                Ok(Value::Null)
+            }
+            "threads" => {
+                let name = match &self.engine {
+                    Some(engine) => engine.program().path().display().to_string(),
+                    None => "DOS".into(),
+                };
+                Ok(json!({ "threads": [{ "id": THREAD_ID, "name": name }] }))
+            }
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                    { "name": "Flags", "variablesReference": FLAGS_REF, "expensive": false },
+                ]
+            })),
+            "variables" => self.variables(args["variablesReference"].as_u64().unwrap_or(0)),
+            "evaluate" => {
+                let expression = args["expression"].as_str().unwrap_or_default();
+                let value = evaluate(self.engine()?, expression)?;
+                Ok(json!({ "result": format!("{value:x}"), "variablesReference": 0 }))
+            }
+            "continue" => {
+                self.resume(Resume::Continue)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                self.resume(Resume::StepOver)?;
+                Ok(Value::Null)
+            }
+            "stepIn" => {
+                self.resume(Resume::Step)?;
+                Ok(Value::Null)
+            }
+            "stepOut" => {
+                self.resume(Resume::StepOut)?;
+                Ok(Value::Null)
+            }
+            // Runs finish before we read the next request, there is nothing to pause
+            "pause" => Ok(Value::Null),
+            "disassemble" => self.disassemble(args),
+            "readMemory" => self.read_memory(args),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request '{command}'")),
+        }
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<()> {
+        let command = request["command"].as_str().unwrap_or_default();
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+        });
+        match self.call(command, &request["arguments"]) {
+            Ok(body) => {
+                response["success"] = json!(true);
+                if !body.is_null() {
+                    response["body"] = body;
+                }
+            }
+            Err(message) => {
+                response["success"] = json!(false);
+                response["message"] = json!(message);
+            }
+        }
+        self.send(response)?;
+
+        for (event, body) in std::mem::take(&mut self.events) {
+            self.send(json!({ "type": "event", "event": event, "body": body }))?;
+        }
+        Ok(())
+    }
+
+    /// Answer requests until the client disconnects or closes the stream
+    pub fn serve(&mut self, mut reader: impl BufRead) -> io::Result<()> {
+        while !self.done {
+            let Some(message) = read_message(&mut reader)? else {
+                break;
+            };
+            if message["type"] == "request" {
+                self.handle(&message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use serde_json::{Value, json};
+
+    use crate::{
+        dap::{Server, base64, parse_reference, read_message, write_message},
+        engine::Engine,
+        error::Error,
+        program::Program,
+    };
+
+    fn requests(requests: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for request in requests {
+            write_message(&mut input, request).unwrap();
+        }
+        input
+    }
+
+    fn messages(mut output: &[u8]) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"MZ"), "TVo=");
+        assert_eq!(base64(b"MZ\x90\x00"), "TVqQAA==");
+        assert_eq!(parse_reference("0x1a2b0"), Some(0x1a2b0));
+        assert_eq!(parse_reference("1a2b0"), Some(0x1a2b0));
+        assert_eq!(parse_reference("cs:ip"), None);
+    }
+
+    #[test]
+    fn framing() {
+        let input = requests(&[json!({"seq": 1, "type": "request", "command": "threads"})]);
+        assert!(input.starts_with(b"Content-Length: 46\r\n\r\n{"));
+        let mut reader = Cursor::new(input);
+        assert_eq!(
+            read_message(&mut reader).unwrap().unwrap()["command"],
+            "threads"
+        );
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn session_without_program() {
+        let input = requests(&[
+            json!({"seq": 1, "type": "request", "command": "initialize", "arguments": {}}),
+            json!({"seq": 2, "type": "request", "command": "launch", "arguments": {"program": "NONE.EXE"}}),
+            json!({"seq": 3, "type": "request", "command": "next", "arguments": {"threadId": 1}}),
+            json!({"seq": 4, "type": "request", "command": "setFunctionBreakpoints",
+                   "arguments": {"breakpoints": [{"name": "1000:0010"}]}}),
+            json!({"seq": 5, "type": "request", "command": "disconnect"}),
+            json!({"seq": 6, "type": "request", "command": "threads"}),
+        ]);
+        let mut output = Vec::new();
+        let mut server = Server::new(
+            |path: &str| -> Result<Engine<'static>, Error> {
+                Err(Error::MalformedHeader(format!("no '{path}' here")))
+            },
+            &mut output,
+        );
+        server.serve(Cursor::new(input)).unwrap();
+
+        let messages = messages(&output);
+        let summary: Vec<(&str, &str, bool)> = messages
+            .iter()
+            .map(|message| {
+                (
+                    message["type"].as_str().unwrap(),
+                    message["command"]
+                        .as_str()
+                        .or(message["event"].as_str())
+                        .unwrap(),
+                    message["success"].as_bool().unwrap_or(true),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("response", "initialize", true),
+                ("event", "initialized", true),
+                ("response", "launch", false),
+                ("response", "next", false),
+                ("response", "setFunctionBreakpoints", true),
+                ("response", "disconnect", true),
+            ]
+        );
+        assert_eq!(
+            messages[2]["message"],
+            "malformed MZ header: no 'NONE.EXE' here"
+        );
+        assert_eq!(messages[3]["message"], "no program launched");
+        assert_eq!(
+            messages[4]["body"]["breakpoints"][0]["verified"],
+            json!(false)
+        );
+        let seqs: Vec<u64> = messages
+            .iter()
+            .map(|m| m["seq"].as_u64().unwrap())
+            .collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn breakpoint_and_stack_trace() {
+        let program = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let input = requests(&[
+            json!({"seq": 1, "type": "request", "command": "initialize", "arguments": {}}),
+            json!({"seq": 2, "type": "request", "command": "launch", "arguments": {"program": program}}),
+            // The print subroutine
+            json!({"seq": 3, "type": "request", "command": "setFunctionBreakpoints",
+                   "arguments": {"breakpoints": [{"name": "1000:000f"}]}}),
+            json!({"seq": 4, "type": "request", "command": "configurationDone"}),
+            json!({"seq": 5, "type": "request", "command": "stackTrace", "arguments": {"threadId": 1}}),
+            json!({"seq": 6, "type": "request", "command": "continue", "arguments": {"threadId": 1}}),
+            json!({"seq": 7, "type": "request", "command": "disconnect"}),
+        ]);
+        let mut output = Vec::new();
+        let mut server = Server::new(
+            |path: &str| Engine::new(Program::new(path, 0x1000)?),
+            &mut output,
+        );
+        server.serve(Cursor::new(input)).unwrap();
+
+        let messages = messages(&output);
+        let find = |kind: &str, name: &str| {
+            messages
+                .iter()
+                .find(|message| {
+                    message["type"] == kind
+                        && (message["command"] == name || message["event"] == name)
+                })
+                .unwrap_or_else(|| panic!("no {kind} {name} in {messages:?}"))
+        };
+        assert_eq!(find("response", "launch")["success"], true);
+        assert_eq!(
+            find("response", "setFunctionBreakpoints")["body"]["breakpoints"][0],
+            json!({"verified": true, "instructionReference": "0x1000f"})
+        );
+        assert_eq!(find("event", "stopped")["body"]["reason"], "breakpoint");
+
+        let trace = &find("response", "stackTrace")["body"];
+        let frames: Vec<&Value> = trace["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| &frame["instructionPointerReference"])
+            .collect();
+        // Stopped in print, called from the start of the program
+        assert_eq!(frames, [&json!("0x1000f"), &json!("0x10007")]);
+
+        let stdout: Vec<&Value> = messages
+            .iter()
+            .filter(|message| message["body"]["category"] == "stdout")
+            .map(|message| &message["body"]["output"])
+            .collect();
+        assert_eq!(stdout, [&json!("Hello from DOS\r\n")]);
+        assert_eq!(find("event", "exited")["body"]["exitCode"], 2);
+    }
+
+    #[test]
+    fn memory_requests_stay_in_memory() {
+        let program = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let input = requests(&[
+            json!({"seq": 1, "type": "request", "command": "launch", "arguments": {"program": program}}),
+            json!({"seq": 2, "type": "request", "command": "readMemory",
+                   "arguments": {"memoryReference": "0x10000", "count": 1_000_000_000_000_000u64}}),
+            json!({"seq": 3, "type": "request", "command": "readMemory",
+                   "arguments": {"memoryReference": "0x10fff0", "offset": 1, "count": 16}}),
+            json!({"seq": 4, "type": "request", "command": "readMemory",
+                   "arguments": {"memoryReference": "0x10fff0", "count": 16}}),
+            json!({"seq": 5, "type": "request", "command": "disassemble",
+                   "arguments": {"memoryReference": "0x10000", "instructionCount": 1_000_000_000_000u64}}),
+            json!({"seq": 6, "type": "request", "command": "disassemble",
+                   "arguments": {"memoryReference": "0x10000", "instructionOffset": -1_000_000_000_000i64,
+                                 "instructionCount": 1}}),
+            json!({"seq": 7, "type": "request", "command": "disassemble",
+                   "arguments": {"memoryReference": "0x10000", "instructionCount": 2}}),
+            json!({"seq": 8, "type": "request", "command": "disconnect"}),
+        ]);
+        let mut output = Vec::new();
+        let mut server = Server::new(
+            |path: &str| Engine::new(Program::new(path, 0x1000)?),
+            &mut output,
+        );
+        server.serve(Cursor::new(input)).unwrap();
+
+        let responses: Vec<(u64, bool)> = messages(&output)
+            .iter()
+            .filter(|message| message["type"] == "response")
+            .map(|message| {
+                (
+                    message["request_seq"].as_u64().unwrap(),
+                    message["success"].as_bool().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            responses,
+            [
+                (1, true),
+                (2, false),
+                (3, false),
+                (4, true),
+                (5, false),
+                (6, false),
+                (7, true),
+                (8, true)
+            ]
+        );
+    }
+}
@@ -1,29 +1,127 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Display,
     fs,
     io::{self, BufRead, Write},
     num::ParseIntError,
-    process::exit,
 };
 
-use crate::engine::{Engine, FarPointer};
+use crate::{
+    engine::{Catchpoint, Cpu, Engine, FarPointer, Fault},
+    error::Error,
+    pattern::{Field, Patterns, Type},
+    script::{self, Env, Expr, Part},
+};
 
 #[derive(Debug)]
 enum Command {
     Quit,
-    Print(String),
+    Print(Option<PrintTarget>),
     Run,
+    /// Step instructions, CALLs and INTs run until they return
     Next(Option<usize>),
+    /// Step instructions, into CALLs
+    StepInstruction(Option<usize>),
+    /// Run until the current function returns
+    Finish,
+    /// Run to an address through a temporary breakpoint
+    Until(u64),
     Continue,
     Logon,
     Logoff,
-    Break(String),
-    WhileBreak { addr: u64, commands: Vec<Command> },
+    /// List the words the loader relocated
+    Relocs,
+    /// Show the overlays of the program and which ones are in memory
+    Overlays,
+    /// List the interrupt services and how well they are emulated
+    Services,
+    /// Write memory to a host file
+    Dump {
+        addr: u64,
+        len: usize,
+        file: String,
+    },
+    /// Write the running program as it is in memory to an executable
+    DumpImage(String),
+    /// Copy a host file into memory
+    Load {
+        file: String,
+        addr: u64,
+    },
+    /// Show the layout of a struct
+    PType(String),
+    /// Print memory as a struct
+    View {
+        name: String,
+        addr: u64,
+    },
+    /// Load structs from an ImHex pattern file and show its placements
+    Pattern(String),
+    /// With a program name the address is relative to where that program gets loaded
+    Break {
+        program: Option<String>,
+        addr: u64,
+    },
+    /// Offset from the start of an overlay, set wherever the overlay gets read to
+    OverlayBreak {
+        number: u16,
+        offset: u64,
+    },
+    /// Stop before an interrupt, or one AH function of it, is serviced and log the call
+    Catch(Catchpoint),
+    /// Log the calls of an interrupt, or one AH function of it, without stopping
+    Log(Catchpoint),
+    /// Stop when the program faults, before the fault ends it
+    CatchFault(Fault),
+    WhileBreak {
+        addr: u64,
+        commands: Vec<Command>,
+    },
+    Let {
+        name: String,
+        value: Expr,
+    },
+    If {
+        condition: Expr,
+        then: Vec<Command>,
+        otherwise: Vec<Command>,
+    },
+    While {
+        condition: Expr,
+        commands: Vec<Command>,
+    },
+    /// Print a line with `{expr}` replaced by values
+    Echo(Vec<Part>),
+    /// Check that an expression isn't 0, `line` is where it is in the script
+    Assert {
+        condition: Expr,
+        message: String,
+        line: usize,
+    },
+    /// Check that the program printed `text` since the last check
+    ExpectOutput {
+        text: String,
+        line: usize,
+    },
+}
+
+#[derive(Debug)]
+enum PrintTarget {
+    /// Address as it was written and its value
+    Addr(String, u64),
+    /// Segment and offset registers, like es:di
+    Registers(String, String),
 }
 
 #[derive(Debug)]
 enum ParseVal {
     Comment,
     BlockEnd,
+    /// `} else {`
+    Else,
+    /// `} else if <expr> {`
+    ElseIf(Expr),
     Command(Command),
 }
 
@@ -33,115 +131,598 @@ struct Ast {
 }
 
 impl Ast {
-    fn new(file: &str) -> Self {
+    fn new(file: &str) -> Result<Self, Error> {
         let mut commands = Vec::new();
 
         let mut idx = 0;
         let lines: Vec<&str> = file.lines().collect();
-        while let Some((value, next_idx)) = Self::parse_command(idx, &lines, false) {
+        while let Some((value, next_idx)) = Self::parse_command(idx, &lines, false)? {
             if let ParseVal::Command(command) = value {
                 commands.push(command);
             }
             idx = next_idx;
         }
 
-        Self { commands }
+        Ok(Self { commands })
     }
 
-    fn parse_command(idx: usize, lines: &[&str], in_block: bool) -> Option<(ParseVal, usize)> {
+    /// Parse error pointing at `token` on line `idx`, or the start of the line if it isn't there
+    fn error(idx: usize, lines: &[&str], token: &str, message: String) -> Error {
+        let column = lines[idx].find(token).map_or(1, |column| column + 1);
+        Error::Parse {
+            line: idx + 1,
+            column,
+            message,
+        }
+    }
+
+    /// Parse an expression that is part of line `idx`, errors point into the expression
+    fn parse_expr(idx: usize, lines: &[&str], text: &str) -> Result<Expr, Error> {
+        Expr::parse(text).map_err(|(pos, message)| {
+            let start = lines[idx].find(text).unwrap_or(0);
+            Error::Parse {
+                line: idx + 1,
+                column: start + pos + 1,
+                message,
+            }
+        })
+    }
+
+    /// The expression between `keyword` and the `{` that opens the block
+    fn parse_condition(idx: usize, lines: &[&str], keyword: &str) -> Result<Expr, Error> {
+        let line = lines[idx].trim();
+        let Some(condition) = line
+            .strip_prefix(keyword)
+            .and_then(|rest| rest.strip_suffix('{'))
+            .map(str::trim)
+            .filter(|condition| !condition.is_empty())
+        else {
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                format!("expected '{keyword} <expr> {{'"),
+            ));
+        };
+        Self::parse_expr(idx, lines, condition)
+    }
+
+    /// Commands up to the closing brace of the block opened on line `start`,
+    /// returns how the block ended and the line after it
+    fn parse_block(
+        start: usize,
+        lines: &[&str],
+        what: &str,
+    ) -> Result<(Vec<Command>, ParseVal, usize), Error> {
+        let mut idx = start + 1;
+        let mut commands = Vec::new();
+        while let Some((value, next_idx)) = Self::parse_command(idx, lines, true)? {
+            idx = next_idx;
+            match value {
+                ParseVal::Comment => {}
+                ParseVal::Command(command) => commands.push(command),
+                end => return Ok((commands, end, idx)),
+            }
+        }
+
+        Err(Self::error(
+            start,
+            lines,
+            lines[start].trim(),
+            format!("expected closing '}}' after {what}"),
+        ))
+    }
+
+    /// Only an if can be followed by an else
+    fn expect_block_end(end: &ParseVal, idx: usize, lines: &[&str]) -> Result<(), Error> {
+        if matches!(end, ParseVal::BlockEnd) {
+            return Ok(());
+        }
+        Err(Self::error(idx, lines, "else", "else without an if".into()))
+    }
+
+    /// if and else if blocks, an else if becomes an if in the else block
+    fn parse_if(start: usize, lines: &[&str], condition: Expr) -> Result<(Command, usize), Error> {
+        let (then, end, idx) = Self::parse_block(start, lines, "an if command")?;
+        let (otherwise, idx) = match end {
+            ParseVal::Else => {
+                let (otherwise, end, next_idx) = Self::parse_block(idx - 1, lines, "an else")?;
+                Self::expect_block_end(&end, next_idx - 1, lines)?;
+                (otherwise, next_idx)
+            }
+            ParseVal::ElseIf(condition) => {
+                let (command, next_idx) = Self::parse_if(idx - 1, lines, condition)?;
+                (vec![command], next_idx)
+            }
+            _ => (Vec::new(), idx),
+        };
+
+        let command = Command::If {
+            condition,
+            then,
+            otherwise,
+        };
+        Ok((command, idx))
+    }
+
+    fn parse_let(idx: usize, lines: &[&str]) -> Result<Command, Error> {
+        let line = lines[idx].trim();
+        let Some((name, value)) = line
+            .strip_prefix("let ")
+            .and_then(|rest| rest.split_once('='))
+        else {
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                "expected 'let <name> = <expr>'".into(),
+            ));
+        };
+        let name = name.trim();
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid || script::is_register(name) {
+            return Err(Self::error(
+                idx,
+                lines,
+                name,
+                format!("'{name}' can't be used as a variable name"),
+            ));
+        }
+        let value = Self::parse_expr(idx, lines, value.trim())?;
+        Ok(Command::Let {
+            name: name.into(),
+            value,
+        })
+    }
+
+    fn parse_echo(idx: usize, lines: &[&str]) -> Result<Command, Error> {
+        let line = lines[idx].trim();
+        let text = line.strip_prefix("echo").unwrap_or_default().trim();
+        // Quotes are optional, they keep leading and trailing spaces
+        let text = text
+            .strip_prefix('"')
+            .and_then(|text| text.strip_suffix('"'))
+            .unwrap_or(text);
+        let parts = script::parse_interpolation(text).map_err(|(pos, message)| {
+            let start = lines[idx].find(text).unwrap_or(0);
+            Error::Parse {
+                line: idx + 1,
+                column: start + pos + 1,
+                message,
+            }
+        })?;
+        Ok(Command::Echo(parts))
+    }
+
+    /// `assert <expr> ["message"]`, the expression is the message if there is none
+    fn parse_assert(idx: usize, lines: &[&str]) -> Result<Command, Error> {
+        let line = lines[idx].trim();
+        let rest = line.strip_prefix("assert").unwrap_or_default().trim();
+        let (condition, message) = match rest
+            .strip_suffix('"')
+            .and_then(|rest| rest.rsplit_once('"'))
+        {
+            Some((condition, message)) => (condition.trim().trim_end_matches(',').trim(), message),
+            None => (rest, rest),
+        };
+        if condition.is_empty() {
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                "expected 'assert <expr> [\"message\"]'".into(),
+            ));
+        }
+        Ok(Command::Assert {
+            condition: Self::parse_expr(idx, lines, condition)?,
+            message: message.into(),
+            line: idx + 1,
+        })
+    }
+
+    fn parse_expect_output(idx: usize, lines: &[&str]) -> Result<Command, Error> {
+        let line = lines[idx].trim();
+        let Some(text) = line
+            .strip_prefix("expect-output")
+            .map(str::trim)
+            .and_then(|text| text.strip_prefix('"'))
+            .and_then(|text| text.strip_suffix('"'))
+        else {
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                "expected 'expect-output \"<text>\"'".into(),
+            ));
+        };
+        Ok(Command::ExpectOutput {
+            text: text.replace("\\n", "\n").replace("\\r", "\r"),
+            line: idx + 1,
+        })
+    }
+
+    fn parse_command(
+        idx: usize,
+        lines: &[&str],
+        in_block: bool,
+    ) -> Result<Option<(ParseVal, usize)>, Error> {
         if idx >= lines.len() {
-            return None;
+            return Ok(None);
         }
 
         let line = lines[idx];
         let line = line.trim();
         if line.is_empty() || line.starts_with('#') {
-            return Some((ParseVal::Comment, idx + 1));
+            return Ok(Some((ParseVal::Comment, idx + 1)));
         }
 
         if in_block && line == "}" {
-            return Some((ParseVal::BlockEnd, idx + 1));
+            return Ok(Some((ParseVal::BlockEnd, idx + 1)));
+        }
+        if in_block && line.starts_with('}') {
+            let rest = line[1..].trim_start();
+            if rest.split_whitespace().collect::<Vec<_>>() == ["else", "{"] {
+                return Ok(Some((ParseVal::Else, idx + 1)));
+            }
+            if let Some(rest) = rest.strip_prefix("else ")
+                && rest.trim_start().starts_with("if ")
+            {
+                let condition = rest
+                    .trim_start()
+                    .strip_prefix("if")
+                    .and_then(|rest| rest.strip_suffix('{'))
+                    .map(str::trim)
+                    .filter(|condition| !condition.is_empty())
+                    .ok_or_else(|| {
+                        Self::error(idx, lines, line, "expected '} else if <expr> {'".into())
+                    })?;
+                let condition = Self::parse_expr(idx, lines, condition)?;
+                return Ok(Some((ParseVal::ElseIf(condition), idx + 1)));
+            }
         }
 
+        let parts: Vec<&str> = line.split_whitespace().collect();
         let (command, size) = if line == "q" || line == "quit" || line == "exit" {
             (Command::Quit, 1)
         } else if line == "p" || line == "print" {
-            (Command::Print("".into()), 1)
+            (Command::Print(None), 1)
         } else if line.starts_with("p ") || line.starts_with("print ") {
-            (Command::Print(line.into()), 1)
+            let target = Self::parse_print(idx, lines, parts[1])?;
+            (Command::Print(Some(target)), 1)
         } else if line == "r" || line == "run" {
             (Command::Run, 1)
-        } else if line == "n" || line == "next" {
-            (Command::Next(None), 1)
-        } else if line.starts_with("n ") || line.starts_with("next ") {
-            let count = usize::from_str_radix(line.split_whitespace().nth(1).unwrap(), 10).unwrap();
-            (Command::Next(Some(count)), 1)
+        } else if matches!(parts[0], "n" | "next" | "ni" | "nexti" | "si" | "stepi") {
+            let count = match parts[1..] {
+                [] => None,
+                [count] => Some(count.parse().map_err(|_| {
+                    Self::error(
+                        idx,
+                        lines,
+                        count,
+                        format!("expected an instruction count, got '{count}'"),
+                    )
+                })?),
+                _ => {
+                    return Err(Self::error(
+                        idx,
+                        lines,
+                        line,
+                        format!("expected '{} [count]'", parts[0]),
+                    ));
+                }
+            };
+            if matches!(parts[0], "si" | "stepi") {
+                (Command::StepInstruction(count), 1)
+            } else {
+                (Command::Next(count), 1)
+            }
+        } else if line == "finish" {
+            (Command::Finish, 1)
+        } else if parts[0] == "until" {
+            let [_, addr] = parts[..] else {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    line,
+                    "expected 'until <addr>'".into(),
+                ));
+            };
+            let addr = Self::parse_addr(addr).map_err(|_| {
+                Self::error(idx, lines, addr, format!("cannot parse addr '{addr}'"))
+            })?;
+            (Command::Until(addr), 1)
         } else if line == "c" || line == "continue" {
             (Command::Continue, 1)
         } else if line == "logon" {
             (Command::Logon, 1)
         } else if line == "logoff" {
             (Command::Logoff, 1)
+        } else if line == "relocs" {
+            (Command::Relocs, 1)
+        } else if line == "overlays" {
+            (Command::Overlays, 1)
+        } else if line == "services" {
+            (Command::Services, 1)
+        } else if parts[0] == "dump" {
+            let command = match parts[..] {
+                [_, "image", file] => Command::DumpImage(file.into()),
+                [_, addr, len, file] => Command::Dump {
+                    addr: Self::parse_addr(addr).map_err(|_| {
+                        Self::error(idx, lines, addr, format!("cannot parse addr '{addr}'"))
+                    })?,
+                    len: usize::from_str_radix(len, 16).map_err(|_| {
+                        Self::error(idx, lines, len, format!("cannot parse length '{len}'"))
+                    })?,
+                    file: file.into(),
+                },
+                _ => {
+                    return Err(Self::error(
+                        idx,
+                        lines,
+                        line,
+                        "expected 'dump <addr> <len> <file>' or 'dump image <file>'".into(),
+                    ));
+                }
+            };
+            (command, 1)
+        } else if parts[0] == "ptype" {
+            let [_, name] = parts[..] else {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    line,
+                    "expected 'ptype <struct>'".into(),
+                ));
+            };
+            (Command::PType(name.into()), 1)
+        } else if parts[0] == "view" {
+            let [_, name, addr] = parts[..] else {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    line,
+                    "expected 'view <struct> <addr>'".into(),
+                ));
+            };
+            let addr = Self::parse_addr(addr).map_err(|_| {
+                Self::error(idx, lines, addr, format!("cannot parse addr '{addr}'"))
+            })?;
+            (
+                Command::View {
+                    name: name.into(),
+                    addr,
+                },
+                1,
+            )
+        } else if parts[0] == "pattern" {
+            let [_, file] = parts[..] else {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    line,
+                    "expected 'pattern <file>'".into(),
+                ));
+            };
+            (Command::Pattern(file.into()), 1)
+        } else if parts[0] == "load" {
+            let [_, file, addr] = parts[..] else {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    line,
+                    "expected 'load <file> <addr>'".into(),
+                ));
+            };
+            let addr = Self::parse_addr(addr).map_err(|_| {
+                Self::error(idx, lines, addr, format!("cannot parse addr '{addr}'"))
+            })?;
+            (
+                Command::Load {
+                    file: file.into(),
+                    addr,
+                },
+                1,
+            )
         } else if line.starts_with("b ") || line.starts_with("break ") {
-            (Command::Break(line.into()), 1)
-        } else if line.starts_with("while") {
-            Self::parse_while(idx, lines)
+            // b <program> <addr> is relative to where a child program gets loaded
+            let (program, addr) = match parts[..] {
+                [_, "ovl", number, offset] => {
+                    let number = u16::from_str_radix(number, 16).map_err(|_| {
+                        Self::error(
+                            idx,
+                            lines,
+                            number,
+                            format!("cannot parse overlay number '{number}'"),
+                        )
+                    })?;
+                    let offset = Self::parse_addr(offset).map_err(|_| {
+                        Self::error(
+                            idx,
+                            lines,
+                            offset,
+                            format!("cannot parse offset '{offset}'"),
+                        )
+                    })?;
+                    return Ok(Some((
+                        ParseVal::Command(Command::OverlayBreak { number, offset }),
+                        idx + 1,
+                    )));
+                }
+                [_, addr] => (None, addr),
+                [_, program, addr] => (Some(program.to_string()), addr),
+                _ => {
+                    return Err(Self::error(
+                        idx,
+                        lines,
+                        line,
+                        "expected 'break [program] <addr>' or 'break ovl <number> <offset>'".into(),
+                    ));
+                }
+            };
+            let addr = Self::parse_addr(addr).map_err(|_| {
+                Self::error(idx, lines, addr, format!("cannot parse addr '{addr}'"))
+            })?;
+            (Command::Break { program, addr }, 1)
+        } else if parts[0] == "catch" || parts[0] == "log" {
+            (Self::parse_catch(idx, lines, &parts)?, 1)
+        } else if parts[0] == "while" {
+            Self::parse_while(idx, lines)?
+        } else if parts[0] == "if" {
+            let condition = Self::parse_condition(idx, lines, "if")?;
+            let (command, next_idx) = Self::parse_if(idx, lines, condition)?;
+            (command, next_idx - idx)
+        } else if parts[0] == "let" {
+            (Self::parse_let(idx, lines)?, 1)
+        } else if parts[0] == "assert" {
+            (Self::parse_assert(idx, lines)?, 1)
+        } else if parts[0] == "expect-output" {
+            (Self::parse_expect_output(idx, lines)?, 1)
+        } else if parts[0] == "echo" {
+            (Self::parse_echo(idx, lines)?, 1)
         } else {
-            panic!("Unknown command {line} on line {}", idx + 1);
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                format!("unknown command '{line}'"),
+            ));
         };
 
-        Some((ParseVal::Command(command), idx + size))
+        Ok(Some((ParseVal::Command(command), idx + size)))
     }
 
-    fn parse_while(idx: usize, lines: &[&str]) -> (Command, usize) {
-        let start = idx;
-        let mut idx = idx;
-        let line_num = idx + 1;
+    /// `catch` and `log` take the same interrupt and AH, only `catch` takes faults
+    fn parse_catch(idx: usize, lines: &[&str], parts: &[&str]) -> Result<Command, Error> {
+        let (num, ah) = match parts {
+            [_, "int", num] => (num, None),
+            [_, "int", num, ah] => (num, Some(ah.strip_prefix("ah=").unwrap_or(ah))),
+            ["catch", "exception", name] => {
+                let fault = match *name {
+                    "divide" => Fault::Divide,
+                    "invalid-opcode" => Fault::InvalidOpcode,
+                    "gp" => Fault::GeneralProtection,
+                    _ => {
+                        return Err(Self::error(
+                            idx,
+                            lines,
+                            name,
+                            format!(
+                                "unknown exception '{name}', expected divide, invalid-opcode or gp"
+                            ),
+                        ));
+                    }
+                };
+                return Ok(Command::CatchFault(fault));
+            }
+            ["catch", "unmapped"] => return Ok(Command::CatchFault(Fault::Unmapped)),
+            ["log", ..] => {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    lines[idx].trim(),
+                    "expected 'log int <n> [ah=<x>]'".into(),
+                ));
+            }
+            _ => {
+                return Err(Self::error(
+                    idx,
+                    lines,
+                    lines[idx].trim(),
+                    "expected 'catch int <n> [ah=<x>]', 'catch exception <name>' or 'catch unmapped'"
+                        .into(),
+                ));
+            }
+        };
+        let num = u8::from_str_radix(num, 16)
+            .map_err(|_| Self::error(idx, lines, num, format!("cannot parse interrupt '{num}'")))?;
+        let ah = ah
+            .map(|ah| {
+                u8::from_str_radix(ah, 16)
+                    .map_err(|_| Self::error(idx, lines, ah, format!("cannot parse ah '{ah}'")))
+            })
+            .transpose()?;
+        let catchpoint = Catchpoint { num, ah };
+        if parts[0] == "log" {
+            Ok(Command::Log(catchpoint))
+        } else {
+            Ok(Command::Catch(catchpoint))
+        }
+    }
 
+    fn parse_print(idx: usize, lines: &[&str], target: &str) -> Result<PrintTarget, Error> {
+        if let Ok(addr) = Self::parse_addr(target) {
+            return Ok(PrintTarget::Addr(target.into(), addr));
+        }
+
+        if let Some((reg1, reg2)) = target.split_once(':') {
+            for reg in [reg1, reg2] {
+                if !Cpu::REGISTERS.contains(&reg) {
+                    return Err(Self::error(
+                        idx,
+                        lines,
+                        reg,
+                        format!("unknown register '{reg}'"),
+                    ));
+                }
+            }
+            return Ok(PrintTarget::Registers(reg1.into(), reg2.into()));
+        }
+
+        Err(Self::error(
+            idx,
+            lines,
+            target,
+            format!("expected an address or segment:offset registers, got '{target}'"),
+        ))
+    }
+
+    fn parse_while(idx: usize, lines: &[&str]) -> Result<(Command, usize), Error> {
         let line = lines[idx].trim();
         let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() < 4 {
-            panic!("line {line_num}: while statement requires 4 parts");
+        if parts.get(1) != Some(&"break") {
+            let condition = Self::parse_condition(idx, lines, "while")?;
+            let (commands, end, next_idx) = Self::parse_block(idx, lines, "a while command")?;
+            Self::expect_block_end(&end, next_idx - 1, lines)?;
+            return Ok((
+                Command::While {
+                    condition,
+                    commands,
+                },
+                next_idx - idx,
+            ));
         }
 
-        if parts[1] != "break" {
-            panic!("line {line_num}: only 'break' is supported after while command");
+        if parts.len() < 4 {
+            return Err(Self::error(
+                idx,
+                lines,
+                line,
+                "while break statement requires 4 parts".into(),
+            ));
         }
 
-        let addr = if let Ok(addr) = Self::parse_addr(&parts[2]) {
-            addr
-        } else {
-            panic!(
-                "line {line_num}: cannot parse addr '{}' after break",
-                parts[2]
-            );
-        };
+        let addr = Self::parse_addr(parts[2]).map_err(|_| {
+            Self::error(
+                idx,
+                lines,
+                parts[2],
+                format!("cannot parse addr '{}' after break", parts[2]),
+            )
+        })?;
 
         if parts[3] != "{" {
-            panic!("line {line_num}: expected '{{' after address")
+            return Err(Self::error(
+                idx,
+                lines,
+                parts[3],
+                "expected '{' after address".into(),
+            ));
         };
 
-        // move to the next line and start parsin the commands
-        idx += 1;
-        let mut end_found = false;
-        let mut commands = Vec::new();
-        while let Some((value, next_idx)) = Self::parse_command(idx, &lines, true) {
-            idx = next_idx;
-            match value {
-                ParseVal::BlockEnd => {
-                    end_found = true;
-                    break;
-                }
-                ParseVal::Command(command) => commands.push(command),
-                _ => {}
-            }
-        }
-
-        if !end_found {
-            panic!("expected closing '}}' after a while command ")
-        }
-
-        (Command::WhileBreak { addr, commands }, idx - start)
+        let (commands, end, next_idx) = Self::parse_block(idx, lines, "a while command")?;
+        Self::expect_block_end(&end, next_idx - 1, lines)?;
+        Ok((Command::WhileBreak { addr, commands }, next_idx - idx))
     }
 
     fn parse_addr(addr: &str) -> Result<u64, ParseIntError> {
@@ -157,121 +738,773 @@ impl Ast {
 
 pub struct Debugger<'a> {
     pub engine: Engine<'a>,
+    /// Structs for ptype and view
+    patterns: Patterns,
+    /// Script variables set with let
+    variables: HashMap<String, i64>,
+    /// Results of assert and expect-output in the order they ran
+    checks: Vec<Check>,
+    /// How much of the program output expect-output has looked at
+    output_checked: usize,
+    /// Set by quit, the rest of the script is skipped
+    quit: bool,
+    /// Where command output goes, stdout unless the host gave us something else
+    out: RefCell<Box<dyn Write>>,
+}
+
+/// Outcome of an assert or expect-output
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// Line in the script, 1 based
+    pub line: usize,
+    pub description: String,
+    pub passed: bool,
+}
+
+impl Env for Debugger<'_> {
+    fn cpu(&self) -> Result<Cpu, Error> {
+        self.engine.read_cpu()
+    }
+
+    fn read_u16(&self, addr: u64) -> Result<u16, Error> {
+        self.engine.read_mem(addr)
+    }
+
+    fn variable(&self, name: &str) -> Option<i64> {
+        self.variables.get(name).copied()
+    }
 }
 
 impl<'a> Debugger<'a> {
+    /// Debugger printing to stdout
     pub fn new(engine: Engine<'a>) -> Self {
-        Self { engine }
+        Self::build(engine, io::stdout())
     }
 
-    fn run(&mut self) {
-        if self.engine.exited() {
-            exit(0);
-        }
-        self.engine.start();
+    /// Debugger that writes everything to `out`, the engine messages included
+    pub fn with_output(mut engine: Engine<'a>, out: impl Write + 'static) -> Self {
+        engine.collect_messages();
+        Self::build(engine, out)
     }
 
-    fn cont(&mut self) {
-        if self.engine.exited() {
-            exit(0);
+    fn build(mut engine: Engine<'a>, out: impl Write + 'static) -> Self {
+        // A bad jump shouldn't end the session, stop there so it can be looked at
+        engine.add_fault_catch(Fault::InvalidOpcode);
+        engine.add_fault_catch(Fault::Unmapped);
+        // finish may come in any later command, it needs the calls made before it
+        engine.track_calls();
+        Self {
+            engine,
+            patterns: Patterns::builtin(),
+            variables: HashMap::new(),
+            checks: Vec::new(),
+            output_checked: 0,
+            quit: false,
+            out: RefCell::new(Box::new(out)),
         }
+    }
 
-        self.engine.cont();
+    /// Output of the commands, failing to write it isn't worth stopping the program for
+    fn write_line(&self, line: impl Display) {
+        let _ = writeln!(self.out.borrow_mut(), "{line}");
+    }
+
+    /// Pass on what the engine had to say since the last call, when it isn't printing itself
+    pub fn forward_messages(&mut self) {
+        for message in self.engine.take_messages() {
+            self.write_line(message);
+        }
     }
 
-    fn next(&mut self) {
+    /// The program can't be resumed once it has exited
+    fn running(&self) -> bool {
         if self.engine.exited() {
-            exit(0);
+            self.write_line("The program is not running");
         }
+        !self.engine.exited()
+    }
 
-        self.engine.step();
+    fn run(&mut self) -> Result<(), Error> {
+        if !self.running() {
+            return Ok(());
+        }
+        self.engine.start()?;
+        self.report_catch()?;
+        Ok(())
     }
 
-    fn add_break(&mut self, cmd: &str) {
-        let addr = cmd.split_whitespace().nth(1).unwrap();
-        let addr = if let Some(addrs) = addr.split_once(':') {
-            let segment = u64::from_str_radix(addrs.0, 16).unwrap();
-            let offset = u64::from_str_radix(addrs.1, 16).unwrap();
-            segment * 16 + offset
+    fn cont(&mut self) -> Result<(), Error> {
+        if !self.running() {
+            return Ok(());
+        }
+        self.engine.cont()?;
+        self.report_catch()?;
+        Ok(())
+    }
+
+    /// Step one instruction, true if a catchpoint stopped it
+    fn next(&mut self, over: bool) -> Result<bool, Error> {
+        if !self.running() {
+            return Ok(false);
+        }
+        if over {
+            self.engine.step_over()?;
         } else {
-            u64::from_str_radix(addr, 16).unwrap()
-        };
+            self.engine.step()?;
+        }
+        self.report_catch()
+    }
+
+    /// Step `count` instructions, stops early at catchpoints
+    fn steps(&mut self, count: Option<usize>, over: bool) -> Result<(), Error> {
+        for _ in 0..count.unwrap_or(1) {
+            if self.engine.exited() || self.next(over)? {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-        self.engine.add_break(addr);
+    fn finish(&mut self) -> Result<(), Error> {
+        if !self.running() {
+            return Ok(());
+        }
+        self.engine.finish()?;
+        self.report_catch()?;
+        Ok(())
     }
 
-    fn print(&self, cmd: &str) {
-        let parts: Vec<&str> = cmd.split_whitespace().collect();
-        let cpu = self.engine.read_cpu();
-        if parts.len() <= 1 {
-            println!("{cpu}");
-            return;
+    fn until(&mut self, addr: u64) -> Result<(), Error> {
+        if !self.running() {
+            return Ok(());
         }
+        self.engine.run_to(addr)?;
+        self.report_catch()?;
+        Ok(())
+    }
 
-        let (at, addr) = if let Ok(addr) = Ast::parse_addr(&parts[1]) {
-            (parts[1].into(), addr)
-        } else if let Some((reg1, reg2)) = parts[1].split_once(':') {
-            let segment = cpu.register(reg1);
-            let offset = cpu.register(reg2);
-            let fp = FarPointer::from_segment_offset(segment, offset);
-            (format!("{}[{segment}:{offset}]", parts[1]), fp.address())
+    /// Show what a catchpoint stopped at and the registers, true if one did
+    fn report_catch(&self) -> Result<bool, Error> {
+        let cpu = self.engine.read_cpu()?;
+        let what = if let Some(num) = self.engine.caught() {
+            match Fault::from_vector(num) {
+                Some(fault) => fault.to_string(),
+                None => {
+                    let ah = script::register(&cpu, "ah").unwrap_or_default();
+                    format!("INT {num:02X}h/{ah:02X}h")
+                }
+            }
+        } else if let Some((_, detail)) = self.engine.caught_fault() {
+            detail.to_string()
         } else {
-            panic!("at the disco")
+            return Ok(false);
+        };
+        // Both always exist
+        let fp = FarPointer::from_segment_offset(
+            cpu.register("cs").unwrap(),
+            cpu.register("ip").unwrap(),
+        );
+        self.write_line(format_args!("Caught {what} at [{fp}]"));
+        self.write_line(cpu);
+        Ok(true)
+    }
+
+    fn print(&self, target: &Option<PrintTarget>) -> Result<(), Error> {
+        let cpu = self.engine.read_cpu()?;
+        let (at, addr) = match target {
+            None => {
+                self.write_line(cpu);
+                return Ok(());
+            }
+            Some(PrintTarget::Addr(at, addr)) => (at.clone(), *addr),
+            Some(PrintTarget::Registers(reg1, reg2)) => {
+                // Both were checked when parsing
+                let segment = cpu.register(reg1).unwrap();
+                let offset = cpu.register(reg2).unwrap();
+                let fp = FarPointer::from_segment_offset(segment, offset);
+                (format!("{reg1}:{reg2}[{segment}:{offset}]"), fp.address())
+            }
         };
 
-        println!("Data(u16) at {at}: {:x}", self.engine.read_mem(addr));
+        self.write_line(format_args!(
+            "Data(u16) at {at}: {:x}",
+            self.engine.read_mem(addr)?
+        ));
+        Ok(())
     }
 
-    fn run_commands(&mut self, commands: &[Command]) {
+    fn relocs(&self) {
+        let program = self.engine.program();
+        let start = program.start();
+        self.write_line(format_args!(
+            "{} relocations in '{}' loaded at segment {start:04x}",
+            program.relocations().len(),
+            program.path().display()
+        ));
+        for patch in program.relocations() {
+            let fp = FarPointer::from_segment_offset(
+                patch.relocation.segment as u64 + start,
+                patch.relocation.offset as u64,
+            );
+            self.write_line(format_args!(
+                "  [{fp}] {:04x} -> {:04x}",
+                patch.original, patch.relocated
+            ));
+        }
+    }
+
+    fn overlays(&self) {
+        let program = self.engine.program();
+        self.write_line(format_args!(
+            "{} in '{}', {} bytes after the load image",
+            program.overlay_format(),
+            program.path().display(),
+            program.appended().len()
+        ));
+        let resident = self.engine.resident_overlays();
+        for overlay in program.overlays() {
+            self.write_line(format_args!(
+                "  overlay {}: {} bytes at file offset 0x{:x}",
+                overlay.number, overlay.size, overlay.offset
+            ));
+            for part in resident.iter().filter(|part| part.number == overlay.number) {
+                self.write_line(format_args!(
+                    "    bytes {:x}-{:x} resident at [{}]",
+                    part.offset,
+                    part.offset + part.len,
+                    FarPointer::from_segment_offset(part.segment, part.ip)
+                ));
+            }
+        }
+    }
+
+    fn services(&self) {
+        for service in self.engine.services().list() {
+            self.write_line(format_args!(
+                "{:<16} {:<30} {}",
+                service.key.to_string(),
+                service.name,
+                service.support
+            ));
+        }
+    }
+
+    fn dump(&self, addr: u64, len: usize, file: &str) -> Result<(), Error> {
+        let data = self.engine.read_memory(addr, len)?;
+        fs::write(file, &data)?;
+        self.write_line(format_args!(
+            "Wrote {len:x} bytes from {addr:x} to '{file}'"
+        ));
+        Ok(())
+    }
+
+    fn dump_image(&self, file: &str) -> Result<(), Error> {
+        let program = self.engine.program();
+        let image = self
+            .engine
+            .read_memory(program.start() * 16, program.data().len())?;
+        fs::write(file, program.to_exe(&image))?;
+        self.write_line(format_args!(
+            "Wrote '{}' as it is in memory to '{file}'",
+            program.path().display()
+        ));
+        Ok(())
+    }
+
+    fn load(&mut self, file: &str, addr: u64) -> Result<(), Error> {
+        let data = fs::read(file)?;
+        self.engine.write_memory(addr, &data)?;
+        self.write_line(format_args!(
+            "Loaded {:x} bytes from '{file}' to {addr:x}",
+            data.len()
+        ));
+        Ok(())
+    }
+
+    fn ptype(&self, name: &str) {
+        match self.patterns.describe(name) {
+            Some(lines) => lines.iter().for_each(|line| self.write_line(line)),
+            None => self.unknown_struct(name),
+        }
+    }
+
+    fn unknown_struct(&self, name: &str) {
+        self.write_line(format_args!(
+            "Unknown struct '{name}', known structs: {}",
+            self.patterns.names().join(", ")
+        ));
+    }
+
+    fn view(&self, field: &Field, addr: u64) -> Result<(), Error> {
+        let data = self
+            .engine
+            .read_memory(addr, self.patterns.field_size(field))?;
+        self.write_line(format_args!("{addr:x}:"));
+        for line in self.patterns.format(field, &data) {
+            self.write_line(format_args!("  {line}"));
+        }
+        Ok(())
+    }
+
+    fn pattern(&mut self, file: &str) -> Result<(), Error> {
+        let placements = self.patterns.load(&fs::read_to_string(file)?)?;
+        for placement in placements {
+            self.view(&placement.field, placement.addr)?;
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, line: usize, description: String, passed: bool) {
+        let status = if passed { "PASS" } else { "FAIL" };
+        self.write_line(format_args!("{status} line {line}: {description}"));
+        self.checks.push(Check {
+            line,
+            description,
+            passed,
+        });
+    }
+
+    /// Look for the text in what the program printed since the last expect-output,
+    /// a match moves past it so the next one has to come after
+    fn expect_output(&mut self, line: usize, text: &str) {
+        let output =
+            String::from_utf8_lossy(&self.engine.output()[self.output_checked..]).into_owned();
+        match output.find(text) {
+            Some(pos) => {
+                self.output_checked += output[..pos + text.len()].len();
+                self.check(line, format!("output contains {text:?}"), true);
+            }
+            None => self.check(
+                line,
+                format!("expected output {text:?}, the program printed {output:?}"),
+                false,
+            ),
+        }
+    }
+
+    /// Results of the assertions so far
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+
+    fn run_commands(&mut self, commands: &[Command]) -> Result<(), Error> {
         for command in commands {
+            self.forward_messages();
+            if self.quit {
+                break;
+            }
             match command {
-                Command::Quit => exit(0),
-                Command::Print(cmd) => self.print(cmd),
-                Command::Run => self.run(),
-                Command::Next(None) => self.next(),
-                Command::Next(Some(count)) => {
-                    for _ in 0..*count {
-                        self.next();
-                    }
-                }
-                Command::Continue => self.cont(),
+                Command::Quit => self.quit = true,
+                Command::Print(target) => self.print(target)?,
+                Command::Run => self.run()?,
+                Command::Next(count) => self.steps(*count, true)?,
+                Command::StepInstruction(count) => self.steps(*count, false)?,
+                Command::Finish => self.finish()?,
+                Command::Until(addr) => self.until(*addr)?,
+                Command::Continue => self.cont()?,
                 Command::Logon => self.engine.set_verbose(true),
                 Command::Logoff => self.engine.set_verbose(false),
-                Command::Break(cmd) => self.add_break(cmd),
+                Command::Relocs => self.relocs(),
+                Command::Overlays => self.overlays(),
+                Command::Services => self.services(),
+                Command::Dump { addr, len, file } => self.dump(*addr, *len, file)?,
+                Command::DumpImage(file) => self.dump_image(file)?,
+                Command::Load { file, addr } => self.load(file, *addr)?,
+                Command::PType(name) => self.ptype(name),
+                Command::View { name, addr } => {
+                    if self.patterns.get(name).is_none() {
+                        self.unknown_struct(name);
+                        continue;
+                    }
+                    let field = Field {
+                        ty: Type::Struct(name.clone()),
+                        name: name.to_lowercase(),
+                        count: None,
+                    };
+                    self.view(&field, *addr)?;
+                }
+                Command::Pattern(file) => self.pattern(file)?,
+                Command::Let { name, value } => {
+                    let value = value.eval(self)?;
+                    self.variables.insert(name.clone(), value);
+                }
+                Command::If {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    if condition.eval(self)? != 0 {
+                        self.run_commands(then)?;
+                    } else {
+                        self.run_commands(otherwise)?;
+                    }
+                }
+                Command::While {
+                    condition,
+                    commands,
+                } => {
+                    while !self.quit && condition.eval(self)? != 0 {
+                        self.run_commands(commands)?;
+                    }
+                }
+                Command::Echo(parts) => {
+                    let text = script::interpolate(parts, self)?;
+                    self.write_line(text);
+                }
+                Command::Assert {
+                    condition,
+                    message,
+                    line,
+                } => {
+                    let passed = condition.eval(self)? != 0;
+                    self.check(*line, message.clone(), passed);
+                }
+                Command::ExpectOutput { text, line } => self.expect_output(*line, text),
+                Command::Break {
+                    program: None,
+                    addr,
+                } => self.engine.add_break(*addr),
+                Command::Break {
+                    program: Some(program),
+                    addr,
+                } => self.engine.add_program_break(program, *addr),
+                Command::OverlayBreak { number, offset } => {
+                    self.engine.add_overlay_break(*number, *offset as usize)
+                }
+                Command::Catch(catchpoint) => {
+                    self.engine.add_catchpoint(*catchpoint);
+                    self.engine.log_calls(*catchpoint);
+                }
+                Command::Log(catchpoint) => self.engine.log_calls(*catchpoint),
+                Command::CatchFault(fault) => self.engine.add_fault_catch(*fault),
                 Command::WhileBreak { addr, commands } => {
                     self.engine.add_while_break(*addr);
                     loop {
-                        self.cont();
-                        let ip = FarPointer::read_engine(self.engine.engine());
-                        if ip.address() != *addr {
+                        self.cont()?;
+                        let ip = FarPointer::read_engine(self.engine.engine())?;
+                        if self.quit || self.engine.exited() || ip.address() != *addr {
                             break;
                         }
 
-                        self.run_commands(commands);
+                        self.run_commands(commands)?;
                     }
                 }
             }
         }
+        self.forward_messages();
+
+        Ok(())
+    }
+
+    fn run_ast(&mut self, ast: &Ast) -> Result<(), Error> {
+        self.run_commands(&ast.commands)
     }
 
-    fn run_ast(&mut self, ast: &Ast) {
-        self.run_commands(&ast.commands);
+    /// Run debugger commands given as text
+    pub fn run_line(&mut self, text: &str) -> Result<(), Error> {
+        let ast = Ast::new(text)?;
+        self.run_ast(&ast)
     }
 
-    pub fn run_file(&mut self, path: &str) {
-        let file_data = fs::read_to_string(path).unwrap();
-        let ast = Ast::new(&file_data);
-        self.run_ast(&ast);
+    pub fn run_file(&mut self, path: &str) -> Result<(), Error> {
+        let file_data = fs::read_to_string(path)?;
+        let ast = Ast::new(&file_data)?;
+        self.run_ast(&ast)
     }
 
-    pub fn repl(&mut self) {
-        loop {
-            print!("> ");
-            io::stdout().flush().unwrap();
+    /// Read commands until the input ends or quit. Errors are reported and the
+    /// session goes on, a typo shouldn't lose the program state
+    pub fn repl(&mut self, mut input: impl BufRead) {
+        while !self.quit {
+            {
+                let mut out = self.out.borrow_mut();
+                let _ = write!(out, "> ");
+                let _ = out.flush();
+            }
             let mut cmd = String::new();
-            let _ = io::stdin().lock().read_line(&mut cmd).unwrap();
-            let ast = Ast::new(&cmd);
-            self.run_ast(&ast);
+            if input.read_line(&mut cmd).unwrap_or(0) == 0 {
+                // End of input
+                break;
+            }
+            if let Err(err) = Ast::new(&cmd).and_then(|ast| self.run_ast(&ast)) {
+                self.forward_messages();
+                self.write_line(format_args!("error: {err}"));
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    use crate::{
+        debugger::{Ast, Command, Debugger},
+        engine::{Catchpoint, Engine, Fault},
+        error::Error,
+        program::Program,
+    };
+
+    /// Debugger output the test can read while the debugger holds on to it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Output {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
+        }
+    }
+
+    fn debug_hello() -> (Debugger<'static>, Output) {
+        let path = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let engine = Engine::new(Program::new(&path, 0x1000).unwrap()).unwrap();
+        let output = Output::default();
+        (Debugger::with_output(engine, output.clone()), output)
+    }
+
+    #[test]
+    fn parse_errors() {
+        let ast =
+            Ast::new("b 202b:002b\nb CHILD.EXE 10\n# comment\np es:di\nb ovl 2 1a0\ndump 202b:0000 100 code.bin\ndump image out.exe\nload code.bin 202b:0000\nview PSP 0ff0:0000\nptype MCB\ncatch int 21 ah=3d\ncatch int 10\ncatch exception gp\ncatch unmapped\nlog int 21 ah=3f\n").unwrap();
+        assert!(matches!(
+            ast.commands[..],
+            [
+                Command::Break {
+                    program: None,
+                    addr: 0x202db
+                },
+                Command::Break {
+                    program: Some(_),
+                    addr: 0x10
+                },
+                Command::Print(Some(_)),
+                Command::OverlayBreak {
+                    number: 2,
+                    offset: 0x1a0
+                },
+                Command::Dump {
+                    addr: 0x202b0,
+                    len: 0x100,
+                    ..
+                },
+                Command::DumpImage(_),
+                Command::Load { addr: 0x202b0, .. },
+                Command::View { addr: 0xff00, .. },
+                Command::PType(_),
+                Command::Catch(Catchpoint {
+                    num: 0x21,
+                    ah: Some(0x3d)
+                }),
+                Command::Catch(Catchpoint {
+                    num: 0x10,
+                    ah: None
+                }),
+                Command::CatchFault(Fault::GeneralProtection),
+                Command::CatchFault(Fault::Unmapped),
+                Command::Log(Catchpoint {
+                    num: 0x21,
+                    ah: Some(0x3f)
+                })
+            ]
+        ));
+
+        let err = Ast::new("r\n  p es:xx\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 2,
+                column: 8,
+                ..
+            }
+        ));
+        let err = Ast::new("n\nfoo\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 2,
+                column: 1,
+                ..
+            }
+        ));
+        let err = Ast::new("while break 10 {\n  p\n").unwrap_err();
+        assert!(matches!(err, Error::Parse { line: 1, .. }));
+        let err = Ast::new("let x = 1 +* 2\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 12,
+                ..
+            }
+        ));
+        let err = Ast::new("let ax = 1\n").unwrap_err();
+        assert!(matches!(err, Error::Parse { line: 1, .. }));
+        let err = Ast::new("catch exception nmi\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 17,
+                ..
+            }
+        ));
+        let err = Ast::new("catch int 21 zz\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 14,
+                ..
+            }
+        ));
+        let err = Ast::new("while 1 {\n} else {\n}\n").unwrap_err();
+        assert!(matches!(err, Error::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn parse_steps() {
+        let ast = Ast::new("n\nnexti 3\nsi\nstepi 2\nfinish\nuntil 1000:0010\n").unwrap();
+        assert!(matches!(
+            ast.commands[..],
+            [
+                Command::Next(None),
+                Command::Next(Some(3)),
+                Command::StepInstruction(None),
+                Command::StepInstruction(Some(2)),
+                Command::Finish,
+                Command::Until(0x10010)
+            ]
+        ));
+
+        let err = Ast::new("until\n").unwrap_err();
+        assert!(matches!(err, Error::Parse { line: 1, .. }));
+        let err = Ast::new("si x\n").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 4,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn parse_blocks() {
+        let script = "let i = 0
+while i < 3 {
+    if i == 0 {
+        echo first
+    } else if [ds:si] == 0ff {
+        echo \"i={i} \"
+    } else {
+        n
+    }
+    let i = i + 1
+}
+";
+        let ast = Ast::new(script).unwrap();
+        let [Command::Let { .. }, Command::While { commands, .. }] = &ast.commands[..] else {
+            panic!("unexpected commands {:?}", ast.commands);
+        };
+        let [
+            Command::If {
+                then, otherwise, ..
+            },
+            Command::Let { .. },
+        ] = &commands[..]
+        else {
+            panic!("unexpected loop body {commands:?}");
+        };
+        assert!(matches!(then[..], [Command::Echo(_)]));
+        let [
+            Command::If {
+                then, otherwise, ..
+            },
+        ] = &otherwise[..]
+        else {
+            panic!("unexpected else block {otherwise:?}");
+        };
+        assert!(matches!(then[..], [Command::Echo(_)]));
+        assert!(matches!(otherwise[..], [Command::Next(None)]));
+
+        let ast = Ast::new("r\nassert ax == 0, \"ax is clear\"\nassert [ds:si] != 0\nexpect-output \"done\\r\\n\"\n").unwrap();
+        assert!(matches!(
+            &ast.commands[..],
+            [
+                Command::Run,
+                Command::Assert { message, line: 2, .. },
+                Command::Assert { line: 3, .. },
+                Command::ExpectOutput { text, line: 4 },
+            ] if message == "ax is clear" && text == "done\r\n"
+        ));
+    }
+
+    #[test]
+    fn catch_stops_before_the_service() {
+        let (mut debugger, output) = debug_hello();
+        debugger.run_line("catch int 21 ah=40\nc").unwrap();
+        assert_eq!(debugger.engine.caught(), Some(0x21));
+        assert!(!debugger.engine.exited());
+        assert!(debugger.engine.output().is_empty());
+
+        // Resuming services the write and logs it
+        debugger.run_line("c").unwrap();
+        assert_eq!(debugger.engine.output(), b"Hello from DOS\r\n");
+        assert_eq!(debugger.engine.exit_status(), 2);
+        let caught = output.take();
+        assert!(
+            caught.contains("INT21/40 write handle=1 count=16 -> 16 bytes"),
+            "{caught}"
+        );
+
+        let (mut debugger, output) = debug_hello();
+        debugger.run_line("log int 21 ah=40\nc").unwrap();
+        assert!(debugger.engine.exited());
+        let output = output.take();
+        assert!(
+            output.contains("INT21/40 write handle=1 count=16 -> 16 bytes"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn until_and_finish() {
+        let (mut debugger, _) = debug_hello();
+        debugger
+            .run_line("b 1000:0007\nuntil 1000:000f\nfinish")
+            .unwrap();
+        let engine = &debugger.engine;
+        assert_eq!(engine.read_cpu().unwrap().register("ip"), Some(0x07));
+        assert_eq!(engine.output(), b"Hello from DOS\r\n");
+        assert!(engine.has_break(0x10007));
+        assert!(!engine.has_break(0x1000f));
+    }
+
+    #[test]
+    fn finish_in_a_later_command() {
+        let (mut debugger, _) = debug_hello();
+        assert!(matches!(
+            debugger.run_line("finish"),
+            Err(Error::NoCallToFinish)
+        ));
+
+        // The call into print was made before finish was ever typed
+        debugger.run_line("b 1000:000f").unwrap();
+        debugger.run_line("c").unwrap();
+        debugger.run_line("finish").unwrap();
+        let engine = &debugger.engine;
+        assert_eq!(engine.read_cpu().unwrap().register("ip"), Some(0x07));
+        assert_eq!(engine.output(), b"Hello from DOS\r\n");
+    }
+}
@@ -0,0 +1,809 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+
+/// Label returned when a program searches for the volume label
+const VOLUME_LABEL: &str = "UNICORN";
+
+/// DOS error codes returned in AX with the carry flag set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DosError {
+    InvalidFunction = 0x01,
+    FileNotFound = 0x02,
+    PathNotFound = 0x03,
+    TooManyOpenFiles = 0x04,
+    AccessDenied = 0x05,
+    InvalidHandle = 0x06,
+    InsufficientMemory = 0x08,
+    InvalidFormat = 0x0b,
+    InvalidDrive = 0x0f,
+    CurrentDirectory = 0x10,
+    NoMoreFiles = 0x12,
+}
+
+impl DosError {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_code(code: u16) -> Option<Self> {
+        [
+            DosError::InvalidFunction,
+            DosError::FileNotFound,
+            DosError::PathNotFound,
+            DosError::TooManyOpenFiles,
+            DosError::AccessDenied,
+            DosError::InvalidHandle,
+            DosError::InsufficientMemory,
+            DosError::InvalidFormat,
+            DosError::InvalidDrive,
+            DosError::CurrentDirectory,
+            DosError::NoMoreFiles,
+        ]
+        .into_iter()
+        .find(|err| err.code() == code)
+    }
+}
+
+impl From<io::Error> for DosError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => DosError::FileNotFound,
+            _ => DosError::AccessDenied,
+        }
+    }
+}
+
+/// Pack a local time into the DOS (time, date) words
+pub fn dos_datetime(time: NaiveDateTime) -> (u16, u16) {
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date =
+        ((time.year().clamp(1980, 2107) as u32 - 1980) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+/// Unpack the DOS (time, date) words, None if they aren't a valid date
+pub fn from_dos_datetime(time: u16, date: u16) -> Option<NaiveDateTime> {
+    let date = NaiveDate::from_ymd_opt(
+        1980 + (date >> 9) as i32,
+        ((date >> 5) & 0xf) as u32,
+        (date & 0x1f) as u32,
+    )?;
+    let time = NaiveTime::from_hms_opt(
+        (time >> 11) as u32,
+        ((time >> 5) & 0x3f) as u32,
+        (time & 0x1f) as u32 * 2,
+    )?;
+    Some(date.and_time(time))
+}
+
+fn local_time(time: SystemTime) -> NaiveDateTime {
+    DateTime::<Local>::from(time).naive_local()
+}
+
+fn system_time(time: NaiveDateTime) -> SystemTime {
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .map_or_else(SystemTime::now, SystemTime::from)
+}
+
+/// A file or directory as DOS sees it
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// 8.3 name, upper case
+    pub name: String,
+    pub attributes: u8,
+    pub time: u16,
+    pub date: u16,
+    pub size: u32,
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Is the name already a valid 8.3 name, ignoring case
+fn is_short_name(name: &str) -> bool {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    (1..=8).contains(&base.len())
+        && ext.len() <= 3
+        && base.chars().chain(ext.chars()).all(is_short_char)
+}
+
+/// Generate the `NAME~N.EXT` alias for a long host name
+fn mangle(name: &str, taken: &[String]) -> String {
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+        _ => (name, ""),
+    };
+    let clean = |s: &str, len: usize| -> String {
+        s.chars()
+            .filter(|c| is_short_char(*c))
+            .take(len)
+            .collect::<String>()
+            .to_ascii_uppercase()
+    };
+    let base = clean(stem, 8);
+    let ext = clean(ext, 3);
+
+    (1..)
+        .map(|n| {
+            let suffix = format!("~{n}");
+            let base: String = base.chars().take(8 - suffix.len()).collect();
+            if ext.is_empty() {
+                format!("{base}{suffix}")
+            } else {
+                format!("{base}{suffix}.{ext}")
+            }
+        })
+        .find(|candidate| !taken.contains(candidate))
+        .unwrap()
+}
+
+/// List a host directory with the 8.3 names DOS programs see.
+/// Names that are valid 8.3 names keep their name, others get a `~N` alias
+fn short_names(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .collect();
+    names.sort();
+
+    let mut taken = Vec::new();
+    let mut entries = Vec::new();
+    let mut long = Vec::new();
+    for name in names {
+        let short = name.to_ascii_uppercase();
+        if is_short_name(&name) && !taken.contains(&short) {
+            taken.push(short.clone());
+            entries.push((short, dir.join(name)));
+        } else {
+            long.push(name);
+        }
+    }
+
+    for name in long {
+        let short = mangle(&name, &taken);
+        taken.push(short.clone());
+        entries.push((short, dir.join(name)));
+    }
+
+    Ok(entries)
+}
+
+/// Expand a file name into the 11 character blank padded FCB form, `*` becomes `?`
+fn fcb_name(name: &str) -> [u8; 11] {
+    let mut fcb = [b' '; 11];
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    for (part, range) in [(base, 0..8), (ext, 8..11)] {
+        for (idx, c) in range.clone().zip(part.bytes()) {
+            if c == b'*' {
+                fcb[idx..range.end].fill(b'?');
+                break;
+            }
+            fcb[idx] = c.to_ascii_uppercase();
+        }
+    }
+    fcb
+}
+
+/// Match a name against a DOS wildcard pattern like `*.TXT` or `FILE?.*`
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    if name == "." || name == ".." {
+        return pattern == "*.*" || pattern == "*" || pattern == name;
+    }
+
+    let pattern = fcb_name(pattern);
+    let name = fcb_name(name);
+    pattern
+        .iter()
+        .zip(name.iter())
+        .all(|(p, n)| *p == b'?' || p == n)
+}
+
+/// The fixed disk C: backed by a directory on the host
+pub struct VirtualDrive {
+    root: PathBuf,
+    /// Current directory as upper case components below the root
+    cwd: Vec<String>,
+    /// Hidden, system and archive bits set by programs, the host can't store them
+    attributes: HashMap<PathBuf, u8>,
+    /// DOS paths that map to host files outside the root, as upper case components
+    aliases: HashMap<Vec<String>, PathBuf>,
+    searches: HashMap<u16, Vec<DirEntry>>,
+    next_search: u16,
+}
+
+impl VirtualDrive {
+    /// Drive number of C: as used by INT 21h, 0 = A:
+    pub const NUMBER: u8 = 2;
+
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            cwd: Vec::new(),
+            attributes: HashMap::new(),
+            aliases: HashMap::new(),
+            searches: HashMap::new(),
+            next_search: 0,
+        }
+    }
+
+    /// Current directory without drive letter or leading backslash, as returned by INT 21h/47h
+    pub fn current_dir(&self) -> String {
+        self.cwd.join("\\")
+    }
+
+    /// Split a DOS path into components below the root, resolving `.` and `..`
+    fn components(&self, path: &str) -> Result<Vec<String>, DosError> {
+        let path = path.replace('/', "\\");
+        let path = match path.as_bytes() {
+            [drive, b':', ..] => {
+                if drive.to_ascii_uppercase() != b'A' + Self::NUMBER {
+                    return Err(DosError::InvalidDrive);
+                }
+                &path[2..]
+            }
+            _ => &path[..],
+        };
+
+        let mut components = if path.starts_with('\\') {
+            Vec::new()
+        } else {
+            self.cwd.clone()
+        };
+
+        for part in path.split('\\').filter(|part| !part.is_empty()) {
+            match part {
+                "." => {}
+                ".." => {
+                    components.pop();
+                }
+                _ => components.push(part.to_ascii_uppercase()),
+            }
+        }
+        Ok(components)
+    }
+
+    /// Make a host file reachable as `path` even if it isn't below the root
+    pub fn add_alias(&mut self, path: &str, host: impl Into<PathBuf>) -> Result<(), DosError> {
+        let components = self.components(path)?;
+        self.aliases.insert(components, host.into());
+        Ok(())
+    }
+
+    /// Host path of an existing entry
+    fn lookup(&self, components: &[String]) -> Result<PathBuf, DosError> {
+        if let Some(host) = self.aliases.get(components) {
+            return Ok(host.clone());
+        }
+        let mut host = self.root.clone();
+        for (idx, component) in components.iter().enumerate() {
+            let last = idx == components.len() - 1;
+            let missing = if last {
+                DosError::FileNotFound
+            } else {
+                DosError::PathNotFound
+            };
+            let entries = short_names(&host).map_err(|_| DosError::PathNotFound)?;
+            host = entries
+                .into_iter()
+                .find(|(name, _)| name == component)
+                .map(|(_, path)| path)
+                .ok_or(missing)?;
+        }
+        Ok(host)
+    }
+
+    /// Host path for an existing file or directory
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, DosError> {
+        self.lookup(&self.components(path)?)
+    }
+
+    /// Host path for an entry that may not exist yet, its directory has to exist
+    pub fn resolve_new(&self, path: &str) -> Result<PathBuf, DosError> {
+        let mut components = self.components(path)?;
+        let name = components.pop().ok_or(DosError::PathNotFound)?;
+        let dir = self
+            .lookup(&components)
+            .map_err(|_| DosError::PathNotFound)?;
+        if !dir.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+
+        components.push(name.clone());
+        match self.lookup(&components) {
+            Ok(existing) => Ok(existing),
+            Err(_) => Ok(dir.join(name)),
+        }
+    }
+
+    pub fn change_dir(&mut self, path: &str) -> Result<(), DosError> {
+        let components = self.components(path)?;
+        let host = self
+            .lookup(&components)
+            .map_err(|_| DosError::PathNotFound)?;
+        if !host.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+        self.cwd = components;
+        Ok(())
+    }
+
+    pub fn make_dir(&mut self, path: &str) -> Result<(), DosError> {
+        let host = self.resolve_new(path)?;
+        if host.exists() {
+            return Err(DosError::AccessDenied);
+        }
+        fs::create_dir(host).map_err(|_| DosError::AccessDenied)
+    }
+
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), DosError> {
+        let components = self.components(path)?;
+        if components.is_empty() || self.cwd.starts_with(&components) {
+            return Err(DosError::CurrentDirectory);
+        }
+        let host = self
+            .lookup(&components)
+            .map_err(|_| DosError::PathNotFound)?;
+        if !host.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+        fs::remove_dir(host).map_err(|_| DosError::AccessDenied)
+    }
+
+    pub fn delete(&mut self, path: &str) -> Result<(), DosError> {
+        let host = self.resolve(path)?;
+        if host.is_dir() {
+            return Err(DosError::FileNotFound);
+        }
+        if self.attributes(path)? & ATTR_READ_ONLY != 0 {
+            return Err(DosError::AccessDenied);
+        }
+        fs::remove_file(&host)?;
+        self.attributes.remove(&host);
+        Ok(())
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), DosError> {
+        let from = self.resolve(from)?;
+        let to = self.resolve_new(to)?;
+        if to.exists() {
+            return Err(DosError::AccessDenied);
+        }
+        fs::rename(&from, &to)?;
+        if let Some(attributes) = self.attributes.remove(&from) {
+            self.attributes.insert(to, attributes);
+        }
+        Ok(())
+    }
+
+    fn host_attributes(&self, host: &Path) -> Result<u8, DosError> {
+        let metadata = fs::metadata(host)?;
+        let mut attributes = if metadata.is_dir() {
+            ATTR_DIRECTORY
+        } else {
+            self.attributes.get(host).copied().unwrap_or(ATTR_ARCHIVE)
+        };
+        if metadata.permissions().readonly() {
+            attributes |= ATTR_READ_ONLY;
+        }
+        Ok(attributes)
+    }
+
+    pub fn attributes(&self, path: &str) -> Result<u8, DosError> {
+        let host = self.resolve(path)?;
+        self.host_attributes(&host)
+    }
+
+    pub fn set_attributes(&mut self, path: &str, attributes: u8) -> Result<(), DosError> {
+        let host = self.resolve(path)?;
+        if host.is_dir() || attributes & (ATTR_DIRECTORY | ATTR_VOLUME) != 0 {
+            return Err(DosError::AccessDenied);
+        }
+
+        let mut permissions = fs::metadata(&host)?.permissions();
+        permissions.set_readonly(attributes & ATTR_READ_ONLY != 0);
+        fs::set_permissions(&host, permissions)?;
+        self.attributes.insert(
+            host,
+            attributes & (ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE),
+        );
+        Ok(())
+    }
+
+    fn entry(&self, name: String, host: &Path) -> Result<DirEntry, DosError> {
+        let metadata = fs::metadata(host)?;
+        let (time, date) = dos_datetime(local_time(metadata.modified()?));
+        Ok(DirEntry {
+            name,
+            attributes: self.host_attributes(host)?,
+            time,
+            date,
+            size: if metadata.is_dir() {
+                0
+            } else {
+                metadata.len().min(u32::MAX as u64) as u32
+            },
+        })
+    }
+
+    /// Every entry matching `pattern` that the attribute mask allows.
+    /// Hidden, system and directory entries are only returned when asked for,
+    /// the volume label is dated `now` on the guest clock
+    pub fn find(
+        &self,
+        pattern: &str,
+        attributes: u8,
+        now: NaiveDateTime,
+    ) -> Result<Vec<DirEntry>, DosError> {
+        let mut components = self.components(pattern)?;
+        let pattern = components.pop().unwrap_or_else(|| "*.*".into());
+        let dir = self
+            .lookup(&components)
+            .map_err(|_| DosError::PathNotFound)?;
+        if !dir.is_dir() {
+            return Err(DosError::PathNotFound);
+        }
+
+        if attributes == ATTR_VOLUME {
+            let (time, date) = dos_datetime(now);
+            let label = DirEntry {
+                name: VOLUME_LABEL.into(),
+                attributes: ATTR_VOLUME,
+                time,
+                date,
+                size: 0,
+            };
+            return Ok(vec![label]);
+        }
+
+        let mut entries = Vec::new();
+        if !components.is_empty() {
+            entries.push(self.entry(".".into(), &dir)?);
+            entries.push(self.entry("..".into(), dir.parent().unwrap_or(&dir))?);
+        }
+        for (name, host) in short_names(&dir)? {
+            entries.push(self.entry(name, &host)?);
+        }
+
+        let special = ATTR_HIDDEN | ATTR_SYSTEM | ATTR_DIRECTORY;
+        Ok(entries
+            .into_iter()
+            .filter(|entry| entry.attributes & special & !attributes == 0)
+            .filter(|entry| wildcard_match(&pattern, &entry.name))
+            .collect())
+    }
+
+    /// INT 21h/4Eh, returns the search id to continue with and the first match
+    pub fn find_first(
+        &mut self,
+        pattern: &str,
+        attributes: u8,
+        now: NaiveDateTime,
+    ) -> Result<(u16, DirEntry), DosError> {
+        let entries = self.find(pattern, attributes, now)?;
+        let first = entries.first().cloned().ok_or(DosError::NoMoreFiles)?;
+
+        // Programs can abandon searches whenever, keep the most recent ones around
+        let id = self.next_search;
+        self.next_search = self.next_search.wrapping_add(1);
+        self.searches.remove(&id.wrapping_sub(64));
+        self.searches.insert(id, entries);
+        Ok((id, first))
+    }
+
+    /// INT 21h/4Fh, the id and index come from the DTA
+    pub fn find_next(&mut self, id: u16, index: u16) -> Result<DirEntry, DosError> {
+        self.searches
+            .get(&id)
+            .and_then(|entries| entries.get(index as usize))
+            .cloned()
+            .ok_or(DosError::NoMoreFiles)
+    }
+}
+
+/// The disk transfer area as filled in by FindFirst/FindNext
+#[repr(C)]
+#[repr(packed)]
+#[derive(Clone, Copy)]
+pub struct FindData {
+    drive: u8,
+    template: [u8; 11],
+    search_attributes: u8,
+    /// DOS keeps the directory position here, we store the index of the next match
+    next_index: u16,
+    /// DOS keeps the directory cluster here, we store which search this is
+    search_id: u16,
+    resv: [u8; 4],
+    attributes: u8,
+    time: u16,
+    date: u16,
+    size: u32,
+    name: [u8; 13],
+}
+
+impl FindData {
+    pub fn new(search_id: u16, next_index: u16, search_attributes: u8, entry: &DirEntry) -> Self {
+        let mut name = [0; 13];
+        for (idx, c) in entry.name.bytes().take(12).enumerate() {
+            name[idx] = c;
+        }
+
+        Self {
+            drive: VirtualDrive::NUMBER + 1,
+            template: fcb_name(&entry.name),
+            search_attributes,
+            next_index,
+            search_id,
+            resv: [0; 4],
+            attributes: entry.attributes,
+            time: entry.time,
+            date: entry.date,
+            size: entry.size,
+            name,
+        }
+    }
+
+    /// Search id and next index stored in a DTA by [`FindData::new`]
+    pub fn search(bytes: &[u8]) -> (u16, u16) {
+        let index = u16::from_le_bytes([bytes[0x0d], bytes[0x0e]]);
+        let id = u16::from_le_bytes([bytes[0x0f], bytes[0x10]]);
+        (id, index)
+    }
+}
+
+impl<'a> From<&'a FindData> for &'a [u8] {
+    fn from(value: &'a FindData) -> &'a [u8] {
+        unsafe {
+            ::core::slice::from_raw_parts(
+                (value as *const FindData) as *const u8,
+                ::core::mem::size_of::<FindData>(),
+            )
+        }
+    }
+}
+
+struct OpenFile {
+    file: File,
+    /// Where the file lives on the host, the engine spots reads of the program's own file with it
+    host: PathBuf,
+    written: bool,
+    /// Set through INT 21h/57h, applied when the file is closed
+    timestamp: Option<NaiveDateTime>,
+}
+
+/// Open files, handles 0-4 are the standard devices
+pub struct FileTable {
+    files: HashMap<u16, OpenFile>,
+}
+
+impl FileTable {
+    const FIRST_HANDLE: u16 = 5;
+    /// Matches the 20 entry handle array in the PSP
+    const MAX_HANDLES: u16 = 20;
+
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+        }
+    }
+
+    fn insert(&mut self, file: File, host: &Path) -> Result<u16, DosError> {
+        let handle = (Self::FIRST_HANDLE..Self::MAX_HANDLES)
+            .find(|handle| !self.files.contains_key(handle))
+            .ok_or(DosError::TooManyOpenFiles)?;
+        let file = OpenFile {
+            file,
+            host: host.to_path_buf(),
+            written: false,
+            timestamp: None,
+        };
+        self.files.insert(handle, file);
+        Ok(handle)
+    }
+
+    /// INT 21h/3Dh, the low bits of mode are 0 = read, 1 = write, 2 = read/write
+    pub fn open(&mut self, host: &Path, mode: u8) -> Result<u16, DosError> {
+        if host.is_dir() {
+            return Err(DosError::AccessDenied);
+        }
+        let file = OpenOptions::new()
+            .read(mode & 0x3 != 1)
+            .write(mode & 0x3 != 0)
+            .open(host)?;
+        self.insert(file, host)
+    }
+
+    /// INT 21h/3Ch, truncates existing files
+    pub fn create(&mut self, host: &Path) -> Result<u16, DosError> {
+        if host.is_dir() {
+            return Err(DosError::AccessDenied);
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(host)?;
+        self.insert(file, host)
+    }
+
+    /// Like DOS the modification time is updated on close, using the guest clock
+    pub fn close(&mut self, handle: u16, now: NaiveDateTime) -> Result<(), DosError> {
+        let file = self.files.remove(&handle).ok_or(DosError::InvalidHandle)?;
+        let timestamp = match file.timestamp {
+            Some(timestamp) => timestamp,
+            None if file.written => now,
+            None => return Ok(()),
+        };
+        // Read only handles can't change the timestamp on every host, that's fine
+        let _ = file.file.set_modified(system_time(timestamp));
+        Ok(())
+    }
+
+    fn file(&mut self, handle: u16) -> Result<&mut OpenFile, DosError> {
+        self.files.get_mut(&handle).ok_or(DosError::InvalidHandle)
+    }
+
+    /// Host path the handle was opened with
+    pub fn host_path(&self, handle: u16) -> Option<&Path> {
+        self.files.get(&handle).map(|file| file.host.as_path())
+    }
+
+    pub fn read(&mut self, handle: u16, count: usize) -> Result<Vec<u8>, DosError> {
+        let mut data = Vec::with_capacity(count);
+        (&self.file(handle)?.file)
+            .take(count as u64)
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn write(&mut self, handle: u16, data: &[u8]) -> Result<usize, DosError> {
+        let file = self.file(handle)?;
+        file.written = true;
+        if data.is_empty() {
+            // A zero length write truncates the file at the current position
+            let pos = file.file.stream_position()?;
+            file.file.set_len(pos)?;
+            return Ok(0);
+        }
+        Ok(file.file.write(data)?)
+    }
+
+    /// INT 21h/42h, origin 0 = start, 1 = current, 2 = end
+    pub fn seek(&mut self, handle: u16, origin: u8, offset: i32) -> Result<u32, DosError> {
+        let pos = match origin {
+            0 => SeekFrom::Start(offset as u32 as u64),
+            1 => SeekFrom::Current(offset as i64),
+            _ => SeekFrom::End(offset as i64),
+        };
+        Ok(self.file(handle)?.file.seek(pos)? as u32)
+    }
+
+    /// INT 21h/57h AL=00, DOS (time, date) of the last modification
+    pub fn timestamp(&mut self, handle: u16) -> Result<(u16, u16), DosError> {
+        let file = self.file(handle)?;
+        let timestamp = match file.timestamp {
+            Some(timestamp) => timestamp,
+            None => local_time(file.file.metadata()?.modified()?),
+        };
+        Ok(dos_datetime(timestamp))
+    }
+
+    /// INT 21h/57h AL=01, takes effect when the file is closed
+    pub fn set_timestamp(&mut self, handle: u16, time: u16, date: u16) -> Result<(), DosError> {
+        let file = self.file(handle)?;
+        file.timestamp = Some(from_dos_datetime(time, date).ok_or(DosError::AccessDenied)?);
+        Ok(())
+    }
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use crate::drive::{
+        ATTR_DIRECTORY, ATTR_VOLUME, DosError, FileTable, VirtualDrive, dos_datetime,
+        from_dos_datetime, wildcard_match,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("unicorn_debugger_{name}_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("*.*", "README.TXT"));
+        assert!(wildcard_match("*.TXT", "README.TXT"));
+        assert!(wildcard_match("READ????.T?T", "README.TXT"));
+        assert!(wildcard_match("*", "MAKEFILE"));
+        assert!(!wildcard_match("*", "README.TXT"));
+        assert!(!wildcard_match("*.EXE", "README.TXT"));
+        assert!(!wildcard_match("R*.TXT", "LIST.TXT"));
+    }
+
+    #[test]
+    fn directories_and_search() {
+        let root = temp_dir("drive");
+        fs::write(root.join("readme.txt"), b"hello").unwrap();
+        fs::write(root.join("a long file name.data"), b"").unwrap();
+        fs::write(root.join("a long file name.docs"), b"").unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src").join("main.c"), b"").unwrap();
+
+        let mut drive = VirtualDrive::new(&root);
+        let stamp = from_dos_datetime(0x6000, 0x1c61).unwrap();
+        assert_eq!(stamp.to_string(), "1994-03-01 12:00:00");
+        let names: Vec<String> = drive
+            .find("*.*", 0, stamp)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, ["README.TXT", "ALONGF~1.DAT", "ALONGF~1.DOC"]);
+
+        let (id, first) = drive.find_first("C:\\*.*", ATTR_DIRECTORY, stamp).unwrap();
+        assert_eq!(first.name, "README.TXT");
+        assert_eq!(first.size, 5);
+        assert_eq!(drive.find_next(id, 1).unwrap().name, "SRC");
+        assert_eq!(drive.find_next(id, 4).unwrap_err(), DosError::NoMoreFiles);
+
+        drive.change_dir("src").unwrap();
+        assert_eq!(drive.current_dir(), "SRC");
+        assert_eq!(
+            drive.resolve("MAIN.C").unwrap(),
+            root.join("src").join("main.c")
+        );
+        assert_eq!(drive.remove_dir("\\SRC"), Err(DosError::CurrentDirectory));
+        assert_eq!(drive.change_dir("NOPE"), Err(DosError::PathNotFound));
+
+        drive.make_dir("OBJ").unwrap();
+        drive.rename("MAIN.C", "OBJ\\MAIN.BAK").unwrap();
+        assert!(root.join("src").join("OBJ").join("MAIN.BAK").exists());
+
+        drive.change_dir("..").unwrap();
+        assert_eq!(drive.current_dir(), "");
+        assert_eq!(drive.resolve("D:\\README.TXT"), Err(DosError::InvalidDrive));
+
+        // Written files get the guest time when they are closed
+        let mut files = FileTable::new();
+        let handle = files
+            .create(&drive.resolve_new("OUT.TXT").unwrap())
+            .unwrap();
+        files.write(handle, b"output").unwrap();
+        files.close(handle, stamp).unwrap();
+        let out = drive.find("OUT.TXT", 0, stamp).unwrap();
+        assert_eq!((out[0].time, out[0].date), dos_datetime(stamp));
+
+        // The volume label is dated by the guest clock too
+        let label = drive.find("*.*", ATTR_VOLUME, stamp).unwrap();
+        assert_eq!(label[0].name, "UNICORN");
+        assert_eq!((label[0].time, label[0].date), dos_datetime(stamp));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,8 +1,30 @@
-use crate::program::{PSP, Program};
-use std::{collections::HashMap, fmt::Display, rc::Rc};
-use unicorn_engine::{Arch, Mode, Prot, RegisterX86, Unicorn};
+use crate::{
+    bios::{BDA_ADDR, BiosDataArea, CONVENTIONAL_MEMORY_KB, ticks_since_midnight},
+    clock::Clock,
+    drive::{DosError, FileTable, FindData, VirtualDrive, dos_datetime},
+    error::Error,
+    ports::{PortDevice, PortRegistry},
+    program::{PSP, Program},
+    script,
+    services::{ServiceKey, ServiceRegistry, Support},
+    syscalls::{CallState, describe_call, describe_result},
+};
+use chrono::{Datelike, Timelike};
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    fs,
+    mem::{offset_of, size_of},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use unicorn_engine::{Arch, HookType, MemType, Mode, Prot, RegisterX86, Unicorn, uc_error};
+use yaxpeax_arch::LengthedInstruction;
+use yaxpeax_x86::real_mode::Opcode;
 
 /// Addresses are 16 bit, but u64 makes it easier to work with unicorn
+#[derive(Default)]
 pub struct Cpu {
     ax: u64,
     bx: u64,
@@ -22,24 +44,24 @@ pub struct Cpu {
 }
 
 impl Cpu {
-    fn read_engine(engine: &Unicorn<EngineData>) -> Self {
-        let ax = engine.reg_read(RegisterX86::AX).unwrap();
-        let bx = engine.reg_read(RegisterX86::BX).unwrap();
-        let cx = engine.reg_read(RegisterX86::CX).unwrap();
-        let dx = engine.reg_read(RegisterX86::DX).unwrap();
-        let si = engine.reg_read(RegisterX86::SI).unwrap();
-        let di = engine.reg_read(RegisterX86::DI).unwrap();
-        let sp = engine.reg_read(RegisterX86::SP).unwrap();
-        let bp = engine.reg_read(RegisterX86::BP).unwrap();
-        let ip = engine.reg_read(RegisterX86::IP).unwrap();
-        let cs = engine.reg_read(RegisterX86::CS).unwrap();
-        let ds = engine.reg_read(RegisterX86::DS).unwrap();
-        let es = engine.reg_read(RegisterX86::ES).unwrap();
-        let ss = engine.reg_read(RegisterX86::SS).unwrap();
-        let fs = engine.reg_read(RegisterX86::FS).unwrap();
-        let gs = engine.reg_read(RegisterX86::GS).unwrap();
+    fn read_engine(engine: &Unicorn<EngineData>) -> Result<Self, Error> {
+        let ax = engine.reg_read(RegisterX86::AX)?;
+        let bx = engine.reg_read(RegisterX86::BX)?;
+        let cx = engine.reg_read(RegisterX86::CX)?;
+        let dx = engine.reg_read(RegisterX86::DX)?;
+        let si = engine.reg_read(RegisterX86::SI)?;
+        let di = engine.reg_read(RegisterX86::DI)?;
+        let sp = engine.reg_read(RegisterX86::SP)?;
+        let bp = engine.reg_read(RegisterX86::BP)?;
+        let ip = engine.reg_read(RegisterX86::IP)?;
+        let cs = engine.reg_read(RegisterX86::CS)?;
+        let ds = engine.reg_read(RegisterX86::DS)?;
+        let es = engine.reg_read(RegisterX86::ES)?;
+        let ss = engine.reg_read(RegisterX86::SS)?;
+        let fs = engine.reg_read(RegisterX86::FS)?;
+        let gs = engine.reg_read(RegisterX86::GS)?;
 
-        Self {
+        Ok(Self {
             ax,
             bx,
             cx,
@@ -55,11 +77,16 @@ impl Cpu {
             ss,
             fs,
             gs,
-        }
+        })
     }
 
-    pub fn register(&self, register: &str) -> u64 {
-        match register {
+    /// Names accepted by `register`
+    pub const REGISTERS: [&'static str; 15] = [
+        "ax", "bx", "cx", "dx", "si", "di", "sp", "bp", "ip", "cs", "ds", "es", "ss", "fs", "gs",
+    ];
+
+    pub fn register(&self, register: &str) -> Option<u64> {
+        let value = match register {
             "ax" => self.ax,
             "bx" => self.bx,
             "cx" => self.cx,
@@ -75,8 +102,9 @@ impl Cpu {
             "ss" => self.ss,
             "fs" => self.fs,
             "gs" => self.gs,
-            _ => panic!("Unknown cpu register {register}"),
-        }
+            _ => return None,
+        };
+        Some(value)
     }
 }
 
@@ -104,16 +132,17 @@ impl Display for Cpu {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct FarPointer {
-    cs: u64,
-    ip: u64,
+    pub cs: u64,
+    pub ip: u64,
 }
 
 impl FarPointer {
-    pub fn read_engine(engine: &Unicorn<EngineData>) -> Self {
-        let cs = engine.reg_read(RegisterX86::CS).unwrap();
-        let ip = engine.reg_read(RegisterX86::IP).unwrap();
-        Self { cs, ip }
+    pub fn read_engine(engine: &Unicorn<EngineData>) -> Result<Self, Error> {
+        let cs = engine.reg_read(RegisterX86::CS)?;
+        let ip = engine.reg_read(RegisterX86::IP)?;
+        Ok(Self { cs, ip })
     }
 
     pub fn from_segment_offset(segment: u64, offset: u64) -> Self {
@@ -147,27 +176,349 @@ impl EngineBreak {
     }
 }
 
-pub struct EngineData {
+/// Why the program stopped running for good
+#[derive(Debug, Clone)]
+pub enum ExitReason {
+    /// The program terminated itself with this code
+    Exited(u8),
+    /// The program needed an interrupt or DOS service we don't emulate
+    Unimplemented(String),
+    /// A cpu exception the program can't recover from, like a divide error
+    CpuException { vector: u8, at: String },
+    /// Unicorn gave up, like when fetching from unmapped memory
+    EmulatorFault { error: String, at: String },
+}
+
+impl ExitReason {
+    /// Exit status of the emulator, the exit code of the program is passed through up to 251.
+    /// Higher codes are reported as 251 so they can't be taken for one of the emulator's own
+    pub fn host_code(&self) -> i32 {
+        match self {
+            ExitReason::Exited(code) => (*code).min(251) as i32,
+            ExitReason::Unimplemented(_) => 252,
+            ExitReason::CpuException { .. } => 253,
+            ExitReason::EmulatorFault { .. } => 254,
+        }
+    }
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitReason::Exited(code) => write!(f, "Program exited with code 0x{code:02x}"),
+            ExitReason::Unimplemented(what) => write!(f, "Aborted on unimplemented {what}"),
+            ExitReason::CpuException { vector, at } => {
+                let name = match vector {
+                    0x00 => "divide error",
+                    0x06 => "invalid opcode",
+                    _ => "exception",
+                };
+                write!(f, "Aborted on cpu {name} (INT 0x{vector:02x}) at [{at}]")
+            }
+            ExitReason::EmulatorFault { error, at } => {
+                write!(f, "Aborted on emulator fault '{error}' at [{at}]")
+            }
+        }
+    }
+}
+
+/// First segment past conventional memory, nothing gets loaded above this
+const MEMORY_END: u16 = CONVENTIONAL_MEMORY_KB * 64;
+
+/// 1 MiB and the HMA above it, all real mode can address. Anything past it is
+/// unmapped, 32 bit offsets can still reach there
+pub const MEMORY_SIZE: u64 = 0x110000;
+
+/// Timer ticks since midnight in the BDA, kept in step with the guest clock
+const TICK_COUNT_ADDR: u64 = BDA_ADDR + offset_of!(BiosDataArea, tick_count) as u64;
+
+/// Registers EXEC saves for the parent, they are restored when the child terminates
+const SAVED_REGISTERS: [RegisterX86; 11] = [
+    RegisterX86::AX,
+    RegisterX86::BX,
+    RegisterX86::CX,
+    RegisterX86::DX,
+    RegisterX86::SI,
+    RegisterX86::DI,
+    RegisterX86::BP,
+    RegisterX86::SP,
+    RegisterX86::DS,
+    RegisterX86::ES,
+    RegisterX86::SS,
+];
+
+/// State of the parent at the time it called EXEC
+struct ParentState {
+    registers: Vec<u64>,
+    flags: u64,
+    dta: (u16, u16),
+}
+
+/// A program loaded by DOS, the first one is the program we were started with
+struct Process {
     program: Rc<Program>,
+    psp: u16,
+    /// First segment after the memory owned by the process
+    end: u16,
+    /// None for the first program, it has nothing to return to
+    parent: Option<ParentState>,
+    /// Overlays read into memory, the latest read wins where they overlap
+    resident: Vec<ResidentOverlay>,
+}
+
+impl Process {
+    fn new(program: Program, parent: Option<ParentState>) -> Self {
+        let psp = program.start() as u16 - 0x10;
+        let end = (psp as u32 + 0x10 + program.paragraphs()).min(MEMORY_END as u32) as u16;
+        Self {
+            program: Rc::new(program),
+            psp,
+            end,
+            parent,
+            resident: Vec::new(),
+        }
+    }
+}
+
+/// Breakpoint relative to the load segment of a program started through EXEC,
+/// we can't know where it ends up until it's loaded
+struct ProgramBreak {
+    /// DOS file name, upper case
+    name: String,
+    offset: u64,
+}
+
+/// Part of an overlay the program read from its own executable
+#[derive(Debug, Clone, Copy)]
+pub struct ResidentOverlay {
+    pub number: u16,
+    /// Offset of the first byte in memory from the start of the overlay
+    pub offset: usize,
+    pub len: usize,
+    /// Where it was read to
+    pub segment: u64,
+    pub ip: u64,
+}
+
+impl ResidentOverlay {
+    fn addr(&self) -> u64 {
+        self.segment * 16 + self.ip
+    }
+
+    /// Memory address of an overlay relative offset if that part of the overlay is resident
+    pub fn address_of(&self, offset: usize) -> Option<u64> {
+        (self.offset..self.offset + self.len)
+            .contains(&offset)
+            .then(|| self.addr() + (offset - self.offset) as u64)
+    }
+}
+
+/// Breakpoint relative to the start of an overlay, set wherever it gets read to
+struct OverlayBreak {
+    number: u16,
+    offset: usize,
+}
+
+/// A CALL that hasn't returned yet
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    /// Where the call went, None until the first instruction there ran
+    pub function: Option<FarPointer>,
+    /// Instruction after the CALL
+    pub return_to: FarPointer,
+    /// Linear SS:SP before the CALL, the frame is gone once the stack is back here
+    stack: u64,
+}
+
+/// Interrupt that stops the emulator before it is serviced, any AH when `ah` is None
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Catchpoint {
+    pub num: u8,
+    pub ah: Option<u8>,
+}
+
+impl Catchpoint {
+    pub fn matches(&self, num: u32, ah: u64) -> bool {
+        self.num as u32 == num && self.ah.is_none_or(|want| want as u64 == ah)
+    }
+}
+
+/// CPU faults the debugger can stop at before they end the program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    Divide,
+    InvalidOpcode,
+    GeneralProtection,
+    /// Reading, writing or running memory that isn't mapped
+    Unmapped,
+}
+
+impl Fault {
+    /// Exception vector, unicorn raises these through the interrupt hook
+    pub fn vector(self) -> Option<u8> {
+        match self {
+            Fault::Divide => Some(0x00),
+            Fault::InvalidOpcode => Some(0x06),
+            Fault::GeneralProtection => Some(0x0d),
+            Fault::Unmapped => None,
+        }
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        [
+            Fault::Divide,
+            Fault::InvalidOpcode,
+            Fault::GeneralProtection,
+        ]
+        .into_iter()
+        .find(|fault| fault.vector() == Some(vector))
+    }
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Fault::Divide => "divide error",
+            Fault::InvalidOpcode => "invalid opcode",
+            Fault::GeneralProtection => "general protection fault",
+            Fault::Unmapped => "unmapped memory access",
+        })
+    }
+}
+
+/// Where the stub of a packed program handed over to the code it unpacked
+#[derive(Debug, Clone, Copy)]
+pub struct EntryPoint {
+    pub cs: u64,
+    pub ip: u64,
+    pub ss: u64,
+    pub sp: u64,
+    /// Start of the segment the stub jumped from, the unpacked code is below it
+    pub stub: u64,
+}
+
+/// Watching a packer stub run, see `Engine::run_to_entry`
+struct UnpackState {
+    /// Start of the load image
+    image: u64,
+    /// CS and address of the last instruction
+    previous: (u64, u64),
+    entry: Option<EntryPoint>,
+}
+
+pub struct EngineData {
+    /// Running programs, the last one is the current process
+    processes: Vec<Process>,
+    /// Exit code of the last child for INT 21h/4Dh
+    return_code: u16,
+    program_breaks: Vec<ProgramBreak>,
+    overlay_breaks: Vec<OverlayBreak>,
+    catchpoints: Vec<Catchpoint>,
+    /// Interrupt a catchpoint stopped at, it is serviced when the emulator resumes
+    caught: Option<u32>,
+    /// Faults unicorn stops on that stop in the debugger instead of ending the program
+    fault_catches: Vec<Fault>,
+    /// Fault a catch stopped at, what happened and how the program ends once it resumes
+    faulted: Option<(Fault, String, ExitReason)>,
+    /// Last access to unmapped memory, unicorn only reports the kind of access
+    unmapped: Option<(MemType, u64)>,
+    /// Run the program's INT 06h handler on invalid opcodes like a 286 does
+    deliver_int6: bool,
+    /// Log every interrupt call, otherwise only the ones matching `logged`
+    log_syscalls: bool,
+    logged: Vec<Catchpoint>,
     /// address -> break data
     breaks: HashMap<u64, EngineBreak>,
     /// started -> addr
     while_break: Option<(bool, u64)>,
-    exited: bool,
+    unpack: Option<UnpackState>,
+    /// Everything the programs wrote to standard output and error
+    output: Vec<u8>,
+    /// The last `trace_len` instructions that ran, oldest first
+    trace: VecDeque<FarPointer>,
+    trace_len: usize,
+    /// Calls that haven't returned, innermost last, None unless tracking calls
+    calls: Option<Vec<Frame>>,
+    /// Messages collected instead of printed, see `Engine::collect_messages`
+    messages: Option<Vec<String>>,
+    /// Set once the program can't run anymore
+    exit_reason: Option<ExitReason>,
     verbose: bool,
+    ports: PortRegistry,
+    services: ServiceRegistry,
+    drive: VirtualDrive,
+    files: FileTable,
+    /// Disk transfer area as segment, offset
+    dta: (u16, u16),
+    /// Shared with the CMOS so both report the same time
+    clock: Rc<Cell<Clock>>,
 }
 
 impl EngineData {
     fn new(program: Program) -> Self {
+        // Drive C: defaults to the directory the program lives in
+        let root = program
+            .path()
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let mut drive = VirtualDrive::new(root);
+        alias_program(&mut drive, &program);
+        // Default DTA is the command tail in the PSP
+        let dta = (program.start() as u16 - 0x10, 0x80);
+        let clock = Rc::new(Cell::new(Clock::host()));
+
         Self {
-            program: Rc::new(program),
+            processes: vec![Process::new(program, None)],
+            return_code: 0,
+            program_breaks: Vec::new(),
+            overlay_breaks: Vec::new(),
+            catchpoints: Vec::new(),
+            caught: None,
+            fault_catches: Vec::new(),
+            faulted: None,
+            unmapped: None,
+            deliver_int6: false,
+            log_syscalls: false,
+            logged: Vec::new(),
             breaks: HashMap::new(),
-            exited: false,
+            ports: PortRegistry::standard(clock.clone()),
+            services: standard_services(),
+            clock,
+            drive,
+            files: FileTable::new(),
+            dta,
+            unpack: None,
+            output: Vec::new(),
+            trace: VecDeque::new(),
+            trace_len: 0,
+            calls: None,
+            messages: None,
+            exit_reason: None,
             verbose: false,
             while_break: None,
         }
     }
 
+    /// Messages for the user are printed unless somebody is collecting them
+    fn report(&mut self, message: String) {
+        match &mut self.messages {
+            Some(messages) => messages.push(message),
+            None => println!("{message}"),
+        }
+    }
+
+    /// Record why the program stopped, reports the summary line
+    fn exit(&mut self, reason: ExitReason) {
+        self.report(reason.to_string());
+        self.exit_reason = Some(reason);
+    }
+
+    fn current(&self) -> &Process {
+        self.processes.last().unwrap()
+    }
+
     fn add_break(&mut self, ebreak: EngineBreak) {
         self.breaks.insert(ebreak.addr, ebreak);
     }
@@ -181,208 +532,2037 @@ impl EngineData {
     }
 }
 
+/// Unicorn register for a register name, the 8 bit halves included
+fn register_id(name: &str) -> Option<RegisterX86> {
+    let register = match name {
+        "ax" => RegisterX86::AX,
+        "bx" => RegisterX86::BX,
+        "cx" => RegisterX86::CX,
+        "dx" => RegisterX86::DX,
+        "si" => RegisterX86::SI,
+        "di" => RegisterX86::DI,
+        "sp" => RegisterX86::SP,
+        "bp" => RegisterX86::BP,
+        "ip" => RegisterX86::IP,
+        "cs" => RegisterX86::CS,
+        "ds" => RegisterX86::DS,
+        "es" => RegisterX86::ES,
+        "ss" => RegisterX86::SS,
+        "fs" => RegisterX86::FS,
+        "gs" => RegisterX86::GS,
+        "al" => RegisterX86::AL,
+        "ah" => RegisterX86::AH,
+        "bl" => RegisterX86::BL,
+        "bh" => RegisterX86::BH,
+        "cl" => RegisterX86::CL,
+        "ch" => RegisterX86::CH,
+        "dl" => RegisterX86::DL,
+        "dh" => RegisterX86::DH,
+        _ => return None,
+    };
+    Some(register)
+}
+
+/// Decode the instruction at the start of `code`, its text and length
+fn disassemble(code: &[u8]) -> (String, usize) {
+    let decoder = yaxpeax_x86::real_mode::InstDecoder::default();
+    match decoder.decode_slice(code) {
+        Ok(inst) => (inst.to_string(), inst.len().to_const() as usize),
+        Err(err) => (format!("({err})"), 1),
+    }
+}
+
+/// Read guest memory, reading past what we mapped reports the address
+fn read_bytes(emu: &Unicorn<EngineData>, addr: u64, len: usize) -> Result<Vec<u8>, Error> {
+    emu.mem_read_as_vec(addr, len).map_err(|err| match err {
+        uc_error::READ_UNMAPPED => Error::UnmappedMemory(addr),
+        err => Error::Unicorn(err),
+    })
+}
+
+fn read_u16(emu: &Unicorn<EngineData>, addr: u64) -> Result<u16, Error> {
+    let data = read_bytes(emu, addr, 2)?;
+    Ok(u16::from_le_bytes([data[0], data[1]]))
+}
+
+fn read_u32(emu: &Unicorn<EngineData>, addr: u64) -> Result<u32, Error> {
+    let data = read_bytes(emu, addr, 4)?;
+    Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+}
+
+fn read_far_pointer(emu: &Unicorn<EngineData>, addr: u64) -> Result<FarPointer, Error> {
+    let offset = read_u16(emu, addr)? as u64;
+    let segment = read_u16(emu, addr + 2)? as u64;
+    Ok(FarPointer::from_segment_offset(segment, offset))
+}
+
+/// Read a NUL terminated string, DOS paths can't be longer than 128 bytes
+fn read_asciiz(emu: &Unicorn<EngineData>, addr: u64) -> Result<String, Error> {
+    let data = read_bytes(emu, addr, 128)?;
+    let end = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+}
+
+fn set_carry(emu: &mut Unicorn<EngineData>, carry: bool) -> Result<(), Error> {
+    let flags = emu.reg_read(RegisterX86::FLAGS)?;
+    let flags = if carry { flags | 1 } else { flags & !1 };
+    emu.reg_write(RegisterX86::FLAGS, flags)?;
+    Ok(())
+}
+
+/// Finish a DOS call, errors set the carry flag and put the error code in AX
+fn dos_result<T>(
+    emu: &mut Unicorn<EngineData>,
+    result: Result<T, DosError>,
+) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => {
+            set_carry(emu, false)?;
+            Ok(Some(value))
+        }
+        Err(err) => {
+            if emu.get_data().verbose {
+                emu.get_data_mut()
+                    .report(format!("DOS call failed with {err:?}"));
+            }
+            set_carry(emu, true)?;
+            emu.reg_write(RegisterX86::AX, err.code() as u64)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Fill the DTA with a FindFirst/FindNext match
+fn write_find_data(emu: &mut Unicorn<EngineData>, find_data: &FindData) -> Result<(), Error> {
+    let (segment, offset) = emu.get_data().dta;
+    let data: &[u8] = find_data.into();
+    emu.mem_write(segment as u64 * 16 + offset as u64, data)?;
+    Ok(())
+}
+
+/// Path the program finds itself under, C:\ and the upper case file name
+fn dos_path(program: &Program) -> String {
+    let name = program
+        .path()
+        .file_name()
+        .map(|name| name.to_string_lossy().to_uppercase())
+        .unwrap_or_default();
+    format!("C:\\{name}")
+}
+
+/// Keep the program's own file readable when the drive is mounted somewhere else,
+/// overlay managers open it to read the overlays
+fn alias_program(drive: &mut VirtualDrive, program: &Program) {
+    // The name always has a drive letter we know, it can't fail
+    let _ = drive.add_alias(&dos_path(program), program.path());
+}
+
+/// Environment block of the first program, the variables are followed by the
+/// path of the program like DOS 3 does, overlay managers look for themselves there
+fn environment(program: &Program) -> Vec<u8> {
+    let mut env = b"PATH=C:\\\0\0".to_vec();
+    env.extend_from_slice(&1u16.to_le_bytes());
+    env.extend_from_slice(dos_path(program).as_bytes());
+    env.push(0);
+    env
+}
+
+/// Copy a program into memory with its PSP in the 256 bytes before the image
+fn load_program(emu: &mut Unicorn<EngineData>, program: &Program, psp: &PSP) -> Result<(), Error> {
+    let start = program.start() * 16;
+    emu.mem_write(start, program.data())?;
+    let psp_data: &[u8] = psp.into();
+    emu.mem_write(start - 256, psp_data)?;
+    Ok(())
+}
+
+/// Point the cpu at the entry point of a loaded program, DS and ES hold the PSP segment
+fn enter_program(emu: &mut Unicorn<EngineData>, program: &Program) -> Result<(), Error> {
+    let header = program.header();
+    let start = program.start();
+    emu.reg_write(RegisterX86::SS, header.initial_ss as u64 + start)?;
+    emu.reg_write(RegisterX86::SP, header.initial_sp as u64)?;
+    emu.reg_write(RegisterX86::DS, start - 0x10)?;
+    emu.reg_write(RegisterX86::ES, start - 0x10)?;
+    emu.reg_write(RegisterX86::CS, header.initial_cs as u64 + start)?;
+    emu.reg_write(RegisterX86::IP, header.initial_ip as u64)?;
+    Ok(())
+}
+
+/// INT 21h/4Bh, AL=00 loads and runs a child program, AL=01 only loads it and
+/// AL=03 loads an overlay into memory the caller already owns.
+/// The inner error goes back to the program, the outer one stops the emulator
+fn exec(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<Result<(), DosError>, Error> {
+    let al = cpu.ax & 0xff;
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let params = cpu.es * 16 + cpu.bx;
+    let host = match emu.get_data().drive.resolve(&path) {
+        Ok(host) => host,
+        Err(err) => return Ok(Err(err)),
+    };
+
+    // Anything Program can't load, like .COM files, is reported as an invalid format
+    let host = host.to_string_lossy();
+
+    if al == 3 {
+        let segment = read_u16(emu, params)? as u64;
+        let relocation = read_u16(emu, params + 2)? as u64;
+        let Ok(overlay) = Program::new(&host, relocation) else {
+            return Ok(Err(DosError::InvalidFormat));
+        };
+        emu.mem_write(segment * 16, overlay.data())?;
+        return Ok(Ok(()));
+    } else if al != 0 && al != 1 {
+        return Ok(Err(DosError::InvalidFunction));
+    }
+
+    // The child goes right after the memory of the parent
+    let parent_psp = emu.get_data().current().psp;
+    let psp_segment = emu.get_data().current().end;
+    let Ok(program) = Program::new(&host, psp_segment as u64 + 0x10) else {
+        return Ok(Err(DosError::InvalidFormat));
+    };
+    if psp_segment as u32 + 0x10 + program.paragraphs() > MEMORY_END as u32 {
+        let available = MEMORY_END.saturating_sub(psp_segment);
+        emu.reg_write(RegisterX86::BX, available as u64)?;
+        return Ok(Err(DosError::InsufficientMemory));
+    }
+
+    let env_segment = match read_u16(emu, params)? {
+        0 => read_u16(
+            emu,
+            parent_psp as u64 * 16 + offset_of!(PSP, env_segment_addr) as u64,
+        )?,
+        env => env,
+    };
+    let tail_addr = read_far_pointer(emu, params + 2)?.address();
+    let tail_len = read_bytes(emu, tail_addr, 1)?[0] as usize;
+    let tail = read_bytes(emu, tail_addr + 1, tail_len)?;
+
+    let mut psp = PSP::new(MEMORY_END, 0x0);
+    psp.set_command_tail(&tail);
+    psp.env_segment_addr = env_segment;
+    psp.parent_addr = parent_psp;
+    psp.prev_psp = (parent_psp as u32) << 16;
+    // The child returns to the instruction after the INT 21h, INT 22h points there
+    // while it runs and the parent's Ctrl-Break and critical error handlers are inherited
+    let return_addr = ((cpu.cs as u32) << 16) | cpu.ip as u32;
+    emu.mem_write(EXIT_VECTORS_ADDR, &return_addr.to_le_bytes())?;
+    psp.term_addr = return_addr;
+    psp.ctrl_break_addr = read_u32(emu, EXIT_VECTORS_ADDR + 4)?;
+    psp.crit_err_addr = read_u32(emu, EXIT_VECTORS_ADDR + 8)?;
+    psp.stack_save = ((cpu.ss as u32) << 16) | cpu.sp as u32;
+    load_program(emu, &program, &psp)?;
+
+    let psp_addr = psp_segment as u64 * 16;
+    for (field, fcb) in [(0x06, 0x5c), (0x0a, 0x6c)] {
+        let fcb_addr = read_far_pointer(emu, params + field)?.address();
+        let data = read_bytes(emu, fcb_addr, 16)?;
+        emu.mem_write(psp_addr + fcb, &data)?;
+    }
+
+    let name = path.rsplit(['\\', '/', ':']).next().unwrap_or(&path);
+    let name = name.to_uppercase();
+    let load_segment = program.start();
+    emu.get_data_mut().report(format!(
+        "EXEC '{path}' loaded at segment {load_segment:04x}, entry [{}]",
+        FarPointer::from_segment_offset(
+            program.header().initial_cs as u64 + load_segment,
+            program.header().initial_ip as u64
+        )
+    ));
+    let breaks: Vec<u64> = emu
+        .get_data()
+        .program_breaks
+        .iter()
+        .filter(|pbreak| pbreak.name == name)
+        .map(|pbreak| load_segment * 16 + pbreak.offset)
+        .collect();
+    for addr in breaks {
+        emu.get_data_mut().add_break(EngineBreak::new(addr));
+    }
+
+    let mut registers = Vec::with_capacity(SAVED_REGISTERS.len());
+    for reg in SAVED_REGISTERS {
+        registers.push(emu.reg_read(reg)?);
+    }
+    let parent = ParentState {
+        registers,
+        flags: emu.reg_read(RegisterX86::FLAGS)?,
+        dta: emu.get_data().dta,
+    };
+    let data = emu.get_data_mut();
+    data.dta = (psp_segment, 0x80);
+    data.processes.push(Process::new(program, Some(parent)));
+    let program = data.current().program.clone();
+
+    if al == 1 {
+        // The caller starts the child itself with these
+        let header = program.header();
+        let ss = header.initial_ss + load_segment as u16;
+        let cs = header.initial_cs + load_segment as u16;
+        emu.mem_write(params + 0x0e, &header.initial_sp.to_le_bytes())?;
+        emu.mem_write(params + 0x10, &ss.to_le_bytes())?;
+        emu.mem_write(params + 0x12, &header.initial_ip.to_le_bytes())?;
+        emu.mem_write(params + 0x14, &cs.to_le_bytes())?;
+    } else {
+        enter_program(emu, &program)?;
+        // AL and AH report whether the drives in the two FCBs are valid
+        emu.reg_write(RegisterX86::AX, 0)?;
+    }
+
+    Ok(Ok(()))
+}
+
+/// Note which overlays a read from the program's own executable brought in and
+/// set the breakpoints that were waiting for them. The read went to DS:DX
+fn load_overlays(
+    emu: &mut Unicorn<EngineData>,
+    handle: u16,
+    pos: usize,
+    cpu: &Cpu,
+    len: usize,
+) -> Result<(), Error> {
+    let data = emu.get_data_mut();
+    let program = data.current().program.clone();
+    if program.overlays().is_empty() || len == 0 {
+        return Ok(());
+    }
+    let Some(host) = data.files.host_path(handle) else {
+        return Ok(());
+    };
+    let own_file = match (fs::canonicalize(host), fs::canonicalize(program.path())) {
+        (Ok(host), Ok(path)) => host == path,
+        _ => false,
+    };
+    if !own_file {
+        return Ok(());
+    }
+
+    let verbose = data.verbose;
+    let start = cpu.ds * 16 + cpu.dx;
+    let end = start + len as u64;
+    let process = data.processes.last_mut().unwrap();
+    // Whatever was there before got overwritten
+    process.resident.retain(|resident| {
+        resident.addr() + resident.len as u64 <= start || end <= resident.addr()
+    });
+
+    let mut loaded = Vec::new();
+    for overlay in program.overlays() {
+        if !overlay.intersects(pos, len) {
+            continue;
+        }
+        let first = pos.max(overlay.offset);
+        let last = (pos + len).min(overlay.offset + overlay.size);
+        let resident = ResidentOverlay {
+            number: overlay.number,
+            offset: first - overlay.offset,
+            len: last - first,
+            segment: cpu.ds,
+            ip: cpu.dx + (first - pos) as u64,
+        };
+        process.resident.push(resident);
+        loaded.push(resident);
+    }
+    if verbose {
+        for resident in &loaded {
+            data.report(format!(
+                "Overlay {} bytes {:x}-{:x} read to [{}]",
+                resident.number,
+                resident.offset,
+                resident.offset + resident.len,
+                FarPointer::from_segment_offset(resident.segment, resident.ip)
+            ));
+        }
+    }
+
+    let breaks: Vec<u64> = data
+        .overlay_breaks
+        .iter()
+        .flat_map(|obreak| {
+            loaded
+                .iter()
+                .filter(|resident| resident.number == obreak.number)
+                .filter_map(|resident| resident.address_of(obreak.offset))
+        })
+        .collect();
+    for addr in breaks {
+        data.add_break(EngineBreak::new(addr));
+    }
+    Ok(())
+}
+
+/// The INT 22h, 23h and 24h vectors, the PSP keeps a copy of all three in the same order
+const EXIT_VECTORS_ADDR: u64 = 0x22 * 4;
+const EXIT_VECTORS_LEN: usize = 12;
+
+/// End the current process, a child goes back to its parent through the
+/// terminate address in its PSP, the first program stops the emulator.
+/// Resident programs keep the given number of paragraphs from their PSP
+fn terminate(emu: &mut Unicorn<EngineData>, code: u8, resident: Option<u16>) -> Result<(), Error> {
+    let data = emu.get_data_mut();
+    // AH of INT 21h/4Dh, 3 is terminate and stay resident
+    let kind = if resident.is_some() { 3 } else { 0 };
+    data.return_code = (kind << 8) | code as u16;
+    if data.processes.len() == 1 {
+        if resident.is_some() {
+            data.report("Program is staying resident, nothing to return to".into());
+        }
+        data.exit(ExitReason::Exited(code));
+        emu.emu_stop()?;
+        return Ok(());
+    }
+
+    let process = data.processes.pop().unwrap();
+    let parent = process.parent.unwrap();
+    data.dta = parent.dta;
+    if let Some(paragraphs) = resident {
+        // The parent continues after the memory the child keeps
+        let end = (process.psp as u32 + paragraphs as u32).min(MEMORY_END as u32) as u16;
+        let parent_process = data.processes.last_mut().unwrap();
+        parent_process.end = parent_process.end.max(end);
+    }
+    emu.get_data_mut().report(format!(
+        "Child '{}' terminating with code '0x{code:x}', returning to parent",
+        process.program.path().display()
+    ));
+
+    // Put back the handlers the parent had, the child may have replaced them
+    let psp_addr = process.psp as u64 * 16;
+    let vectors = read_bytes(
+        emu,
+        psp_addr + offset_of!(PSP, term_addr) as u64,
+        EXIT_VECTORS_LEN,
+    )?;
+    emu.mem_write(EXIT_VECTORS_ADDR, &vectors)?;
+
+    let term_addr = read_far_pointer(emu, psp_addr + offset_of!(PSP, term_addr) as u64)?;
+    for (reg, value) in SAVED_REGISTERS.iter().zip(parent.registers) {
+        emu.reg_write(*reg, value)?;
+    }
+    // EXEC succeeded, the carry flag the child left behind isn't the parent's
+    emu.reg_write(RegisterX86::FLAGS, parent.flags & !1)?;
+    emu.reg_write(RegisterX86::CS, term_addr.cs)?;
+    emu.reg_write(RegisterX86::IP, term_addr.ip)?;
+    Ok(())
+}
+
+/// A hook failed, there's no telling what state the program is in so stop it
+fn fault(emu: &mut Unicorn<EngineData>, err: Error) {
+    let at = FarPointer::read_engine(emu)
+        .map(|fp| fp.to_string())
+        .unwrap_or_default();
+    emu.get_data_mut().exit(ExitReason::EmulatorFault {
+        error: err.to_string(),
+        at,
+    });
+    let _ = emu.emu_stop();
+}
+
+/// Run the program's handler for a CPU exception like the hardware does, the
+/// handler returns to the faulting instruction. False when the vector is still 0:0
+fn raise_exception(emu: &mut Unicorn<EngineData>, vector: u8) -> Result<bool, Error> {
+    let handler = read_far_pointer(emu, vector as u64 * 4)?;
+    if handler.cs == 0 && handler.ip == 0 {
+        return Ok(false);
+    }
+    let cpu = Cpu::read_engine(emu)?;
+    let flags = emu.reg_read(RegisterX86::FLAGS)?;
+    let sp = cpu.sp.wrapping_sub(6) & 0xffff;
+    let mut frame = Vec::new();
+    for word in [cpu.ip, cpu.cs, flags] {
+        frame.extend_from_slice(&(word as u16).to_le_bytes());
+    }
+    emu.mem_write(cpu.ss * 16 + sp, &frame)?;
+    emu.reg_write(RegisterX86::SP, sp)?;
+    // Interrupts and single stepping are off in the handler
+    emu.reg_write(RegisterX86::FLAGS, flags & !0x300)?;
+    emu.reg_write(RegisterX86::CS, handler.cs)?;
+    emu.reg_write(RegisterX86::IP, handler.ip)?;
+    Ok(true)
+}
+
+/// Write the ticks since midnight of the guest clock to the BDA
+fn update_tick_count(emu: &mut Unicorn<EngineData>) -> Result<(), Error> {
+    let ticks = ticks_since_midnight(emu.get_data().clock.get().now().time());
+    emu.mem_write(TICK_COUNT_ADDR, &ticks.to_le_bytes())?;
+    Ok(())
+}
+
+/// Packer stubs move themselves up in memory and decompress the program below,
+/// the first far jump down into the load image goes to the original entry point
+fn check_entry(emu: &mut Unicorn<EngineData>, fp: &FarPointer) -> Result<bool, Error> {
+    let Some(state) = emu.get_data().unpack.as_ref() else {
+        return Ok(false);
+    };
+    let (previous_cs, previous) = state.previous;
+    let addr = fp.address();
+    let entered = fp.cs != previous_cs && addr < previous && addr >= state.image;
+    let image = state.image;
+
+    let entry = if entered {
+        Some(EntryPoint {
+            cs: fp.cs,
+            ip: fp.ip,
+            ss: emu.reg_read(RegisterX86::SS)?,
+            sp: emu.reg_read(RegisterX86::SP)?,
+            stub: (previous_cs * 16).max(image),
+        })
+    } else {
+        None
+    };
+    let state = emu.get_data_mut().unpack.as_mut().unwrap();
+    state.previous = (fp.cs, addr);
+    if entry.is_some() {
+        state.entry = entry;
+        emu.get_data_mut()
+            .report(format!("Stub jumped to the original entry point at [{fp}]"));
+        emu.emu_stop()?;
+    }
+    Ok(entered)
+}
+
+/// Keep a shadow stack of CALLs, RET, RETF and longjmps all leave the stack
+/// pointer above the frames they unwind
+fn track_call(
+    emu: &mut Unicorn<EngineData>,
+    fp: &FarPointer,
+    addr: u64,
+    len: u32,
+) -> Result<(), Error> {
+    let stack = emu.reg_read(RegisterX86::SS)? * 16 + emu.reg_read(RegisterX86::SP)?;
+    let decoder = yaxpeax_x86::real_mode::InstDecoder::default();
+    let is_call = decoder
+        .decode_slice(&read_bytes(emu, addr, len as usize)?)
+        .is_ok_and(|inst| matches!(inst.opcode(), Opcode::CALL | Opcode::CALLF));
+
+    let calls = emu.get_data_mut().calls.as_mut().unwrap();
+    while calls.last().is_some_and(|frame| stack >= frame.stack) {
+        calls.pop();
+    }
+    if let Some(frame) = calls.last_mut()
+        && frame.function.is_none()
+    {
+        frame.function = Some(*fp);
+    }
+    if is_call {
+        calls.push(Frame {
+            function: None,
+            return_to: FarPointer::from_segment_offset(fp.cs, fp.ip + len as u64),
+            stack,
+        });
+    }
+    Ok(())
+}
+
+fn code_hook(emu: &mut Unicorn<EngineData>, addr: u64, len: u32) -> Result<(), Error> {
+    let fp = FarPointer::read_engine(emu)?;
+    if check_entry(emu, &fp)? {
+        return Ok(());
+    }
+    if emu.get_data().calls.is_some() {
+        track_call(emu, &fp, addr, len)?;
+    }
+    let data = emu.get_data_mut();
+    if data.trace_len > 0 {
+        if data.trace.len() == data.trace_len {
+            data.trace.pop_front();
+        }
+        data.trace.push_back(fp);
+    }
+    if emu.get_data().verbose {
+        let (inst, _) = disassemble(&read_bytes(emu, addr, len as usize)?);
+        emu.get_data_mut()
+            .report(format!("code exec: [{fp}]: {inst}"));
+    }
+
+    let has_break = emu.get_data().get_break(addr).is_some();
+    if has_break {
+        let is_intr = emu.get_data().get_break(addr).unwrap().intr;
+        if !is_intr {
+            emu.get_data_mut().report(format!("breaking at [{fp}]"));
+            emu.emu_stop()?;
+            if emu.get_data().while_break.is_some_and(|wb| wb.1 == addr) {
+                emu.get_data_mut().while_break = Some((true, addr));
+            }
+        }
+        let ebreak = emu.get_data_mut().get_break_mut(addr).unwrap();
+        ebreak.intr = !ebreak.intr;
+    } else if emu.get_data().while_break.is_some_and(|wb| wb.0) {
+        emu.get_data_mut()
+            .report(format!("stopping after while break at [{fp}]"));
+        emu.get_data_mut().while_break = None;
+        emu.emu_stop()?;
+    }
+    Ok(())
+}
+
+/// Stop before servicing interrupts somebody wants to look at first
+fn intr_hook(emu: &mut Unicorn<EngineData>, num: u32) -> Result<(), Error> {
+    let ah = emu.reg_read(RegisterX86::AH)?;
+    let data = emu.get_data_mut();
+    if data.catchpoints.iter().any(|catch| catch.matches(num, ah)) {
+        data.caught = Some(num);
+        emu.emu_stop()?;
+        return Ok(());
+    }
+    interrupt(emu, num)
+}
+
+/// Registers and memory of a call for the syscall log
+struct EmuCallState<'a, 'u> {
+    emu: &'a Unicorn<'u, EngineData>,
+    cpu: Cpu,
+    flags: u64,
+}
+
+impl<'a, 'u> EmuCallState<'a, 'u> {
+    fn read(emu: &'a Unicorn<'u, EngineData>) -> Result<Self, Error> {
+        Ok(Self {
+            cpu: Cpu::read_engine(emu)?,
+            flags: emu.reg_read(RegisterX86::FLAGS)?,
+            emu,
+        })
+    }
+}
+
+impl CallState for EmuCallState<'_, '_> {
+    fn reg(&self, name: &str) -> u64 {
+        script::register(&self.cpu, name).unwrap_or_default() as u64
+    }
+
+    fn string(&self, segment: &str, offset: &str) -> String {
+        let addr = self.reg(segment) * 16 + self.reg(offset);
+        read_asciiz(self.emu, addr).unwrap_or_else(|_| "?".into())
+    }
+
+    fn carry(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+/// INT instructions, we emulate the BIOS and DOS services registered for the
+/// call instead of running handlers from the IVT
+fn interrupt(emu: &mut Unicorn<EngineData>, num: u32) -> Result<(), Error> {
+    let cpu = Cpu::read_engine(emu)?;
+    let (int, ah, al) = (num as u8, (cpu.ax >> 8) as u8, cpu.ax as u8);
+    let data = emu.get_data();
+    let call = if data.log_syscalls || data.logged.iter().any(|c| c.matches(num, ah as u64)) {
+        Some(describe_call(int, &EmuCallState::read(emu)?))
+    } else {
+        None
+    };
+
+    let data = emu.get_data();
+    let Some(service) = data.services.find(int, ah, al).cloned() else {
+        let what = if data.services.handles(int) {
+            format!("INT 0x{int:x} ah 0x{ah:x}")
+        } else {
+            format!("interrupt 0x{int:x}")
+        };
+        if let Some(call) = call {
+            emu.get_data_mut()
+                .report(format!("{call} -> unimplemented"));
+        }
+        emu.get_data_mut().exit(ExitReason::Unimplemented(what));
+        emu.emu_stop()?;
+        return Ok(());
+    };
+    if data.verbose {
+        let fp = FarPointer::read_engine(emu)?;
+        emu.get_data_mut()
+            .report(format!("{} {} at [{fp}]", service.key, service.name));
+    }
+    (service.handler)(emu, &cpu)?;
+
+    if let Some(call) = call {
+        // Calls that end the program never return
+        let terminates = matches!((int, ah), (0x20 | 0x27, _) | (0x21, 0x00 | 0x31 | 0x4c));
+        let line = if terminates || emu.get_data().exit_reason.is_some() {
+            call
+        } else {
+            let result = describe_result(int, ah, &EmuCallState::read(emu)?);
+            format!("{call} -> {result}")
+        };
+        emu.get_data_mut().report(line);
+    }
+    Ok(())
+}
+
+/// INT 20h and INT 21h/00h terminate program
+fn dos_terminate(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    terminate(emu, 0, None)?;
+    Ok(())
+}
+
+/// INT 21h/0Eh select disk
+fn dos_select_disk(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    // Only C: exists, selecting anything else is silently ignored like DOS does
+    emu.reg_write(RegisterX86::AL, 3)?;
+    Ok(())
+}
+
+/// INT 21h/19h get current drive
+fn dos_current_drive(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    emu.reg_write(RegisterX86::AL, VirtualDrive::NUMBER as u64)?;
+    Ok(())
+}
+
+/// INT 21h/1Ah set disk transfer area
+fn dos_set_dta(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    emu.get_data_mut().dta = (cpu.ds as u16, cpu.dx as u16);
+    Ok(())
+}
+
+/// INT 21h/25h set interrupt vector
+fn dos_set_vector(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    // Vectors are stored offset first, then segment
+    let al = cpu.ax & 0xff;
+    let handler_ptr = ((cpu.ds << 16) | cpu.dx) as u32;
+    emu.mem_write(al * 4, &handler_ptr.to_le_bytes())?;
+    Ok(())
+}
+
+/// INT 21h/2Ah get date
+fn dos_get_date(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let now = emu.get_data().clock.get().now();
+    let day_of_week = now.weekday().num_days_from_sunday();
+    emu.reg_write(RegisterX86::CX, now.year() as u64)?;
+    emu.reg_write(RegisterX86::DX, ((now.month() << 8) | now.day()) as u64)?;
+    emu.reg_write(RegisterX86::AL, day_of_week as u64)?;
+    Ok(())
+}
+
+/// INT 21h/2Bh set date
+fn dos_set_date(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let mut clock = emu.get_data().clock.get();
+    let valid = clock.set_date(cpu.cx as i32, (cpu.dx >> 8) as u32, (cpu.dx & 0xff) as u32);
+    emu.get_data().clock.set(clock);
+    emu.reg_write(RegisterX86::AL, if valid { 0 } else { 0xff })?;
+    Ok(())
+}
+
+/// INT 21h/2Ch get time
+fn dos_get_time(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let now = emu.get_data().clock.get().now();
+    let hundredths = now.nanosecond() % 1_000_000_000 / 10_000_000;
+    emu.reg_write(RegisterX86::CX, ((now.hour() << 8) | now.minute()) as u64)?;
+    emu.reg_write(RegisterX86::DX, ((now.second() << 8) | hundredths) as u64)?;
+    Ok(())
+}
+
+/// INT 21h/2Dh set time
+fn dos_set_time(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let mut clock = emu.get_data().clock.get();
+    let valid = clock.set_time(
+        (cpu.cx >> 8) as u32,
+        (cpu.cx & 0xff) as u32,
+        (cpu.dx >> 8) as u32,
+        (cpu.dx & 0xff) as u32,
+    );
+    emu.get_data().clock.set(clock);
+    emu.reg_write(RegisterX86::AL, if valid { 0 } else { 0xff })?;
+    Ok(())
+}
+
+/// INT 21h/2Fh get disk transfer area
+fn dos_get_dta(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let (segment, offset) = emu.get_data().dta;
+    emu.reg_write(RegisterX86::ES, segment as u64)?;
+    emu.reg_write(RegisterX86::BX, offset as u64)?;
+    Ok(())
+}
+
+/// INT 21h/30h get dos version
+fn dos_version(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    // TXLIST.EXE is checking for DOS version 2 so lets set the dos version to that for now,
+    // the minor version in AH is 0
+    emu.reg_write(RegisterX86::AX, 0x0002)?;
+    Ok(())
+}
+
+/// INT 21h/31h terminate and stay resident
+fn dos_keep(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    terminate(emu, cpu.ax as u8, Some(cpu.dx as u16))?;
+    Ok(())
+}
+
+/// INT 21h/35h get interrupt vector
+fn dos_get_vector(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let al = cpu.ax & 0xff;
+    let handler = read_far_pointer(emu, al * 4)?;
+    emu.reg_write(RegisterX86::BX, handler.ip)?;
+    emu.reg_write(RegisterX86::ES, handler.cs)?;
+    Ok(())
+}
+
+/// INT 21h/39h create directory
+fn dos_make_dir(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let result = emu.get_data_mut().drive.make_dir(&path);
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/3Ah remove directory
+fn dos_remove_dir(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let result = emu.get_data_mut().drive.remove_dir(&path);
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/3Bh change directory
+fn dos_change_dir(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let result = emu.get_data_mut().drive.change_dir(&path);
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/3Ch create file
+fn dos_create(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let data = emu.get_data_mut();
+    let result = data
+        .drive
+        .resolve_new(&path)
+        .and_then(|host| data.files.create(&host))
+        .and_then(|handle| {
+            if cpu.cx != 0 {
+                data.drive.set_attributes(&path, cpu.cx as u8)?;
+            }
+            Ok(handle)
+        });
+    if let Some(handle) = dos_result(emu, result)? {
+        emu.reg_write(RegisterX86::AX, handle as u64)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/3Dh open file
+fn dos_open(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let data = emu.get_data_mut();
+    let result = data
+        .drive
+        .resolve(&path)
+        .and_then(|host| data.files.open(&host, cpu.ax as u8));
+    if let Some(handle) = dos_result(emu, result)? {
+        emu.reg_write(RegisterX86::AX, handle as u64)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/3Eh close file
+fn dos_close(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    // Closing the standard devices is allowed and does nothing
+    let now = emu.get_data().clock.get().now();
+    let result = if cpu.bx < 5 {
+        Ok(())
+    } else {
+        emu.get_data_mut().files.close(cpu.bx as u16, now)
+    };
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/3Fh read file
+fn dos_read(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let handle = cpu.bx as u16;
+    // Where in the file the read starts, overlays are found by their file offset
+    let pos = if handle < 5 {
+        None
+    } else {
+        emu.get_data_mut().files.seek(handle, 1, 0).ok()
+    };
+    // Standard input isn't connected to anything, it's always at the end
+    let result = if cpu.bx < 5 {
+        Ok(Vec::new())
+    } else {
+        emu.get_data_mut()
+            .files
+            .read(cpu.bx as u16, cpu.cx as usize)
+    };
+    if let Some(data) = dos_result(emu, result)? {
+        emu.mem_write(cpu.ds * 16 + cpu.dx, &data)?;
+        emu.reg_write(RegisterX86::AX, data.len() as u64)?;
+        if let Some(pos) = pos {
+            load_overlays(emu, handle, pos as usize, cpu, data.len())?;
+        }
+    }
+    Ok(())
+}
+
+/// INT 21h/40h write file
+fn dos_write(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let ds = cpu.ds;
+    let dx = cpu.dx;
+    let addr = ds * 16 + dx;
+    let data = read_bytes(emu, addr, cpu.cx as usize)?;
+    let result = if cpu.bx < 5 {
+        emu.get_data_mut().report(format!(
+            "Write to fd '{}', string: '{}'",
+            cpu.bx,
+            String::from_utf8_lossy(&data)
+        ));
+        if cpu.bx == 1 || cpu.bx == 2 {
+            emu.get_data_mut().output.extend_from_slice(&data);
+        }
+        Ok(data.len())
+    } else {
+        emu.get_data_mut().files.write(cpu.bx as u16, &data)
+    };
+    if let Some(written) = dos_result(emu, result)? {
+        emu.reg_write(RegisterX86::AX, written as u64)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/41h delete file
+fn dos_delete(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let result = emu.get_data_mut().drive.delete(&path);
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/42h move file pointer
+fn dos_seek(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let al = cpu.ax & 0xff;
+    let offset = ((cpu.cx << 16) | cpu.dx) as i32;
+    let result = emu
+        .get_data_mut()
+        .files
+        .seek(cpu.bx as u16, al as u8, offset);
+    if let Some(pos) = dos_result(emu, result)? {
+        emu.reg_write(RegisterX86::DX, (pos >> 16) as u64)?;
+        emu.reg_write(RegisterX86::AX, (pos & 0xffff) as u64)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/43h get/set file attributes
+fn dos_attributes(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let al = cpu.ax & 0xff;
+    let path = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let drive = &mut emu.get_data_mut().drive;
+    let result = if al == 0 {
+        drive.attributes(&path)
+    } else {
+        drive
+            .set_attributes(&path, cpu.cx as u8)
+            .map(|_| cpu.cx as u8)
+    };
+    if let Some(attributes) = dos_result(emu, result)? {
+        emu.reg_write(RegisterX86::CX, attributes as u64)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/44h ioctl
+fn dos_ioctl(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let al = cpu.ax & 0xff;
+    if cpu.bx > 4 {
+        emu.get_data_mut().exit(ExitReason::Unimplemented(format!(
+            "IOCTL on file handle {}, only default handles are supported",
+            cpu.bx
+        )));
+        emu.emu_stop()?;
+        return Ok(());
+    }
+
+    if al == 0 {
+        // Mark device as character device
+        emu.reg_write(RegisterX86::DX, 0x80)?;
+    } else {
+        emu.get_data_mut().exit(ExitReason::Unimplemented(format!(
+            "IOCTL function 0x{al:x}"
+        )));
+        emu.emu_stop()?;
+    }
+    Ok(())
+}
+
+/// INT 21h/47h get current directory
+fn dos_current_dir(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    // DL is 0 for the default drive, 1 = A:
+    let dl = cpu.dx & 0xff;
+    let result = if dl == 0 || dl == VirtualDrive::NUMBER as u64 + 1 {
+        Ok(emu.get_data().drive.current_dir())
+    } else {
+        Err(DosError::InvalidDrive)
+    };
+    if let Some(dir) = dos_result(emu, result)? {
+        let mut dir = dir.into_bytes();
+        dir.truncate(63);
+        dir.push(0);
+        emu.mem_write(cpu.ds * 16 + cpu.si, &dir)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/4Ah resize memory block
+fn dos_resize(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    // Resizing the memory of the current process, programs shrink
+    // themselves before EXEC to make room for the child
+    let psp = emu.get_data().current().psp;
+    let end = cpu.es + cpu.bx;
+    let result = if cpu.es != psp as u64 {
+        Err(DosError::InvalidFunction)
+    } else if end > MEMORY_END as u64 {
+        let available = MEMORY_END - psp;
+        emu.reg_write(RegisterX86::BX, available as u64)?;
+        Err(DosError::InsufficientMemory)
+    } else {
+        emu.get_data_mut().processes.last_mut().unwrap().end = end as u16;
+        Ok(())
+    };
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/4A01h and 4A02h HMA queries, no HMA is available
+fn dos_hma_query(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    emu.reg_write(RegisterX86::BX, 0)?;
+    emu.reg_write(RegisterX86::ES, 0xffff)?;
+    emu.reg_write(RegisterX86::DI, 0xffff)?;
+    Ok(())
+}
+
+/// INT 21h/4Bh load and execute program
+fn dos_exec(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let result = exec(emu, cpu)?;
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/4Ch terminate with return code
+fn dos_exit(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    terminate(emu, cpu.ax as u8, None)?;
+    Ok(())
+}
+
+/// INT 21h/4Dh get return code
+fn dos_return_code(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    // The code can only be read once
+    let code = std::mem::take(&mut emu.get_data_mut().return_code);
+    emu.reg_write(RegisterX86::AX, code as u64)?;
+    Ok(())
+}
+
+/// INT 21h/4Eh find first file
+fn dos_find_first(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let pattern = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let attributes = cpu.cx as u8;
+    let now = emu.get_data().clock.get().now();
+    let result = emu
+        .get_data_mut()
+        .drive
+        .find_first(&pattern, attributes, now);
+    if let Some((id, entry)) = dos_result(emu, result)? {
+        write_find_data(emu, &FindData::new(id, 1, attributes, &entry))?;
+    }
+    Ok(())
+}
+
+/// INT 21h/4Fh find next file
+fn dos_find_next(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let (segment, offset) = emu.get_data().dta;
+    let dta = read_bytes(
+        emu,
+        segment as u64 * 16 + offset as u64,
+        size_of::<FindData>(),
+    )?;
+    let (id, index) = FindData::search(&dta);
+    let attributes = dta[0x0c];
+    let result = emu.get_data_mut().drive.find_next(id, index);
+    if let Some(entry) = dos_result(emu, result)? {
+        let find_data = FindData::new(id, index + 1, attributes, &entry);
+        write_find_data(emu, &find_data)?;
+    }
+    Ok(())
+}
+
+/// INT 21h/56h rename file
+fn dos_rename(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let from = read_asciiz(emu, cpu.ds * 16 + cpu.dx)?;
+    let to = read_asciiz(emu, cpu.es * 16 + cpu.di)?;
+    let result = emu.get_data_mut().drive.rename(&from, &to);
+    dos_result(emu, result)?;
+    Ok(())
+}
+
+/// INT 21h/57h get/set file date and time
+fn dos_timestamp(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    let al = cpu.ax & 0xff;
+    let handle = cpu.bx as u16;
+    if al == 0 {
+        // The standard devices report the current time
+        let result = if handle < 5 {
+            Ok(dos_datetime(emu.get_data().clock.get().now()))
+        } else {
+            emu.get_data_mut().files.timestamp(handle)
+        };
+        if let Some((time, date)) = dos_result(emu, result)? {
+            emu.reg_write(RegisterX86::CX, time as u64)?;
+            emu.reg_write(RegisterX86::DX, date as u64)?;
+        }
+    } else {
+        let result = if handle < 5 {
+            Ok(())
+        } else {
+            emu.get_data_mut()
+                .files
+                .set_timestamp(handle, cpu.cx as u16, cpu.dx as u16)
+        };
+        dos_result(emu, result)?;
+    }
+    Ok(())
+}
+
+/// INT 27h, the old TSR call, DX is in bytes instead of paragraphs
+fn dos_keep_int27(emu: &mut Unicorn<EngineData>, cpu: &Cpu) -> Result<(), Error> {
+    terminate(emu, 0, Some(cpu.dx.div_ceil(16) as u16))
+}
+
+/// INT 11h equipment list, programs can patch the BDA so read the values back
+/// from memory like the BIOS does
+fn bios_equipment(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let addr = BDA_ADDR + offset_of!(BiosDataArea, equipment) as u64;
+    let equipment = read_u16(emu, addr)?;
+    emu.reg_write(RegisterX86::AX, equipment as u64)?;
+    Ok(())
+}
+
+/// INT 12h conventional memory size
+fn bios_memory_size(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    let addr = BDA_ADDR + offset_of!(BiosDataArea, memory_size) as u64;
+    let memory_size = read_u16(emu, addr)?;
+    emu.reg_write(RegisterX86::AX, memory_size as u64)?;
+    Ok(())
+}
+
+/// INT 00h, runtimes install a handler to report the error themselves. Without
+/// one DOS would kill the program
+fn divide_error(emu: &mut Unicorn<EngineData>, _cpu: &Cpu) -> Result<(), Error> {
+    if raise_exception(emu, 0x00)? {
+        return Ok(());
+    }
+    let at = FarPointer::read_engine(emu)?.to_string();
+    emu.get_data_mut()
+        .exit(ExitReason::CpuException { vector: 0, at });
+    emu.emu_stop()?;
+    Ok(())
+}
+
+/// Handler of a built in service, hosts can register closures too
+type ServiceFn = fn(&mut Unicorn<EngineData>, &Cpu) -> Result<(), Error>;
+
+/// The built in BIOS and DOS services
+fn standard_services() -> ServiceRegistry {
+    let mut services = ServiceRegistry::empty();
+    bios_services(&mut services);
+    dos_services(&mut services);
+    services
+}
+
+/// The DOS services, INT 21h and the old termination interrupts
+pub fn dos_services(services: &mut ServiceRegistry) {
+    use Support::*;
+    let int21: [(u8, &str, Support, ServiceFn); 34] = [
+        (0x00, "Terminate program", Implemented, dos_terminate),
+        (0x0e, "Select disk", Stubbed, dos_select_disk),
+        (0x19, "Get current drive", Implemented, dos_current_drive),
+        (0x1a, "Set disk transfer area", Implemented, dos_set_dta),
+        (0x25, "Set interrupt vector", Implemented, dos_set_vector),
+        (0x2a, "Get date", Implemented, dos_get_date),
+        (0x2b, "Set date", Implemented, dos_set_date),
+        (0x2c, "Get time", Implemented, dos_get_time),
+        (0x2d, "Set time", Implemented, dos_set_time),
+        (0x2f, "Get disk transfer area", Implemented, dos_get_dta),
+        (0x30, "Get DOS version", Stubbed, dos_version),
+        (0x31, "Terminate and stay resident", Implemented, dos_keep),
+        (0x35, "Get interrupt vector", Implemented, dos_get_vector),
+        (0x39, "Create directory", Implemented, dos_make_dir),
+        (0x3a, "Remove directory", Implemented, dos_remove_dir),
+        (0x3b, "Change directory", Implemented, dos_change_dir),
+        (0x3c, "Create file", Implemented, dos_create),
+        (0x3d, "Open file", Implemented, dos_open),
+        (0x3e, "Close file", Implemented, dos_close),
+        (0x3f, "Read file", Implemented, dos_read),
+        (0x40, "Write file", Implemented, dos_write),
+        (0x41, "Delete file", Implemented, dos_delete),
+        (0x42, "Move file pointer", Implemented, dos_seek),
+        (0x43, "Get/set file attributes", Implemented, dos_attributes),
+        (0x44, "IOCTL", Stubbed, dos_ioctl),
+        (0x47, "Get current directory", Implemented, dos_current_dir),
+        (0x4a, "Resize memory block", Implemented, dos_resize),
+        (0x4b, "Load and execute program", Implemented, dos_exec),
+        (0x4c, "Terminate with return code", Implemented, dos_exit),
+        (0x4d, "Get return code", Implemented, dos_return_code),
+        (0x4e, "Find first file", Implemented, dos_find_first),
+        (0x4f, "Find next file", Implemented, dos_find_next),
+        (0x56, "Rename file", Implemented, dos_rename),
+        (0x57, "File date and time", Implemented, dos_timestamp),
+    ];
+    for (ah, name, support, handler) in int21 {
+        services.register(ServiceKey::ah(0x21, ah), name, support, handler);
+    }
+    // DOSBox answers these HMA queries so we do too
+    for al in [0x01, 0x02] {
+        services.register(
+            ServiceKey::ax(0x21, 0x4a, al),
+            "HMA query",
+            Stubbed,
+            dos_hma_query,
+        );
+    }
+    services.register(
+        ServiceKey::int(0x20),
+        "Terminate program",
+        Implemented,
+        dos_terminate,
+    );
+    services.register(
+        ServiceKey::int(0x27),
+        "Terminate and stay resident",
+        Implemented,
+        dos_keep_int27,
+    );
+}
+
+/// The BIOS services and CPU exceptions
+pub fn bios_services(services: &mut ServiceRegistry) {
+    use Support::*;
+    services.register(
+        ServiceKey::int(0x00),
+        "Divide error",
+        Implemented,
+        divide_error,
+    );
+    services.register(
+        ServiceKey::int(0x11),
+        "Get equipment list",
+        Implemented,
+        bios_equipment,
+    );
+    services.register(
+        ServiceKey::int(0x12),
+        "Get memory size",
+        Implemented,
+        bios_memory_size,
+    );
+}
+
+fn port_in(emu: &mut Unicorn<EngineData>, port: u32, size: usize) -> Result<u32, Error> {
+    let fp = FarPointer::read_engine(emu)?;
+    let data = emu.get_data_mut();
+    let port = port as u16;
+    let value = match data.ports.read(port, size) {
+        Some(value) => {
+            if data.verbose {
+                let name = data.ports.device_name(port).unwrap_or_default();
+                data.report(format!(
+                    "port in [{fp}]: 0x{port:x} ({name}) -> 0x{value:x}"
+                ));
+            }
+            value
+        }
+        None => {
+            data.report(format!("Read from unknown port 0x{port:x} at [{fp}]"));
+            // Nothing is driving the bus so it floats high
+            (u64::MAX >> (64 - size * 8)) as u32
+        }
+    };
+    Ok(value)
+}
+
+fn port_out(
+    emu: &mut Unicorn<EngineData>,
+    port: u32,
+    size: usize,
+    value: u32,
+) -> Result<(), Error> {
+    let fp = FarPointer::read_engine(emu)?;
+    let data = emu.get_data_mut();
+    let port = port as u16;
+    if data.ports.write(port, size, value) {
+        if data.verbose {
+            let name = data.ports.device_name(port).unwrap_or_default();
+            data.report(format!(
+                "port out [{fp}]: 0x{port:x} ({name}) <- 0x{value:x}"
+            ));
+        }
+    } else {
+        data.report(format!(
+            "Write of 0x{value:x} to unknown port 0x{port:x} at [{fp}]"
+        ));
+    }
+    Ok(())
+}
+
 pub struct Engine<'a> {
     engine: Unicorn<'a, EngineData>,
 }
 
 impl<'a> Engine<'a> {
-    #[allow(dead_code)]
-    fn clear_cache(&mut self) {
+    fn clear_cache(&mut self) -> Result<(), Error> {
         // we need to invalidate the cache to make sure the code changes are applied
         // https://github.com/unicorn-engine/unicorn/wiki/FAQ#editing-an-instruction-doesnt-take-effecthooks-added-during-emulation-are-not-called
-        self.engine.ctl_remove_cache(0, 8 * 1024 * 1024).unwrap();
+        self.engine.ctl_remove_cache(0, MEMORY_SIZE)?;
+        Ok(())
     }
 
     pub fn engine(&self) -> &Unicorn<'a, EngineData> {
         &self.engine
     }
 
-    pub fn new(program: Program) -> Self {
+    pub fn new(program: Program) -> Result<Self, Error> {
         let data = EngineData::new(program);
-        let mut engine = Unicorn::new_with_data(Arch::X86, Mode::MODE_16, data).unwrap();
-        engine.mem_map(0, 8 * 1024 * 1024, Prot::ALL).unwrap();
-        let program = engine.get_data().program.clone();
-
-        // the start is a far pointer segment thingy so we need to multiply it with 16
-        let start_segment = program.start() * 16;
-        let psp_segment = start_segment - 256;
-        engine.mem_write(start_segment, program.data()).unwrap();
-
-        let psp = &PSP::new(0x0, 0x0);
-        let psp_data: &[u8] = psp.into();
-        engine.mem_write(psp_segment, psp_data).unwrap();
-
-        engine
-            .reg_write(RegisterX86::IP, program.header().initial_ip as u64)
-            .unwrap();
-        engine
-            .reg_write(RegisterX86::SP, program.header().initial_sp as u64)
-            .unwrap();
-        engine
-            .reg_write(
-                RegisterX86::CS,
-                program.header().initial_cs as u64 + program.start(),
-            )
-            .unwrap();
-        engine
-            .reg_write(
-                RegisterX86::SS,
-                program.header().initial_ss as u64 + program.start(),
-            )
-            .unwrap();
+        let mut engine = Unicorn::new_with_data(Arch::X86, Mode::MODE_16, data)?;
+        engine.mem_map(0, MEMORY_SIZE, Prot::ALL)?;
+        let program = engine.get_data().current().program.clone();
 
-        engine
-            .reg_write(RegisterX86::DS, psp_segment >> 4)
-            .unwrap();
-        engine
-            .reg_write(RegisterX86::ES, psp_segment >> 4)
-            .unwrap();
+        // The environment goes in the paragraphs right before the PSP
+        let env_segment = program.start() - 0x20;
+        engine.mem_write(env_segment * 16, &environment(&program))?;
+        let mut psp = PSP::new(MEMORY_END, 0x0);
+        psp.env_segment_addr = env_segment as u16;
+        load_program(&mut engine, &program, &psp)?;
 
-        engine
-            .add_code_hook(program.start(), 0, |emu, addr, len| {
-                let fp = FarPointer::read_engine(&emu);
-                if emu.get_data().verbose {
-                    let decoder = yaxpeax_x86::real_mode::InstDecoder::default();
-                    let inst = decoder
-                        .decode_slice(&emu.mem_read_as_vec(addr, len as usize).unwrap())
-                        .unwrap();
-                    println!("code exec: [{fp}]: {}", inst.to_string());
-                }
+        let now = engine.get_data().clock.get().now();
+        let bda = &BiosDataArea::new(now.time());
+        let bda_data: &[u8] = bda.into();
+        engine.mem_write(BDA_ADDR, bda_data)?;
 
-                let has_break = emu.get_data().get_break(addr).is_some();
-                if has_break {
-                    let is_intr = emu.get_data().get_break(addr).unwrap().intr;
-                    if !is_intr {
-                        println!("breaking at [{fp}]");
-                        emu.emu_stop().unwrap();
-                        if emu.get_data().while_break.is_some_and(|wb| wb.1 == addr) {
-                            emu.get_data_mut().while_break = Some((true, addr));
-                        }
-                    }
-                    let ebreak = emu.get_data_mut().get_break_mut(addr).unwrap();
-                    ebreak.intr = !ebreak.intr;
-                } else if emu.get_data().while_break.is_some_and(|wb| wb.0) {
-                    println!("stopping after while break at [{fp}]");
-                    emu.get_data_mut().while_break = None;
-                    emu.emu_stop().unwrap();
-                }
-            })
-            .unwrap();
+        enter_program(&mut engine, &program)?;
 
-        engine
-            .add_intr_hook(|emu, num| {
-                let cpu = Cpu::read_engine(&emu);
-                if num == 0x21 {
-                    let ah = cpu.ax >> 8;
-                    if ah == 0x25 {
-                        let al = cpu.ax & 0xff;
-                        let handler_ptr = (cpu.ds * 16 + cpu.dx) as u32;
-                        emu.mem_write(al * 4, &handler_ptr.to_le_bytes()).unwrap();
-                    } else if ah == 0x30 {
-                        // TXLIST.EXE is checking for DOS version 2 so lets set the dos version to that for now
-                        emu.reg_write(RegisterX86::AL, 2).unwrap();
-                    } else if ah == 0x35 {
-                        let al = cpu.ax & 0xff;
-                        emu.reg_write(RegisterX86::BX, al * 4).unwrap();
-                        emu.reg_write(RegisterX86::ES, al * 4 + 2).unwrap();
-                    } else if ah == 0x40 {
-                        let ds = cpu.ds;
-                        let dx = cpu.dx;
-                        let addr = ds * 16 + dx;
-                        let data = emu.mem_read_as_vec(addr, cpu.cx as usize).unwrap();
-                        println!(
-                            "Write to fd '{}', string: '{}'",
-                            cpu.bx,
-                            String::from_utf8_lossy(&data)
-                        );
-                    } else if ah == 0x44 {
-                        let al = cpu.ax & 0xff;
-                        if cpu.bx > 4 {
-                            println!("IOCTL functions are only implemented for default file handles. Exiting..");
-                            emu.get_data_mut().exited = true;
-                            emu.emu_stop().unwrap();
-                            return;
-                        }
-
-                        if al == 0 {
-                            // Mark device as character device
-                            emu.reg_write(RegisterX86::DX, 0x80).unwrap();
-                        } else {
-                            println!("Unimplemented IOCTL function {al:x}");
-                            emu.get_data_mut().exited = true;
-                            emu.emu_stop().unwrap();
-                        }
-                    } else if ah == 0x4a {
-                        // Dosbox is doing this so lets do it too for now?
-                        if cpu.ax == 0x4a01 || cpu.ax == 0x4a02 {
-                            emu.reg_write(RegisterX86::BX, 0).unwrap();
-                            emu.reg_write(RegisterX86::ES, 0xffff).unwrap();
-                            emu.reg_write(RegisterX86::DI, 0xffff).unwrap();
-                        } else {
-                            panic!("Only ax 0x4a01 and 0x4a02 are implemented for INT 21,4a");
-                        }
-                    } else if ah == 0x4c {
-                        let al = cpu.ax & 0xff;
-                        println!("Program terminating with code '0x{al:x}', exiting...");
-                        emu.get_data_mut().exited = true;
-                        emu.emu_stop().unwrap();
-                    } else {
-                        println!("Unimplemented ah for 0x21: 0x{ah:x}, exiting...");
-                        emu.get_data_mut().exited = true;
-                        emu.emu_stop().unwrap();
-                        return;
-                    }
-                } else {
-                    println!("Unimplemented interrupt 0x{num:x}, exiting...");
-                    emu.get_data_mut().exited = true;
-                    emu.emu_stop().unwrap();
+        engine.add_code_hook(program.start(), 0, |emu, addr, len| {
+            if let Err(err) = code_hook(emu, addr, len) {
+                fault(emu, err);
+            }
+        })?;
+        engine.add_intr_hook(|emu, num| {
+            if let Err(err) = intr_hook(emu, num) {
+                fault(emu, err);
+            }
+        })?;
+        // Programs wait for the tick count to change, nothing else advances it
+        let ticks_end = TICK_COUNT_ADDR + size_of::<u32>() as u64 - 1;
+        engine.add_mem_hook(
+            HookType::MEM_READ,
+            TICK_COUNT_ADDR,
+            ticks_end,
+            |emu, _, _, _, _| {
+                if let Err(err) = update_tick_count(emu) {
+                    fault(emu, err);
                 }
-            })
-            .unwrap();
+                true
+            },
+        )?;
+        engine.add_mem_hook(HookType::MEM_UNMAPPED, 1, 0, |emu, kind, addr, _, _| {
+            emu.get_data_mut().unmapped = Some((kind, addr));
+            false
+        })?;
+        engine.add_insn_in_hook(|emu, port, size| match port_in(emu, port, size) {
+            Ok(value) => value,
+            Err(err) => {
+                fault(emu, err);
+                0
+            }
+        })?;
+        engine.add_insn_out_hook(|emu, port, size, value| {
+            if let Err(err) = port_out(emu, port, size, value) {
+                fault(emu, err);
+            }
+        })?;
 
-        Self { engine }
+        Ok(Self { engine })
     }
 
     pub fn set_verbose(&mut self, verbose: bool) {
         self.engine.get_data_mut().verbose = verbose;
     }
 
+    /// Attach a device to the I/O port bus, it takes priority over the built in devices
+    pub fn register_port_device(&mut self, device: Box<dyn PortDevice>) {
+        self.engine.get_data_mut().ports.register(device);
+    }
+
+    /// Emulate an interrupt service with `handler`, it takes priority over the
+    /// built in service for the same key
+    pub fn register_service(
+        &mut self,
+        key: ServiceKey,
+        name: impl Into<String>,
+        handler: impl Fn(&mut Unicorn<EngineData>, &Cpu) -> Result<(), Error> + 'static,
+    ) {
+        let services = &mut self.engine.get_data_mut().services;
+        services.register(key, name, Support::Implemented, handler);
+    }
+
+    pub fn services(&self) -> &ServiceRegistry {
+        &self.engine.get_data().services
+    }
+
+    /// Mount a host directory as drive C:
+    pub fn set_drive_root(&mut self, root: impl Into<PathBuf>) {
+        let data = self.engine.get_data_mut();
+        data.drive = VirtualDrive::new(root);
+        alias_program(&mut data.drive, &data.processes[0].program);
+    }
+
+    /// Replace the guest clock, the BDA tick count is updated to match
+    pub fn set_clock(&mut self, clock: Clock) -> Result<(), Error> {
+        self.engine.get_data().clock.set(clock);
+        update_tick_count(&mut self.engine)
+    }
+
+    /// The program that's currently running, a child while one is running
+    pub fn program(&self) -> Rc<Program> {
+        self.engine.get_data().current().program.clone()
+    }
+
     pub fn exited(&self) -> bool {
-        self.engine.get_data().exited
+        self.engine.get_data().exit_reason.is_some()
+    }
+
+    /// Exit status for the host process, 0 while the program hasn't exited
+    pub fn exit_status(&self) -> i32 {
+        self.engine
+            .get_data()
+            .exit_reason
+            .as_ref()
+            .map_or(0, ExitReason::host_code)
+    }
+
+    /// Why the program stopped for good, None while it can still run
+    pub fn exit_reason(&self) -> Option<&ExitReason> {
+        self.engine.get_data().exit_reason.as_ref()
+    }
+
+    /// Keep messages like "breaking at" for `take_messages` instead of printing them
+    pub fn collect_messages(&mut self) {
+        self.engine
+            .get_data_mut()
+            .messages
+            .get_or_insert_with(Vec::new);
+    }
+
+    /// Messages collected since the last call
+    pub fn take_messages(&mut self) -> Vec<String> {
+        self.engine
+            .get_data_mut()
+            .messages
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Follow CALLs so `call_stack` can tell how the program got where it is
+    pub fn track_calls(&mut self) {
+        self.engine
+            .get_data_mut()
+            .calls
+            .get_or_insert_with(Vec::new);
+    }
+
+    /// Calls that haven't returned yet, innermost last, empty unless tracking calls
+    pub fn call_stack(&self) -> Vec<Frame> {
+        self.engine.get_data().calls.clone().unwrap_or_default()
+    }
+
+    /// Replace the command tail in the PSP of the program, it is only looked at
+    /// when the program starts
+    pub fn set_command_tail(&mut self, tail: &str) -> Result<(), Error> {
+        // A length byte and up to 126 characters ending in CR at offset 80h
+        let len = tail.len().min(126);
+        let mut data = vec![0u8; 128];
+        data[0] = len as u8;
+        data[1..=len].copy_from_slice(&tail.as_bytes()[..len]);
+        data[len + 1] = 0x0d;
+        let addr = (self.program().start() - 0x10) * 16 + 0x80;
+        self.engine.mem_write(addr, &data)?;
+        Ok(())
+    }
+
+    /// The FLAGS register
+    pub fn flags(&self) -> Result<u64, Error> {
+        Ok(self.engine.reg_read(RegisterX86::FLAGS)?)
+    }
+
+    /// Remember where the last `len` instructions ran, 0 turns tracing off
+    pub fn set_trace(&mut self, len: usize) {
+        let data = self.engine.get_data_mut();
+        data.trace_len = len;
+        data.trace.clear();
+    }
+
+    /// Instructions that ran last, oldest first
+    pub fn trace(&self) -> Vec<FarPointer> {
+        self.engine.get_data().trace.iter().copied().collect()
+    }
+
+    /// Decode the instruction at an address, its text and length
+    pub fn disassemble(&self, addr: u64) -> Result<(String, usize), Error> {
+        // The longest instruction is 15 bytes
+        Ok(disassemble(&read_bytes(&self.engine, addr, 15)?))
+    }
+
+    /// Step one instruction, CALLs and INTs run until they return
+    pub fn step_over(&mut self) -> Result<(), Error> {
+        let at = FarPointer::read_engine(&self.engine)?;
+        let decoder = yaxpeax_x86::real_mode::InstDecoder::default();
+        let code = read_bytes(&self.engine, at.address(), 15)?;
+        let next = match decoder.decode_slice(&code) {
+            Ok(inst)
+                if matches!(
+                    inst.opcode(),
+                    Opcode::CALL | Opcode::CALLF | Opcode::INT | Opcode::INTO
+                ) =>
+            {
+                at.address() + inst.len().to_const() as u64
+            }
+            _ => return self.step(),
+        };
+        // Recursive calls get to the same address with less on the same stack
+        let (ss, sp) = self.stack()?;
+        self.run_to_when(next, |engine| {
+            let (now_ss, now_sp) = engine.stack()?;
+            Ok(now_ss != ss || now_sp >= sp)
+        })
+    }
+
+    /// Run until the innermost call returns. Fails unless `track_calls` is on
+    /// and a call is in progress
+    pub fn finish(&mut self) -> Result<(), Error> {
+        let Some(calls) = self.engine.get_data().calls.clone() else {
+            return Err(Error::CallsNotTracked);
+        };
+        let Some(frame) = calls.last() else {
+            return Err(Error::NoCallToFinish);
+        };
+        self.run_to_when(frame.return_to.address(), |engine| {
+            Ok(engine.call_stack().len() < calls.len())
+        })
+    }
+
+    /// Run to `addr` through a temporary breakpoint
+    pub fn run_to(&mut self, addr: u64) -> Result<(), Error> {
+        self.run_to_when(addr, |_| Ok(true))
+    }
+
+    /// Continue with a temporary breakpoint at `addr`, a breakpoint already there
+    /// is left alone. Other stops end it early, getting to `addr` only counts once
+    /// `arrived` agrees
+    fn run_to_when(
+        &mut self,
+        addr: u64,
+        arrived: impl Fn(&Self) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let temporary = !self.has_break(addr);
+        self.add_break(addr);
+        let result = self.run_until(addr, arrived);
+        if temporary {
+            self.remove_break(addr);
+        }
+        result
+    }
+
+    fn run_until(
+        &mut self,
+        addr: u64,
+        arrived: impl Fn(&Self) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        loop {
+            self.cont()?;
+            let at = FarPointer::read_engine(&self.engine)?;
+            let caught = self.caught().is_some() || self.caught_fault().is_some();
+            if self.exited() || caught || at.address() != addr || arrived(self)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// SS and SP
+    fn stack(&self) -> Result<(u64, u64), Error> {
+        let ss = self.engine.reg_read(RegisterX86::SS)?;
+        let sp = self.engine.reg_read(RegisterX86::SP)?;
+        Ok((ss, sp))
     }
 
     pub fn add_break(&mut self, addr: u64) {
         self.engine.get_data_mut().add_break(EngineBreak::new(addr));
     }
 
+    pub fn has_break(&self, addr: u64) -> bool {
+        self.engine.get_data().get_break(addr).is_some()
+    }
+
+    /// Drop a breakpoint, false if there was none at the address
+    pub fn remove_break(&mut self, addr: u64) -> bool {
+        self.engine.get_data_mut().breaks.remove(&addr).is_some()
+    }
+
+    /// Break at an address relative to the load segment of a program started through EXEC
+    pub fn add_program_break(&mut self, name: &str, offset: u64) {
+        self.engine
+            .get_data_mut()
+            .program_breaks
+            .push(ProgramBreak {
+                name: name.to_uppercase(),
+                offset,
+            });
+    }
+
+    /// Break at an offset from the start of an overlay of the running program,
+    /// set now if that part is in memory and again whenever it is read in
+    pub fn add_overlay_break(&mut self, number: u16, offset: usize) {
+        let data = self.engine.get_data_mut();
+        let breaks: Vec<u64> = data
+            .current()
+            .resident
+            .iter()
+            .filter(|resident| resident.number == number)
+            .filter_map(|resident| resident.address_of(offset))
+            .collect();
+        for addr in breaks {
+            data.add_break(EngineBreak::new(addr));
+        }
+        data.overlay_breaks.push(OverlayBreak { number, offset });
+    }
+
+    /// Overlays of the running program that are in memory, oldest first
+    pub fn resident_overlays(&self) -> Vec<ResidentOverlay> {
+        self.engine.get_data().current().resident.clone()
+    }
+
+    /// Stop whenever the program calls this interrupt, `caught` tells which one
+    pub fn add_catchpoint(&mut self, catchpoint: Catchpoint) {
+        self.engine.get_data_mut().catchpoints.push(catchpoint);
+    }
+
+    /// Run the program's INT 06h handler on invalid opcodes like a 286 does,
+    /// programs without a handler still end or stop at the fault
+    pub fn set_deliver_int6(&mut self, deliver: bool) {
+        self.engine.get_data_mut().deliver_int6 = deliver;
+    }
+
+    /// Stop when the program faults, before the fault ends it
+    pub fn add_fault_catch(&mut self, fault: Fault) {
+        match fault.vector() {
+            // Unicorn hands these to the interrupt hook like an INT instruction
+            Some(num) if fault != Fault::InvalidOpcode => {
+                self.add_catchpoint(Catchpoint { num, ah: None })
+            }
+            _ => self.engine.get_data_mut().fault_catches.push(fault),
+        }
+    }
+
+    /// Fault the emulator stopped at because of a fault catch and what happened,
+    /// the program ends with it when it resumes
+    pub fn caught_fault(&self) -> Option<(Fault, &str)> {
+        self.engine
+            .get_data()
+            .faulted
+            .as_ref()
+            .map(|(fault, detail, _)| (*fault, detail.as_str()))
+    }
+
+    /// Log every interrupt call with its decoded arguments and result
+    pub fn set_log_syscalls(&mut self, log: bool) {
+        self.engine.get_data_mut().log_syscalls = log;
+    }
+
+    /// Log the calls of this interrupt, or of one function of it
+    pub fn log_calls(&mut self, calls: Catchpoint) {
+        self.engine.get_data_mut().logged.push(calls);
+    }
+
+    /// Interrupt the emulator stopped at because of a catchpoint, the INT has
+    /// executed but the service runs when the emulator resumes
+    pub fn caught(&self) -> Option<u8> {
+        self.engine.get_data().caught.map(|num| num as u8)
+    }
+
     pub fn add_while_break(&mut self, addr: u64) {
         self.engine.get_data_mut().add_break(EngineBreak::new(addr));
         self.engine.get_data_mut().while_break = Some((false, addr))
     }
 
-    pub fn start(&mut self) {
-        let ip = FarPointer::read_engine(&self.engine);
-        self.engine.emu_start(ip.address(), 8192, 0, 0).unwrap()
+    pub fn start(&mut self) -> Result<(), Error> {
+        self.emulate(0)
     }
 
-    pub fn read_cpu(&self) -> Cpu {
+    pub fn read_cpu(&self) -> Result<Cpu, Error> {
         Cpu::read_engine(&self.engine)
     }
 
+    /// Set a 16 bit register or one of the 8 bit halves, false for unknown names
+    pub fn write_register(&mut self, name: &str, value: u64) -> Result<bool, Error> {
+        let Some(register) = register_id(name) else {
+            return Ok(false);
+        };
+        self.engine.reg_write(register, value)?;
+        Ok(true)
+    }
+
     /// Read two bytes from memory
-    pub fn read_mem(&self, addr: u64) -> u16 {
-        let mut buf: [u8; 2] = [0; 2];
-        self.engine.mem_read(addr, &mut buf).unwrap();
-        u16::from_le_bytes(buf)
+    pub fn read_mem(&self, addr: u64) -> Result<u16, Error> {
+        read_u16(&self.engine, addr)
+    }
+
+    /// Read a zero terminated string, like the file names DOS calls get
+    pub fn read_asciiz(&self, addr: u64) -> Result<String, Error> {
+        read_asciiz(&self.engine, addr)
+    }
+
+    /// What the programs wrote to standard output and error so far
+    pub fn output(&self) -> &[u8] {
+        &self.engine.get_data().output
+    }
+
+    /// Read a block of memory
+    pub fn read_memory(&self, addr: u64, len: usize) -> Result<Vec<u8>, Error> {
+        read_bytes(&self.engine, addr, len)
+    }
+
+    /// Write a block of memory, cached translations of the code there are dropped
+    pub fn write_memory(&mut self, addr: u64, data: &[u8]) -> Result<(), Error> {
+        self.engine.mem_write(addr, data)?;
+        self.clear_cache()
+    }
+
+    /// Run the stub of a packed program until it jumps to the code it unpacked,
+    /// None if the program stopped before that
+    pub fn run_to_entry(&mut self) -> Result<Option<EntryPoint>, Error> {
+        let fp = FarPointer::read_engine(&self.engine)?;
+        let image = self.program().start() * 16;
+        self.engine.get_data_mut().unpack = Some(UnpackState {
+            image,
+            previous: (fp.cs, fp.address()),
+            entry: None,
+        });
+        self.emulate(0)?;
+        let state = self.engine.get_data_mut().unpack.take();
+        Ok(state.and_then(|state| state.entry))
     }
 
     /// Continue run where enigne was stopped
-    pub fn cont(&mut self) {
-        self.start();
+    pub fn cont(&mut self) -> Result<(), Error> {
+        self.start()
+    }
+
+    pub fn step(&mut self) -> Result<(), Error> {
+        self.emulate(1)
+    }
+
+    /// Run from the current CS:IP, count 0 runs until something stops the emulator.
+    /// Faults of the program end up in the exit reason, errors are for the emulator itself
+    fn emulate(&mut self, count: usize) -> Result<(), Error> {
+        // The INT of a catchpoint already ran, servicing it finishes that instruction
+        if let Some(num) = self.engine.get_data_mut().caught.take() {
+            if let Err(err) = interrupt(&mut self.engine, num) {
+                fault(&mut self.engine, err);
+            }
+            if count == 1 || self.exited() {
+                return Ok(());
+            }
+        }
+        // A caught fault only held the program back, nothing can run past it
+        if let Some((_, _, reason)) = self.engine.get_data_mut().faulted.take() {
+            self.engine.get_data_mut().exit(reason);
+            return Ok(());
+        }
+        loop {
+            let ip = FarPointer::read_engine(&self.engine)?;
+            let Err(err) = self.engine.emu_start(ip.address(), 8192, 0, count) else {
+                return Ok(());
+            };
+            let invalid = err == uc_error::INSN_INVALID;
+            if invalid
+                && self.engine.get_data().deliver_int6
+                && raise_exception(&mut self.engine, 0x06)?
+            {
+                // Delivering the exception was the step
+                if count == 1 {
+                    return Ok(());
+                }
+                continue;
+            }
+
+            let at = FarPointer::read_engine(&self.engine)?;
+            let (fault, detail, reason) = if invalid {
+                let code = read_bytes(&self.engine, at.address(), 2).unwrap_or_default();
+                let code: Vec<String> = code.iter().map(|byte| format!("{byte:02x}")).collect();
+                let detail = format!("invalid opcode {}", code.join(" "));
+                let reason = ExitReason::CpuException {
+                    vector: 0x06,
+                    at: at.to_string(),
+                };
+                (Some(Fault::InvalidOpcode), detail, reason)
+            } else {
+                let unmapped = self.engine.get_data_mut().unmapped.take();
+                let detail = match unmapped {
+                    Some((kind, addr)) => {
+                        let access = match kind {
+                            MemType::WRITE_UNMAPPED => "write",
+                            MemType::FETCH_UNMAPPED => "fetch",
+                            _ => "read",
+                        };
+                        format!("unmapped {access} at linear address 0x{addr:x}")
+                    }
+                    None => err.to_string(),
+                };
+                let reason = ExitReason::EmulatorFault {
+                    error: detail.clone(),
+                    at: at.to_string(),
+                };
+                (unmapped.map(|_| Fault::Unmapped), detail, reason)
+            };
+            let data = self.engine.get_data_mut();
+            match fault {
+                Some(fault) if data.fault_catches.contains(&fault) => {
+                    data.faulted = Some((fault, detail, reason))
+                }
+                _ => data.exit(reason),
+            }
+            return Ok(());
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use unicorn_engine::RegisterX86;
+
+    use chrono::NaiveDate;
+
+    use crate::{
+        bios::ticks_since_midnight,
+        clock::Clock,
+        engine::{Engine, ExitReason, Fault},
+        error::Error,
+        program::Program,
+        services::{ServiceKey, Support},
+    };
+
+    /// Load one of the DOS test programs in asm/dos at segment 1000h
+    fn load(name: &str) -> Engine<'static> {
+        let path = format!("{}/asm/dos/{name}", env!("CARGO_MANIFEST_DIR"));
+        let mut engine = Engine::new(Program::new(&path, 0x1000).unwrap()).unwrap();
+        engine.collect_messages();
+        engine
+    }
+
+    #[test]
+    fn exec_returns_to_parent() {
+        let mut engine = load("EXEC.EXE");
+        // The instruction after the INT 21h/4Bh
+        engine.run_to(0x10024).unwrap();
+        assert!(!engine.exited());
+        assert_eq!(engine.read_cpu().unwrap().cs, 0x1000);
+        assert_eq!(engine.flags().unwrap() & 1, 0, "the child's carry leaked");
+
+        engine.cont().unwrap();
+        assert!(matches!(engine.exit_reason(), Some(ExitReason::Exited(7))));
+        let messages = engine.take_messages().join("\n");
+        assert!(messages.contains("EXEC 'CHILD.EXE' loaded"), "{messages}");
+    }
+
+    #[test]
+    fn host_code_keeps_emulator_statuses_apart() {
+        assert_eq!(ExitReason::Exited(0).host_code(), 0);
+        assert_eq!(ExitReason::Exited(251).host_code(), 251);
+        assert_eq!(ExitReason::Exited(252).host_code(), 251);
+        assert_eq!(ExitReason::Exited(255).host_code(), 251);
+
+        let at = "1000:0000".to_string();
+        let statuses = [
+            ExitReason::Unimplemented("INT 0x99".to_string()).host_code(),
+            ExitReason::CpuException {
+                vector: 0,
+                at: at.clone(),
+            }
+            .host_code(),
+            ExitReason::EmulatorFault {
+                error: "fault".to_string(),
+                at,
+            }
+            .host_code(),
+        ];
+        assert_eq!(statuses, [252, 253, 254]);
+    }
+
+    #[test]
+    fn host_overrides_a_service() {
+        let mut engine = load("HELLO.EXE");
+        engine.register_service(ServiceKey::ah(0x21, 0x30), "DOS 5", |emu, _cpu| {
+            emu.reg_write(RegisterX86::AX, 0x0005)?;
+            Ok(())
+        });
+        let service = engine.services().find(0x21, 0x30, 0).unwrap();
+        assert_eq!(
+            (service.name.as_str(), service.support),
+            ("DOS 5", Support::Overridden)
+        );
+
+        engine.cont().unwrap();
+        // HELLO.EXE exits with the major version
+        assert!(matches!(engine.exit_reason(), Some(ExitReason::Exited(5))));
+        assert_eq!(engine.output(), b"Hello from DOS\r\n");
+    }
+
+    #[test]
+    fn syscall_log() {
+        let mut engine = load("HELLO.EXE");
+        engine.set_log_syscalls(true);
+        engine.cont().unwrap();
+        let messages = engine.take_messages();
+        let calls: Vec<&str> = messages
+            .iter()
+            .map(String::as_str)
+            .filter(|message| message.starts_with("INT21/"))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "INT21/40 write handle=1 count=16 -> 16 bytes",
+                "INT21/30 ax=3010 -> version 2.0",
+                "INT21/4C exit code=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn stop_at_invalid_opcode() {
+        let mut engine = load("FAULT.EXE");
+        engine.add_fault_catch(Fault::InvalidOpcode);
+        engine.cont().unwrap();
+        assert_eq!(
+            engine.caught_fault(),
+            Some((Fault::InvalidOpcode, "invalid opcode 0f 0b"))
+        );
+        assert!(!engine.exited());
+        assert_eq!(engine.read_cpu().unwrap().ip, 0x0e);
+
+        // Nothing can run past it
+        engine.cont().unwrap();
+        assert!(matches!(
+            engine.exit_reason(),
+            Some(ExitReason::CpuException { vector: 0x06, at }) if at == "1000:000e"
+        ));
+    }
+
+    #[test]
+    fn deliver_int6_then_stop_at_unmapped_read() {
+        let mut engine = load("FAULT.EXE");
+        engine.set_deliver_int6(true);
+        engine.add_fault_catch(Fault::Unmapped);
+        engine.cont().unwrap();
+        // The handler ran and skipped the invalid opcode
+        let cpu = engine.read_cpu().unwrap();
+        assert_eq!((cpu.bx, cpu.ip), (1, 0x16));
+        assert_eq!(
+            engine.caught_fault(),
+            Some((Fault::Unmapped, "unmapped read at linear address 0x210000"))
+        );
+
+        engine.cont().unwrap();
+        assert!(matches!(
+            engine.exit_reason(),
+            Some(ExitReason::EmulatorFault { at, .. }) if at == "1000:0016"
+        ));
+        assert_eq!(engine.exit_status(), 254);
+    }
+
+    #[test]
+    fn step_over_a_call() {
+        let mut engine = load("HELLO.EXE");
+        // CALL print
+        engine.run_to(0x10004).unwrap();
+        assert_eq!(engine.read_cpu().unwrap().ip, 0x04);
+        assert!(!engine.has_break(0x10004));
+
+        engine.step_over().unwrap();
+        assert_eq!(engine.read_cpu().unwrap().ip, 0x07);
+        assert_eq!(engine.output(), b"Hello from DOS\r\n");
+        assert!(!engine.has_break(0x10007));
+
+        // A breakpoint of the user at the return address stays
+        let mut engine = load("HELLO.EXE");
+        engine.add_break(0x10007);
+        engine.run_to(0x10004).unwrap();
+        engine.step_over().unwrap();
+        assert_eq!(engine.read_cpu().unwrap().ip, 0x07);
+        assert!(engine.has_break(0x10007));
+    }
+
+    #[test]
+    fn finish_a_call() {
+        let mut engine = load("HELLO.EXE");
+        engine.run_to(0x1000f).unwrap();
+        assert!(matches!(engine.finish(), Err(Error::CallsNotTracked)));
+
+        let mut engine = load("HELLO.EXE");
+        engine.track_calls();
+        assert!(matches!(engine.finish(), Err(Error::NoCallToFinish)));
+        assert_eq!(engine.read_cpu().unwrap().ip, 0);
+        engine.add_break(0x10007);
+        engine.run_to(0x1000f).unwrap();
+        assert_eq!(engine.call_stack().len(), 1);
+        engine.finish().unwrap();
+        assert_eq!(engine.read_cpu().unwrap().ip, 0x07);
+        assert!(engine.call_stack().is_empty());
+        assert!(engine.has_break(0x10007));
+        assert!(!engine.has_break(0x1000f));
+    }
+
+    #[test]
+    fn tick_count_follows_the_clock() {
+        let mut engine = load("HELLO.EXE");
+        let now = NaiveDate::from_ymd_opt(2024, 3, 1)
+            .unwrap()
+            .and_hms_opt(13, 30, 0)
+            .unwrap();
+        engine.set_clock(Clock::fixed(now)).unwrap();
+        engine.write_memory(0x46c, &[0xff; 4]).unwrap();
+
+        // mov ax, 40h; mov es, ax; mov ax, [es:6ch]; mov dx, [es:6eh]
+        let code = [
+            0xb8, 0x40, 0x00, 0x8e, 0xc0, 0x26, 0xa1, 0x6c, 0x00, 0x26, 0x8b, 0x16, 0x6e, 0x00,
+        ];
+        engine.write_memory(0x10000, &code).unwrap();
+        engine.run_to(0x10000 + code.len() as u64).unwrap();
+        let cpu = engine.read_cpu().unwrap();
+        let ticks = ticks_since_midnight(now.time()) as u64;
+        assert_eq!((cpu.ax, cpu.dx), (ticks & 0xffff, ticks >> 16));
+    }
+
+    #[test]
+    fn divide_error_handler() {
+        // xor bx, bx; div bx, the handler at 0020h exits with 2Ah
+        let divide = [0x31, 0xdb, 0xf7, 0xf3];
+        let handler = [0xb8, 0x2a, 0x4c, 0xcd, 0x21];
+
+        let mut engine = load("HELLO.EXE");
+        engine.write_memory(0x10000, &divide).unwrap();
+        engine.cont().unwrap();
+        assert!(matches!(
+            engine.exit_reason(),
+            Some(ExitReason::CpuException { vector: 0, at }) if at == "1000:0002"
+        ));
 
-    pub fn step(&mut self) {
-        let ip = FarPointer::read_engine(&self.engine);
-        self.engine.emu_start(ip.address(), 8192, 0, 1).unwrap()
+        let mut engine = load("HELLO.EXE");
+        engine.write_memory(0x10000, &divide).unwrap();
+        engine.write_memory(0x10020, &handler).unwrap();
+        engine.write_memory(0, &[0x20, 0x00, 0x00, 0x10]).unwrap();
+        engine.cont().unwrap();
+        assert!(matches!(
+            engine.exit_reason(),
+            Some(ExitReason::Exited(0x2a))
+        ));
     }
 }
@@ -0,0 +1,69 @@
+use std::{fmt::Display, io};
+
+use unicorn_engine::uc_error;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The program isn't an MZ executable we can load
+    MalformedHeader(String),
+    /// The header lists more relocations than the file holds
+    TruncatedRelocations {
+        expected: u16,
+        found: usize,
+    },
+    /// Debugger script error, line and column start at 1
+    Parse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    UnmappedMemory(u64),
+    /// Debugger script failed while running, like an undefined variable
+    Script(String),
+    /// The program isn't packed in a way we know or the stub didn't finish
+    Unpack(String),
+    /// Finishing a call needs the calls, see `Engine::track_calls`
+    CallsNotTracked,
+    /// Finishing outside of any call
+    NoCallToFinish,
+    Unicorn(uc_error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {err}"),
+            Error::MalformedHeader(reason) => write!(f, "malformed MZ header: {reason}"),
+            Error::TruncatedRelocations { expected, found } => write!(
+                f,
+                "truncated relocation table: header lists {expected} entries but the file only has {found}"
+            ),
+            Error::Parse {
+                line,
+                column,
+                message,
+            } => write!(f, "line {line}, column {column}: {message}"),
+            Error::UnmappedMemory(addr) => write!(f, "address 0x{addr:x} is not mapped"),
+            Error::Script(message) => write!(f, "{message}"),
+            Error::Unpack(reason) => write!(f, "cannot unpack: {reason}"),
+            Error::CallsNotTracked => write!(f, "calls aren't tracked, can't tell where to finish"),
+            Error::NoCallToFinish => write!(f, "no call to finish"),
+            Error::Unicorn(err) => write!(f, "unicorn error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<uc_error> for Error {
+    fn from(err: uc_error) -> Self {
+        Error::Unicorn(err)
+    }
+}
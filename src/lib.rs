@@ -0,0 +1,22 @@
+//! MS-DOS MZ executables on unicorn: the loader, the machine with the DOS and
+//! BIOS services it emulates, and the debugger on top of it. The
+//! `unicorn_debugger` binary is a command line front-end for these.
+
+pub mod bios;
+pub mod clock;
+pub mod dap;
+pub mod debugger;
+pub mod drive;
+pub mod engine;
+pub mod error;
+pub mod overlay;
+pub mod pattern;
+pub mod ports;
+pub mod program;
+pub mod rhai_script;
+pub mod rpc;
+pub mod runner;
+pub mod script;
+pub mod services;
+pub mod syscalls;
+pub mod unpack;
@@ -1,26 +1,120 @@
+use std::{io, path::Path, process::exit};
+
 use clap::Parser;
 
-use crate::{debugger::Debugger, engine::Engine, program::Program};
+use unicorn_debugger::{
+    clock::Clock, dap, debugger::Debugger, engine::Engine, error::Error, program::Program,
+    rhai_script, rpc, runner, unpack,
+};
 
 mod cli;
-mod debugger;
-mod engine;
-mod program;
+
+/// Exit status when the program can't be loaded or the debug script is broken
+const ERROR_STATUS: i32 = 255;
 
 fn main() {
     let args = cli::CliArgs::parse();
-    let program = Program::new(&args.program_path, 0x1000);
-    let mut engine = Engine::new(program);
+    match run(&args) {
+        Ok(status) => exit(status),
+        Err(err) => {
+            eprintln!("error: {err}");
+            exit(ERROR_STATUS);
+        }
+    }
+}
+
+/// Load a program at `start` set up the way the arguments ask for
+fn load(args: &cli::CliArgs, path: &str, start: u64) -> Result<Engine<'static>, Error> {
+    let program = Program::new(path, start)?;
+    let mut engine = Engine::new(program)?;
     engine.set_verbose(args.verbose);
+    engine.set_log_syscalls(args.log_syscalls);
+    engine.set_deliver_int6(args.deliver_int6);
+    if let Some(drive) = &args.drive {
+        engine.set_drive_root(drive);
+    }
+    if let Some(time) = args.fixed_time {
+        engine.set_clock(Clock::fixed(time))?;
+    }
+    Ok(engine)
+}
+
+#[cfg(unix)]
+fn serve_socket<'a>(
+    server: &mut rpc::Server<'a, impl FnMut(&str) -> Result<Engine<'a>, Error>>,
+    path: &Path,
+) -> Result<(), Error> {
+    server.serve_socket(path)
+}
+
+#[cfg(not(unix))]
+fn serve_socket<'a>(
+    _server: &mut rpc::Server<'a, impl FnMut(&str) -> Result<Engine<'a>, Error>>,
+    _path: &Path,
+) -> Result<(), Error> {
+    Err(Error::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported on this platform",
+    )))
+}
+
+/// Run the program and return the exit status for the host
+fn run(args: &cli::CliArgs) -> Result<i32, Error> {
+    if !args.test.is_empty() {
+        return Ok(runner::run_tests(&args.test, |path| {
+            load(args, path, 0x1000)
+        }));
+    }
+
+    if args.dap {
+        let mut server = dap::Server::new(|path: &str| load(args, path, 0x1000), io::stdout());
+        server.serve(io::stdin().lock())?;
+        return Ok(0);
+    }
+
+    if args.rpc_mode() {
+        let engine = args
+            .program_path
+            .as_deref()
+            .map(|path| load(args, path, 0x1000))
+            .transpose()?;
+        let mut server = rpc::Server::new(|path: &str| load(args, path, 0x1000), engine);
+        match &args.rpc_socket {
+            Some(socket) => serve_socket(&mut server, Path::new(socket))?,
+            None => server.serve(io::stdin().lock(), io::stdout())?,
+        }
+        return Ok(0);
+    }
+
+    // Clap makes sure there is a program unless we run tests or serve a client
+    let path = args.program_path.as_deref().unwrap_or_default();
+    if let Some(output) = &args.unpack {
+        let unpacked = unpack::unpack(|start| load(args, path, start), Path::new(output))?;
+        println!("Found {} stub in '{path}'", unpacked.packer);
+        let (cs, ip) = unpacked.entry;
+        println!(
+            "Wrote '{output}', {} bytes of code with {} relocations, entry point {cs:04x}:{ip:04x}",
+            unpacked.image_len, unpacked.relocations
+        );
+        return Ok(0);
+    }
+
+    let mut engine = load(args, path, 0x1000)?;
 
     if args.debug_mode() {
         let mut debug = Debugger::new(engine);
         if let Some(file) = &args.debug_file {
-            debug.run_file(file);
+            if rhai_script::is_rhai(file) {
+                debug = rhai_script::run_file(debug, file)?;
+            } else {
+                debug.run_file(file)?;
+            }
         } else {
-            debug.repl();
+            debug.repl(io::stdin().lock());
         }
+        Ok(debug.engine.exit_status())
     } else {
-        engine.start();
+        engine.start()?;
+        Ok(engine.exit_status())
     }
 }
@@ -0,0 +1,162 @@
+use std::fmt::Display;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::program::Header;
+
+/// Signature of the header Borland linkers put in front of the overlay data
+const FBOV_SIGNATURE: &[u8] = b"FBOV";
+const FBOV_HEADER_LEN: usize = 16;
+
+/// How the data appended after the load image is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayFormat {
+    /// Nothing after the load image
+    None,
+    /// Borland VROOMM overlays, a single FBOV block the overlay manager reads units from
+    Borland,
+    /// Microsoft LINK overlays, a chain of MZ images numbered in their headers
+    Microsoft,
+    /// Something we don't know, debug info or a self extracting archive
+    Unknown,
+}
+
+impl Display for OverlayFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlayFormat::None => write!(f, "no appended data"),
+            OverlayFormat::Borland => write!(f, "Borland FBOV overlays"),
+            OverlayFormat::Microsoft => write!(f, "Microsoft overlays"),
+            OverlayFormat::Unknown => write!(f, "unrecognised appended data"),
+        }
+    }
+}
+
+/// Code the overlay manager reads from the executable at run time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Overlay {
+    /// Overlay number from its header, Borland overlays are all number 0
+    pub number: u16,
+    /// Offset of the first byte of code in the executable file, headers excluded
+    pub offset: usize,
+    pub size: usize,
+}
+
+impl Overlay {
+    /// Whether the file range starting at `pos` covers any of the overlay
+    pub fn intersects(&self, pos: usize, len: usize) -> bool {
+        pos < self.offset + self.size && self.offset < pos + len
+    }
+}
+
+/// Recognise the overlays in `appended`, the data that starts at file offset `base`
+pub fn detect(appended: &[u8], base: usize) -> (OverlayFormat, Vec<Overlay>) {
+    if appended.is_empty() {
+        return (OverlayFormat::None, Vec::new());
+    }
+
+    if appended.starts_with(FBOV_SIGNATURE) && appended.len() >= FBOV_HEADER_LEN {
+        // The size doesn't include the 16 byte header, trust the file if they disagree
+        let size = LittleEndian::read_u32(&appended[4..8]) as usize;
+        let size = size.min(appended.len() - FBOV_HEADER_LEN);
+        let overlay = Overlay {
+            number: 0,
+            offset: base + FBOV_HEADER_LEN,
+            size,
+        };
+        return (OverlayFormat::Borland, vec![overlay]);
+    }
+
+    // Each overlay is an executable of its own, the next one follows where its header says it ends
+    let mut overlays = Vec::new();
+    let mut pos = 0;
+    while let Ok(header) = Header::new(&appended[pos..]) {
+        let header_len = header.header_len();
+        let file_size = header.file_size();
+        if header.overlay_number() == 0
+            || header_len > file_size
+            || pos + file_size > appended.len()
+        {
+            break;
+        }
+        overlays.push(Overlay {
+            number: header.overlay_number(),
+            offset: base + pos + header_len,
+            size: file_size - header_len,
+        });
+        pos += file_size;
+    }
+
+    if overlays.is_empty() {
+        (OverlayFormat::Unknown, overlays)
+    } else {
+        (OverlayFormat::Microsoft, overlays)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::overlay::{Overlay, OverlayFormat, detect};
+
+    /// Overlay with a two paragraph header, the way LINK writes them
+    fn microsoft_overlay(number: u16, code: &[u8]) -> Vec<u8> {
+        let mut overlay = vec![0u8; 32];
+        let file_size = overlay.len() + code.len();
+        overlay[0..2].copy_from_slice(b"MZ");
+        LittleEndian::write_u16(&mut overlay[2..4], (file_size % 512) as u16);
+        LittleEndian::write_u16(&mut overlay[4..6], file_size.div_ceil(512) as u16);
+        LittleEndian::write_u16(&mut overlay[8..10], 2);
+        LittleEndian::write_u16(&mut overlay[26..28], number);
+        overlay.extend_from_slice(code);
+        overlay
+    }
+
+    #[test]
+    fn detect_formats() {
+        assert_eq!(detect(&[], 0x200), (OverlayFormat::None, vec![]));
+        assert_eq!(
+            detect(b"NB09 codeview", 0x200),
+            (OverlayFormat::Unknown, vec![])
+        );
+
+        let mut fbov = b"FBOV".to_vec();
+        fbov.extend_from_slice(&100u32.to_le_bytes());
+        fbov.extend_from_slice(&[0; 8]);
+        fbov.extend_from_slice(&[0x90; 100]);
+        assert_eq!(
+            detect(&fbov, 0x200),
+            (
+                OverlayFormat::Borland,
+                vec![Overlay {
+                    number: 0,
+                    offset: 0x210,
+                    size: 100
+                }]
+            )
+        );
+
+        let mut chain = microsoft_overlay(1, &[0x90; 40]);
+        chain.extend(microsoft_overlay(2, &[0xc3; 600]));
+        let (format, overlays) = detect(&chain, 0x200);
+        assert_eq!(format, OverlayFormat::Microsoft);
+        assert_eq!(
+            overlays,
+            vec![
+                Overlay {
+                    number: 1,
+                    offset: 0x220,
+                    size: 40
+                },
+                Overlay {
+                    number: 2,
+                    offset: 0x200 + 72 + 32,
+                    size: 600
+                }
+            ]
+        );
+        assert!(overlays[0].intersects(0x200, 0x21));
+        assert!(!overlays[0].intersects(0x200, 0x20));
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::{HashMap, HashSet};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::error::Error;
+
+/// Structures the debugger knows without loading a pattern file
+const BUILTIN: &str = r#"
+// Memory control block, DOS puts one in the paragraph before every allocation
+struct MCB {
+    char type;
+    u16 owner;
+    u16 size;
+    u8 reserved[3];
+    char name[8];
+};
+
+// Disk transfer area as FindFirst/FindNext fill it
+struct DTA {
+    u8 drive;
+    char template[11];
+    u8 search_attributes;
+    u16 entry;
+    u16 cluster;
+    u8 reserved[4];
+    u8 attributes;
+    u16 time;
+    u16 date;
+    u32 size;
+    char name[13];
+};
+
+// Opened file control block
+struct FCB {
+    u8 drive;
+    char name[8];
+    char extension[3];
+    u16 block;
+    u16 record_size;
+    u32 file_size;
+    u16 date;
+    u16 time;
+    u8 reserved[8];
+    u8 record;
+    u32 random_record;
+};
+
+struct MZ {
+    char signature[2];
+    u16 last_page_bytes;
+    u16 pages_in_file;
+    u16 relocations;
+    u16 header_paragraphs;
+    u16 min_allocation;
+    u16 max_allocation;
+    u16 ss;
+    u16 sp;
+    u16 checksum;
+    u16 ip;
+    u16 cs;
+    u16 relocation_table;
+    u16 overlay;
+};
+"#;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    U8,
+    U16,
+    U32,
+    S8,
+    S16,
+    S32,
+    Char,
+    Struct(String),
+}
+
+impl Type {
+    fn parse(name: &str) -> Self {
+        match name {
+            "u8" => Type::U8,
+            "u16" => Type::U16,
+            "u32" => Type::U32,
+            "s8" => Type::S8,
+            "s16" => Type::S16,
+            "s32" => Type::S32,
+            "char" => Type::Char,
+            _ => Type::Struct(name.into()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Type::U8 => "u8",
+            Type::U16 => "u16",
+            Type::U32 => "u32",
+            Type::S8 => "s8",
+            Type::S16 => "s16",
+            Type::S32 => "s32",
+            Type::Char => "char",
+            Type::Struct(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub ty: Type,
+    pub name: String,
+    /// Number of elements for arrays
+    pub count: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Struct {
+    pub name: String,
+    pub fields: Vec<Field>,
+}
+
+/// A variable placed at a fixed address, `PSP psp @ 0x00;`
+#[derive(Debug, Clone)]
+pub struct Placement {
+    pub field: Field,
+    pub addr: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u64),
+    Symbol(char),
+}
+
+/// Splits the source into tokens with their 1 based line and column
+fn tokenize(source: &str) -> Result<Vec<(Token, usize, usize)>, Error> {
+    let mut tokens = Vec::new();
+    let mut in_comment = false;
+    for (idx, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut col = 0;
+        while col < chars.len() {
+            let c = chars[col];
+            let rest: String = chars[col..].iter().collect();
+            if in_comment {
+                if rest.starts_with("*/") {
+                    in_comment = false;
+                    col += 2;
+                } else {
+                    col += 1;
+                }
+            } else if rest.starts_with("//") || (c == '#' && line.trim_start().starts_with('#')) {
+                // Comments and preprocessor lines like #pragma
+                break;
+            } else if rest.starts_with("/*") {
+                in_comment = true;
+                col += 2;
+            } else if c.is_whitespace() {
+                col += 1;
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let len = chars[col..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+                    .count();
+                tokens.push((Token::Ident(rest[..len].into()), idx + 1, col + 1));
+                col += len;
+            } else if c.is_ascii_digit() {
+                let len = chars[col..]
+                    .iter()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .count();
+                let text = &rest[..len];
+                let value = match text.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => text.parse(),
+                };
+                let value = value.map_err(|_| Error::Parse {
+                    line: idx + 1,
+                    column: col + 1,
+                    message: format!("cannot parse number '{text}'"),
+                })?;
+                tokens.push((Token::Number(value), idx + 1, col + 1));
+                col += len;
+            } else if "{}[];@".contains(c) {
+                tokens.push((Token::Symbol(c), idx + 1, col + 1));
+                col += 1;
+            } else {
+                return Err(Error::Parse {
+                    line: idx + 1,
+                    column: col + 1,
+                    message: format!("unexpected '{c}'"),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize, usize)>,
+    pos: usize,
+}
+
+/// Line and column of a token, 1 based
+type Position = (usize, usize);
+
+fn error_at((line, column): Position, message: String) -> Error {
+    Error::Parse {
+        line,
+        column,
+        message,
+    }
+}
+
+impl Parser {
+    /// Where the next token is, past the end the last token
+    fn position(&self) -> Position {
+        self.tokens
+            .get(self.pos)
+            .or(self.tokens.last())
+            .map_or((1, 1), |(_, line, column)| (*line, *column))
+    }
+
+    fn error(&self, message: String) -> Error {
+        error_at(self.position(), message)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _, _)| token)
+    }
+
+    fn ident(&mut self, what: &str) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.clone();
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => Err(self.error(format!("expected {what}"))),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, Error> {
+        match self.peek() {
+            Some(Token::Number(number)) => {
+                let number = *number;
+                self.pos += 1;
+                Ok(number)
+            }
+            _ => Err(self.error("expected a number".into())),
+        }
+    }
+
+    fn symbol(&mut self, symbol: char) -> Result<(), Error> {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(format!("expected '{symbol}'")))
+        }
+    }
+
+    /// `type name[count]` and where its type is, the terminator is left to the caller
+    fn field(&mut self) -> Result<(Field, Position), Error> {
+        let position = self.position();
+        let ty = Type::parse(&self.ident("a type")?);
+        let name = self.ident("a name")?;
+        let count = if self.peek() == Some(&Token::Symbol('[')) {
+            self.pos += 1;
+            let count = self.number()? as usize;
+            self.symbol(']')?;
+            Some(count)
+        } else {
+            None
+        };
+        Ok((Field { ty, name, count }, position))
+    }
+
+    /// The struct and where the types of its fields are
+    fn structure(&mut self) -> Result<(Struct, Vec<Position>), Error> {
+        let name = self.ident("a struct name")?;
+        self.symbol('{')?;
+        let mut fields = Vec::new();
+        let mut positions = Vec::new();
+        while self.peek() != Some(&Token::Symbol('}')) {
+            if self.peek().is_none() {
+                return Err(self.error(format!("expected '}}' to close struct {name}")));
+            }
+            let (field, position) = self.field()?;
+            fields.push(field);
+            positions.push(position);
+            self.symbol(';')?;
+        }
+        self.next();
+        self.symbol(';')?;
+        Ok((Struct { name, fields }, positions))
+    }
+}
+
+/// Structures from the built in definitions and loaded pattern files
+pub struct Patterns {
+    structs: HashMap<String, Struct>,
+}
+
+impl Patterns {
+    /// PSP from the pattern file next to the sources and the other DOS structures
+    pub fn builtin() -> Self {
+        let mut patterns = Self {
+            structs: HashMap::new(),
+        };
+        for source in [include_str!("../psp.hexpat"), BUILTIN] {
+            // They are part of the program, a mistake in them is a bug
+            patterns
+                .load(source)
+                .expect("built in patterns should parse");
+        }
+        patterns
+    }
+
+    /// Add the structs of an ImHex pattern, returns the placements in it.
+    /// Only structs of u8-u32, s8-s32, char, nested structs and arrays of them are supported
+    pub fn load(&mut self, source: &str) -> Result<Vec<Placement>, Error> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let mut structs = Vec::new();
+        let mut placements = Vec::new();
+        while let Some(token) = parser.peek() {
+            if *token == Token::Ident("struct".into()) {
+                parser.next();
+                structs.push(parser.structure()?);
+            } else {
+                let (field, position) = parser.field()?;
+                parser.symbol('@')?;
+                let addr = parser.number()?;
+                parser.symbol(';')?;
+                placements.push((Placement { field, addr }, position));
+            }
+        }
+
+        // Only add them once they all check out, a broken file changes nothing
+        let mut known = self.structs.clone();
+        let mut fields = Vec::new();
+        for (structure, positions) in structs {
+            // A later definition in the same file replaces the earlier one
+            fields.retain(|(outer, _, _)| *outer != structure.name);
+            for (field, position) in structure.fields.iter().zip(positions) {
+                if let Type::Struct(name) = &field.ty {
+                    if !known.contains_key(name) {
+                        let message = format!("unknown type '{name}' in struct {}", structure.name);
+                        return Err(error_at(position, message));
+                    }
+                    fields.push((structure.name.clone(), name.clone(), position));
+                }
+            }
+            known.insert(structure.name.clone(), structure);
+        }
+        // Redefining a struct can make one that was fine contain itself, every
+        // cycle goes through a struct of this file
+        for (outer, inner, position) in fields {
+            if contains(&known, &inner, &outer, &mut HashSet::new()) {
+                let message = format!("struct {outer} contains itself through {inner}");
+                return Err(error_at(position, message));
+            }
+        }
+        for (placement, position) in &placements {
+            if let Type::Struct(name) = &placement.field.ty
+                && !known.contains_key(name)
+            {
+                let message = format!("unknown type '{name}' in placement");
+                return Err(error_at(*position, message));
+            }
+        }
+        self.structs = known;
+        Ok(placements
+            .into_iter()
+            .map(|(placement, _)| placement)
+            .collect())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Struct> {
+        self.structs.get(name)
+    }
+
+    /// Names of the known structs, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.structs.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// Size in bytes, structs are packed like in ImHex
+    pub fn size_of(&self, ty: &Type) -> usize {
+        match ty {
+            Type::U8 | Type::S8 | Type::Char => 1,
+            Type::U16 | Type::S16 => 2,
+            Type::U32 | Type::S32 => 4,
+            Type::Struct(name) => self.structs[name]
+                .fields
+                .iter()
+                .map(|field| self.field_size(field))
+                .sum(),
+        }
+    }
+
+    pub fn field_size(&self, field: &Field) -> usize {
+        self.size_of(&field.ty) * field.count.unwrap_or(1)
+    }
+
+    /// Layout of a struct with the offset and size of every field
+    pub fn describe(&self, name: &str) -> Option<Vec<String>> {
+        let structure = self.get(name)?;
+        let mut lines = vec![format!(
+            "struct {name} {{ // {} bytes",
+            self.size_of(&Type::Struct(name.into()))
+        )];
+        let mut offset = 0;
+        for field in &structure.fields {
+            let count = field.count.map(|count| format!("[{count}]"));
+            lines.push(format!(
+                "    /* {offset:04x} */ {} {}{};",
+                field.ty.name(),
+                field.name,
+                count.unwrap_or_default()
+            ));
+            offset += self.field_size(field);
+        }
+        lines.push("};".into());
+        Some(lines)
+    }
+
+    /// Pretty print `data` as a value of `field`, `data` has to be at least its size
+    pub fn format(&self, field: &Field, data: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.format_field(field, data, 0, &mut lines);
+        lines
+    }
+
+    fn format_field(&self, field: &Field, data: &[u8], indent: usize, lines: &mut Vec<String>) {
+        let pad = "  ".repeat(indent);
+        let size = self.size_of(&field.ty);
+        match (&field.ty, field.count) {
+            (Type::Struct(name), None) => {
+                lines.push(format!("{pad}{}: {name}", field.name));
+                self.format_struct(name, data, indent + 1, lines);
+            }
+            (Type::Struct(name), Some(count)) => {
+                for idx in 0..count {
+                    lines.push(format!("{pad}{}[{idx}]: {name}", field.name));
+                    self.format_struct(name, &data[idx * size..], indent + 1, lines);
+                }
+            }
+            (Type::Char, Some(count)) => {
+                let text: String = data[..count]
+                    .iter()
+                    .map(|c| match c {
+                        0x20..=0x7e => *c as char,
+                        _ => '.',
+                    })
+                    .collect();
+                lines.push(format!("{pad}{}: \"{text}\"", field.name));
+            }
+            (ty, None) => lines.push(format!("{pad}{}: {}", field.name, scalar(ty, data))),
+            (ty, Some(count)) => {
+                let values: Vec<String> = (0..count)
+                    .map(|idx| scalar(ty, &data[idx * size..]))
+                    .collect();
+                lines.push(format!("{pad}{}: [{}]", field.name, values.join(" ")));
+            }
+        }
+    }
+
+    fn format_struct(&self, name: &str, data: &[u8], indent: usize, lines: &mut Vec<String>) {
+        let mut offset = 0;
+        for field in &self.structs[name].fields {
+            self.format_field(field, &data[offset..], indent, lines);
+            offset += self.field_size(field);
+        }
+    }
+}
+
+/// Whether struct `name` is `target` or has it somewhere in its fields
+fn contains(
+    structs: &HashMap<String, Struct>,
+    name: &str,
+    target: &str,
+    seen: &mut HashSet<String>,
+) -> bool {
+    if name == target {
+        return true;
+    }
+    if !seen.insert(name.to_string()) {
+        return false;
+    }
+    structs[name].fields.iter().any(|field| match &field.ty {
+        Type::Struct(inner) => contains(structs, inner, target, seen),
+        _ => false,
+    })
+}
+
+/// Hex for unsigned values, decimal for signed ones
+fn scalar(ty: &Type, data: &[u8]) -> String {
+    match ty {
+        Type::U8 => format!("{:02x}", data[0]),
+        Type::U16 => format!("{:04x}", LittleEndian::read_u16(data)),
+        Type::U32 => format!("{:08x}", LittleEndian::read_u32(data)),
+        Type::S8 => format!("{}", data[0] as i8),
+        Type::S16 => format!("{}", LittleEndian::read_i16(data)),
+        Type::S32 => format!("{}", LittleEndian::read_i32(data)),
+        Type::Char => match data[0] {
+            c @ 0x20..=0x7e => format!("'{}'", c as char),
+            c => format!("{c:02x}"),
+        },
+        Type::Struct(_) => unreachable!("structs are formatted field by field"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::Error,
+        pattern::{Field, Patterns, Type},
+    };
+
+    #[test]
+    fn builtin_sizes() {
+        let patterns = Patterns::builtin();
+        let size = |name: &str| patterns.size_of(&Type::Struct(name.into()));
+        assert_eq!(size("PSP"), 256);
+        assert_eq!(size("MCB"), 16);
+        assert_eq!(size("DTA"), 43);
+        assert_eq!(size("FCB"), 37);
+        assert_eq!(size("MZ"), 28);
+    }
+
+    #[test]
+    fn nested_structs() {
+        let mut patterns = Patterns::builtin();
+        let placements = patterns
+            .load(
+                "/* two points */\nstruct Point { s16 x; s16 y; };\nstruct Line {\n  Point ends[2];\n  char tag[3];\n  u8 flags;\n};\nLine line @ 0x100;\n",
+            )
+            .unwrap();
+        assert_eq!(placements.len(), 1);
+        assert_eq!(placements[0].addr, 0x100);
+
+        let data = [0xff, 0xff, 2, 0, 3, 0, 4, 0, b'a', b'b', 0, 0x80];
+        let field = Field {
+            ty: Type::Struct("Line".into()),
+            name: "line".into(),
+            count: None,
+        };
+        assert_eq!(
+            patterns.format(&field, &data),
+            vec![
+                "line: Line",
+                "  ends[0]: Point",
+                "    x: -1",
+                "    y: 2",
+                "  ends[1]: Point",
+                "    x: 3",
+                "    y: 4",
+                "  tag: \"ab.\"",
+                "  flags: 80",
+            ]
+        );
+
+        let err = patterns.load("struct A {\n  u16 x\n};").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 3,
+                column: 1,
+                ..
+            }
+        ));
+        let err = patterns
+            .load("struct B {\n  u8 x;\n  Unknown u;\n};")
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 3,
+                column: 3,
+                ..
+            }
+        ));
+        assert!(patterns.get("B").is_none());
+        let err = patterns.load("Missing m @ 0x10;").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 1,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn structs_containing_themselves() {
+        let mut patterns = Patterns::builtin();
+        let err = patterns.load("struct MCB { MCB m; };").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Parse {
+                line: 1,
+                column: 14,
+                ..
+            }
+        ));
+
+        // Through another struct that already uses it
+        patterns
+            .load("struct A { u8 x; };\nstruct B { A a; };\n")
+            .unwrap();
+        let err = patterns.load("struct A {\n  B b;\n};").unwrap_err();
+        assert!(
+            matches!(err, Error::Parse { line: 2, column: 3, ref message } if message == "struct A contains itself through B"),
+            "{err:?}"
+        );
+
+        // Nothing changed, sizes still work
+        assert_eq!(patterns.size_of(&Type::Struct("MCB".into())), 16);
+        assert_eq!(patterns.size_of(&Type::Struct("B".into())), 1);
+    }
+}
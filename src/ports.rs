@@ -0,0 +1,640 @@
+use std::{cell::Cell, collections::VecDeque, ops::RangeInclusive, rc::Rc, time::Instant};
+
+use chrono::{Datelike, Timelike};
+
+use crate::{bios::CONVENTIONAL_MEMORY_KB, clock::Clock};
+
+/// A device living on the I/O port bus.
+/// Devices work on single bytes, wider IN/OUT accesses are split up by the [`PortRegistry`]
+pub trait PortDevice {
+    /// Name used when logging port accesses
+    fn name(&self) -> &'static str;
+
+    /// Port ranges this device answers to
+    fn ports(&self) -> &[RangeInclusive<u16>];
+
+    fn read(&mut self, port: u16) -> u8;
+
+    fn write(&mut self, port: u16, value: u8);
+}
+
+/// Dispatches IN/OUT instructions to the device registered for the port
+pub struct PortRegistry {
+    devices: Vec<Box<dyn PortDevice>>,
+}
+
+impl PortRegistry {
+    /// Registry without any devices, every port access is unknown
+    pub fn empty() -> Self {
+        Self {
+            devices: Vec::new(),
+        }
+    }
+
+    /// Register a device, devices registered later take priority so stubs can be overridden
+    pub fn register(&mut self, device: Box<dyn PortDevice>) {
+        self.devices.push(device);
+    }
+
+    fn device_mut(&mut self, port: u16) -> Option<&mut Box<dyn PortDevice>> {
+        self.devices
+            .iter_mut()
+            .rev()
+            .find(|device| device.ports().iter().any(|range| range.contains(&port)))
+    }
+
+    /// Name of the device handling `port`, if any
+    pub fn device_name(&mut self, port: u16) -> Option<&'static str> {
+        self.device_mut(port).map(|device| device.name())
+    }
+
+    /// Read `size` bytes starting at `port`, returns None when no device handles the port
+    pub fn read(&mut self, port: u16, size: usize) -> Option<u32> {
+        self.device_mut(port)?;
+
+        let mut value = 0;
+        for n in 0..size {
+            let port = port.wrapping_add(n as u16);
+            let byte = self
+                .device_mut(port)
+                .map_or(0xff, |device| device.read(port));
+            value |= (byte as u32) << (n * 8);
+        }
+        Some(value)
+    }
+
+    /// Write `size` bytes starting at `port`, returns false when no device handles the port
+    pub fn write(&mut self, port: u16, size: usize, value: u32) -> bool {
+        if self.device_mut(port).is_none() {
+            return false;
+        }
+
+        for n in 0..size {
+            let port = port.wrapping_add(n as u16);
+            if let Some(device) = self.device_mut(port) {
+                device.write(port, (value >> (n * 8)) as u8);
+            }
+        }
+        true
+    }
+
+    /// Registry with the standard PC devices, the RTC follows the guest clock
+    pub fn standard(clock: Rc<Cell<Clock>>) -> Self {
+        let mut registry = Self::empty();
+        registry.register(Box::new(Pic::new(0x20)));
+        registry.register(Box::new(Pic::new(0xa0)));
+        registry.register(Box::new(Pit::new()));
+        registry.register(Box::new(KeyboardController::new()));
+        registry.register(Box::new(Cmos::new(clock)));
+        registry.register(Box::new(Vga::new()));
+        registry
+    }
+}
+
+/// 8259 programmable interrupt controller, we never raise hardware interrupts
+/// so this only keeps the registers around for programs that set them up
+pub struct Pic {
+    ports: [RangeInclusive<u16>; 1],
+    imr: u8,
+    /// Which ICW we are expecting next, 0 when not initialising
+    init_step: u8,
+    /// ICW4 is only sent when ICW1 asks for it
+    needs_icw4: bool,
+}
+
+impl Pic {
+    pub fn new(base: u16) -> Self {
+        Self {
+            ports: [base..=base + 1],
+            imr: 0,
+            init_step: 0,
+            needs_icw4: false,
+        }
+    }
+
+    fn base(&self) -> u16 {
+        *self.ports[0].start()
+    }
+}
+
+impl PortDevice for Pic {
+    fn name(&self) -> &'static str {
+        if self.base() == 0x20 { "pic1" } else { "pic2" }
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &self.ports
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        if port == self.base() {
+            // Nothing is ever pending or in service, IRR and ISR are both empty
+            0
+        } else {
+            self.imr
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        if port == self.base() {
+            if value & 0x10 != 0 {
+                // ICW1, start of the initialisation sequence
+                self.init_step = 2;
+                self.needs_icw4 = value & 0x01 != 0;
+                self.imr = 0;
+            }
+            // OCW2 (EOI) and OCW3 need no handling as nothing is ever pending or in service
+            return;
+        }
+
+        match self.init_step {
+            // ICW2 sets the vector base, we never deliver IRQs so it can be dropped
+            2 => self.init_step = 3,
+            3 => self.init_step = if self.needs_icw4 { 4 } else { 0 },
+            4 => self.init_step = 0,
+            _ => self.imr = value,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PitChannel {
+    reload: u16,
+    /// 1 = low byte, 2 = high byte, 3 = low then high byte
+    access: u8,
+    /// Next lo/hi access is the high byte
+    write_hi: bool,
+    read_hi: bool,
+    latch: Option<u16>,
+    start: Instant,
+}
+
+impl PitChannel {
+    fn new() -> Self {
+        Self {
+            reload: 0,
+            access: 3,
+            write_hi: false,
+            read_hi: false,
+            latch: None,
+            start: Instant::now(),
+        }
+    }
+
+    /// Current counter value, counts down at 1.193182 MHz using the host clock
+    fn count(&self) -> u16 {
+        let reload = if self.reload == 0 {
+            0x10000
+        } else {
+            self.reload as u128
+        };
+        let ticks = self.start.elapsed().as_nanos() * 1_193_182 / 1_000_000_000;
+        (reload - ticks % reload) as u16
+    }
+}
+
+/// 8253/8254 programmable interval timer
+pub struct Pit {
+    channels: [PitChannel; 3],
+}
+
+impl Pit {
+    const PORTS: [RangeInclusive<u16>; 1] = [0x40..=0x43];
+
+    pub fn new() -> Self {
+        Self {
+            channels: [PitChannel::new(); 3],
+        }
+    }
+}
+
+impl Default for Pit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortDevice for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &Self::PORTS
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        if port == 0x43 {
+            return 0xff;
+        }
+
+        let channel = &mut self.channels[(port - 0x40) as usize];
+        let value = channel.latch.unwrap_or_else(|| channel.count());
+        let [lo, hi] = value.to_le_bytes();
+        match channel.access {
+            1 => {
+                channel.latch = None;
+                lo
+            }
+            2 => {
+                channel.latch = None;
+                hi
+            }
+            _ => {
+                channel.read_hi = !channel.read_hi;
+                if channel.read_hi {
+                    lo
+                } else {
+                    channel.latch = None;
+                    hi
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        if port == 0x43 {
+            let index = (value >> 6) as usize;
+            if index == 3 {
+                // Read-back command, only on 8254 and nothing uses it
+                return;
+            }
+
+            let channel = &mut self.channels[index];
+            let access = (value >> 4) & 0x3;
+            if access == 0 {
+                channel.latch = Some(channel.count());
+            } else {
+                // The counting mode is ignored, every channel behaves like a rate generator
+                channel.access = access;
+                channel.write_hi = false;
+                channel.read_hi = false;
+                channel.latch = None;
+            }
+            return;
+        }
+
+        let channel = &mut self.channels[(port - 0x40) as usize];
+        let [lo, hi] = channel.reload.to_le_bytes();
+        channel.reload = match channel.access {
+            1 => u16::from_le_bytes([value, hi]),
+            2 => u16::from_le_bytes([lo, value]),
+            _ => {
+                channel.write_hi = !channel.write_hi;
+                if channel.write_hi {
+                    u16::from_le_bytes([value, hi])
+                } else {
+                    u16::from_le_bytes([lo, value])
+                }
+            }
+        };
+        channel.start = Instant::now();
+    }
+}
+
+/// 8042 keyboard controller and system control port B (61h)
+pub struct KeyboardController {
+    output: VecDeque<u8>,
+    last_output: u8,
+    /// Controller command waiting for its data byte on port 60h
+    pending: Option<u8>,
+    command_byte: u8,
+    /// Output port, bit 1 is the A20 gate
+    output_port: u8,
+    port_b: u8,
+}
+
+impl KeyboardController {
+    const PORTS: [RangeInclusive<u16>; 2] = [0x60..=0x61, 0x64..=0x64];
+
+    pub fn new() -> Self {
+        Self {
+            output: VecDeque::new(),
+            last_output: 0,
+            pending: None,
+            command_byte: 0x45,
+            output_port: 0x03,
+            port_b: 0,
+        }
+    }
+}
+
+impl Default for KeyboardController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortDevice for KeyboardController {
+    fn name(&self) -> &'static str {
+        "keyboard"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &Self::PORTS
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        match port {
+            0x60 => {
+                if let Some(value) = self.output.pop_front() {
+                    self.last_output = value;
+                }
+                self.last_output
+            }
+            0x61 => {
+                // Bit 4 is the DRAM refresh toggle, some delay loops wait for it to flip
+                self.port_b ^= 0x10;
+                self.port_b
+            }
+            _ => {
+                // Keyboard unlocked, system flag set and the output buffer state
+                0x14 | !self.output.is_empty() as u8
+            }
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        match port {
+            0x60 => match self.pending.take() {
+                Some(0x60) => self.command_byte = value,
+                Some(0xd1) => self.output_port = value,
+                _ => {
+                    // Commands for the keyboard itself, acknowledge everything
+                    self.output.push_back(0xfa);
+                    if value == 0xff {
+                        // Reset, self test passed
+                        self.output.push_back(0xaa);
+                    }
+                }
+            },
+            0x61 => self.port_b = value,
+            _ => match value {
+                0x20 => self.output.push_back(self.command_byte),
+                0x60 | 0xd1 => self.pending = Some(value),
+                0xaa => self.output.push_back(0x55),
+                0xab => self.output.push_back(0x00),
+                0xd0 => self.output.push_back(self.output_port),
+                _ => {}
+            },
+        }
+    }
+}
+
+/// MC146818 CMOS RTC and its battery backed RAM
+pub struct Cmos {
+    clock: Rc<Cell<Clock>>,
+    index: u8,
+    ram: [u8; 128],
+}
+
+impl Cmos {
+    const PORTS: [RangeInclusive<u16>; 1] = [0x70..=0x71];
+
+    pub fn new(clock: Rc<Cell<Clock>>) -> Self {
+        let mut ram = [0; 128];
+        // Status register A: 32.768 kHz time base, 1024 Hz periodic rate
+        ram[0x0a] = 0x26;
+        // Status register B: 24 hour mode, BCD values
+        ram[0x0b] = 0x02;
+        // Status register D: battery good
+        ram[0x0d] = 0x80;
+        // One 1.44M floppy as drive A
+        ram[0x10] = 0x40;
+        // Equipment byte: floppy installed, EGA/VGA display
+        ram[0x14] = 0x01;
+        [ram[0x15], ram[0x16]] = CONVENTIONAL_MEMORY_KB.to_le_bytes();
+        Self {
+            clock,
+            index: 0,
+            ram,
+        }
+    }
+}
+
+fn to_bcd(value: u32) -> u8 {
+    (((value / 10 % 10) << 4) | (value % 10)) as u8
+}
+
+impl PortDevice for Cmos {
+    fn name(&self) -> &'static str {
+        "cmos"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &Self::PORTS
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        if port == 0x70 {
+            return 0xff;
+        }
+
+        let now = self.clock.get().now();
+        match self.index {
+            0x00 => to_bcd(now.second()),
+            0x02 => to_bcd(now.minute()),
+            0x04 => to_bcd(now.hour()),
+            0x06 => to_bcd(now.weekday().number_from_sunday()),
+            0x07 => to_bcd(now.day()),
+            0x08 => to_bcd(now.month()),
+            0x09 => to_bcd(now.year() as u32 % 100),
+            0x32 => to_bcd(now.year() as u32 / 100),
+            index => self.ram[index as usize],
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        if port == 0x70 {
+            // Bit 7 is the NMI disable flag
+            self.index = value & 0x7f;
+        } else {
+            self.ram[self.index as usize] = value;
+        }
+    }
+}
+
+/// VGA register file, registers are stored but nothing is drawn
+pub struct Vga {
+    misc: u8,
+    seq_index: u8,
+    seq: [u8; 256],
+    crtc_index: u8,
+    crtc: [u8; 256],
+    gc_index: u8,
+    gc: [u8; 256],
+    attr_index: u8,
+    attr: [u8; 32],
+    /// The attribute controller alternates between index and data writes
+    attr_data: bool,
+    dac_read: u8,
+    dac_write: u8,
+    dac_component: usize,
+    dac: [[u8; 3]; 256],
+    /// Toggled on every status read so vertical retrace waits finish
+    retrace: bool,
+}
+
+impl Vga {
+    const PORTS: [RangeInclusive<u16>; 1] = [0x3b0..=0x3df];
+
+    pub fn new() -> Self {
+        Self {
+            misc: 0x67,
+            seq_index: 0,
+            seq: [0; 256],
+            crtc_index: 0,
+            crtc: [0; 256],
+            gc_index: 0,
+            gc: [0; 256],
+            attr_index: 0,
+            attr: [0; 32],
+            attr_data: false,
+            dac_read: 0,
+            dac_write: 0,
+            dac_component: 0,
+            dac: [[0; 3]; 256],
+            retrace: false,
+        }
+    }
+}
+
+impl Default for Vga {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PortDevice for Vga {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn ports(&self) -> &[RangeInclusive<u16>] {
+        &Self::PORTS
+    }
+
+    fn read(&mut self, port: u16) -> u8 {
+        match port {
+            0x3c0 => self.attr_index,
+            0x3c1 => self.attr[(self.attr_index & 0x1f) as usize],
+            0x3c4 => self.seq_index,
+            0x3c5 => self.seq[self.seq_index as usize],
+            0x3c7 => 0x03,
+            0x3c8 => self.dac_write,
+            0x3c9 => {
+                let value = self.dac[self.dac_read as usize][self.dac_component];
+                self.dac_component += 1;
+                if self.dac_component == 3 {
+                    self.dac_component = 0;
+                    self.dac_read = self.dac_read.wrapping_add(1);
+                }
+                value
+            }
+            0x3cc => self.misc,
+            0x3ce => self.gc_index,
+            0x3cf => self.gc[self.gc_index as usize],
+            0x3b4 | 0x3d4 => self.crtc_index,
+            0x3b5 | 0x3d5 => self.crtc[self.crtc_index as usize],
+            0x3ba | 0x3da => {
+                self.attr_data = false;
+                self.retrace = !self.retrace;
+                // Display enable and vertical retrace flip together
+                if self.retrace { 0x09 } else { 0x00 }
+            }
+            _ => 0xff,
+        }
+    }
+
+    fn write(&mut self, port: u16, value: u8) {
+        match port {
+            0x3c0 => {
+                if self.attr_data {
+                    self.attr[(self.attr_index & 0x1f) as usize] = value;
+                } else {
+                    self.attr_index = value;
+                }
+                self.attr_data = !self.attr_data;
+            }
+            0x3c2 => self.misc = value,
+            0x3c4 => self.seq_index = value,
+            0x3c5 => self.seq[self.seq_index as usize] = value,
+            0x3c7 => {
+                self.dac_read = value;
+                self.dac_component = 0;
+            }
+            0x3c8 => {
+                self.dac_write = value;
+                self.dac_component = 0;
+            }
+            0x3c9 => {
+                self.dac[self.dac_write as usize][self.dac_component] = value & 0x3f;
+                self.dac_component += 1;
+                if self.dac_component == 3 {
+                    self.dac_component = 0;
+                    self.dac_write = self.dac_write.wrapping_add(1);
+                }
+            }
+            0x3ce => self.gc_index = value,
+            0x3cf => self.gc[self.gc_index as usize] = value,
+            0x3b4 | 0x3d4 => self.crtc_index = value,
+            0x3b5 | 0x3d5 => self.crtc[self.crtc_index as usize] = value,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_port() {
+        let mut ports = PortRegistry::standard(Rc::default());
+        assert_eq!(ports.read(0x1234, 1), None);
+        assert!(!ports.write(0x1234, 1, 0));
+    }
+
+    #[test]
+    fn word_access_is_split_into_bytes() {
+        let mut ports = PortRegistry::standard(Rc::default());
+        // Select sequencer register 2 and write 0x0f to it with a single OUT DX, AX
+        assert!(ports.write(0x3c4, 2, 0x0f02));
+        assert_eq!(ports.read(0x3c4, 2), Some(0x0f02));
+    }
+
+    #[test]
+    fn keyboard_self_test() {
+        let mut ports = PortRegistry::standard(Rc::default());
+        ports.write(0x64, 1, 0xaa);
+        assert_eq!(ports.read(0x64, 1), Some(0x15));
+        assert_eq!(ports.read(0x60, 1), Some(0x55));
+        assert_eq!(ports.read(0x64, 1), Some(0x14));
+    }
+
+    #[test]
+    fn later_devices_override() {
+        struct Dummy;
+        impl Dummy {
+            const PORTS: [RangeInclusive<u16>; 1] = [0x60..=0x60];
+        }
+        impl PortDevice for Dummy {
+            fn name(&self) -> &'static str {
+                "dummy"
+            }
+            fn ports(&self) -> &[RangeInclusive<u16>] {
+                &Self::PORTS
+            }
+            fn read(&mut self, _port: u16) -> u8 {
+                0x42
+            }
+            fn write(&mut self, _port: u16, _value: u8) {}
+        }
+
+        let mut ports = PortRegistry::standard(Rc::default());
+        ports.register(Box::new(Dummy));
+        assert_eq!(ports.device_name(0x60), Some("dummy"));
+        assert_eq!(ports.read(0x60, 1), Some(0x42));
+        assert_eq!(ports.device_name(0x64), Some("keyboard"));
+    }
+}
@@ -1,33 +1,95 @@
 use byteorder::{ByteOrder, LittleEndian};
-use std::fs::read;
+use std::{
+    fs::read,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::Error,
+    overlay::{self, Overlay, OverlayFormat},
+};
+
+/// Size of the fixed part of the MZ header, the relocation table follows it
+const HEADER_LEN: usize = 28;
 
 pub struct Program {
+    path: PathBuf,
     // TODO: mapp the section header data directly here so it maps 1-1 with the program memory addresses
     data: Vec<u8>,
+    /// Bytes after the load image, overlays and debug info live here. DOS never loads them
+    appended: Vec<u8>,
+    overlay_format: OverlayFormat,
+    overlays: Vec<Overlay>,
+    /// Words changed by applying the relocation table, in table order
+    relocations: Vec<Patch>,
     /// Where does execution start
     start: u64,
     header: Header,
 }
 
 impl Program {
-    pub fn new(path: &str, start: u64) -> Self {
-        let mut data = read(path).unwrap();
-        let header = Header::new(&data);
-        data.drain(0..(header.header_size as usize * 16));
+    pub fn new(path: &str, start: u64) -> Result<Self, Error> {
+        let bytes = read(path)?;
+        Self::from_bytes(path, &bytes, start)
+    }
+
+    /// Load an executable that's already in memory, `path` is only for reference
+    pub fn from_bytes(path: &str, bytes: &[u8], start: u64) -> Result<Self, Error> {
+        let header = Header::new(bytes)?;
+        let header_len = header.header_len();
+        let image_end = header.file_size();
+        if image_end > bytes.len() {
+            return Err(Error::MalformedHeader(format!(
+                "header says the file is {image_end} bytes but it is only {}",
+                bytes.len()
+            )));
+        }
+        if header_len > image_end {
+            return Err(Error::MalformedHeader(format!(
+                "header size of {header_len} bytes is larger than the {image_end} byte file"
+            )));
+        }
+
+        let mut data = bytes[header_len..image_end].to_vec();
+        let mut relocations = Vec::with_capacity(header.relocation_table.len());
         for reloc in &header.relocation_table {
-            let segment = reloc.segment as u64;
-            let offset = reloc.offset as u64;
-            let addr = (segment * 16 + offset) as usize;
-            let bytes = (start as u16).to_le_bytes();
-            data[addr] += bytes[0];
-            data[addr + 1] += bytes[1];
+            let segment = reloc.segment as usize;
+            let offset = reloc.offset as usize;
+            let addr = segment * 16 + offset;
+            if addr + 1 >= data.len() {
+                return Err(Error::MalformedHeader(format!(
+                    "relocation at {segment:04x}:{offset:04x} is outside the load image"
+                )));
+            }
+            // Segments are 16 bit so the add wraps like it does on DOS
+            let original = LittleEndian::read_u16(&data[addr..]);
+            let relocated = original.wrapping_add(start as u16);
+            LittleEndian::write_u16(&mut data[addr..], relocated);
+            relocations.push(Patch {
+                relocation: *reloc,
+                original,
+                relocated,
+            });
         }
 
-        Self {
+        let appended = bytes[image_end..].to_vec();
+        let (overlay_format, overlays) = overlay::detect(&appended, image_end);
+
+        Ok(Self {
+            path: PathBuf::from(path),
             data,
+            relocations,
+            appended,
+            overlay_format,
+            overlays,
             start,
             header,
-        }
+        })
+    }
+
+    /// Path of the executable on the host
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn start(&self) -> u64 {
@@ -38,21 +100,73 @@ impl Program {
         &self.data
     }
 
+    /// Data appended after the load image
+    pub fn appended(&self) -> &[u8] {
+        &self.appended
+    }
+
+    pub fn overlay_format(&self) -> OverlayFormat {
+        self.overlay_format
+    }
+
+    /// Overlays recognised in the appended data, in file order
+    pub fn overlays(&self) -> &[Overlay] {
+        &self.overlays
+    }
+
     pub fn header(&self) -> &Header {
         &self.header
     }
+
+    pub fn relocations(&self) -> &[Patch] {
+        &self.relocations
+    }
+
+    /// Executable file for `image`, the load image as it is in memory now. The relocations
+    /// are taken back out so it loads anywhere again, the appended data is kept
+    pub fn to_exe(&self, image: &[u8]) -> Vec<u8> {
+        let mut image = image.to_vec();
+        for patch in &self.relocations {
+            let addr = patch.relocation.segment as usize * 16 + patch.relocation.offset as usize;
+            if let Some(word) = image.get_mut(addr..addr + 2) {
+                let value = LittleEndian::read_u16(word).wrapping_sub(self.start as u16);
+                LittleEndian::write_u16(word, value);
+            }
+        }
+
+        let mut exe = self.header.to_bytes();
+        exe.extend_from_slice(&image);
+        exe.extend_from_slice(&self.appended);
+        exe
+    }
+
+    /// Paragraphs the program needs in memory, the load image plus the minimum extra allocation
+    pub fn paragraphs(&self) -> u32 {
+        self.data.len().div_ceil(16) as u32 + self.header.min_allocation as u32
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Relocation {
-    offset: u16,
-    segment: u16,
+    pub offset: u16,
+    /// Relative to the start of the load image
+    pub segment: u16,
+}
+
+/// A word the loader relocated, with the value it had in the file
+#[derive(Debug, Clone, Copy)]
+pub struct Patch {
+    pub relocation: Relocation,
+    pub original: u16,
+    pub relocated: u16,
 }
 
 pub struct Header {
+    /// Bytes used in the last 512 byte page, 0 means the whole page
     last_page_bytes: u16,
     pages_in_file: u16,
     relocation_rows: u16,
+    /// In paragraphs
     header_size: u16,
     min_allocation: u16,
     max_allocation: u16,
@@ -63,28 +177,70 @@ pub struct Header {
     pub initial_cs: u16,
     relocation_addr: u16,
     pub relocation_table: Vec<Relocation>,
+    /// 0 for the main program, overlays linked into the same file count up from 1
     overlay: u16,
 }
 
 impl Header {
-    pub fn new(bytes: &[u8]) -> Header {
+    pub fn new(bytes: &[u8]) -> Result<Header, Error> {
+        if bytes.len() < HEADER_LEN {
+            return Err(Error::MalformedHeader(format!(
+                "file is {} bytes, too short for a header",
+                bytes.len()
+            )));
+        }
+        // Some very old linkers wrote the signature backwards, DOS accepts both
+        if &bytes[0..2] != b"MZ" && &bytes[0..2] != b"ZM" {
+            return Err(Error::MalformedHeader(format!(
+                "missing MZ signature, found {:02x} {:02x}",
+                bytes[0], bytes[1]
+            )));
+        }
+
+        let last_page_bytes = LittleEndian::read_u16(&bytes[2..4]);
+        let pages_in_file = LittleEndian::read_u16(&bytes[4..6]);
         let relocations_count = LittleEndian::read_u16(&bytes[6..8]);
-        let mut relocations = vec![];
+        let header_size = LittleEndian::read_u16(&bytes[8..10]);
+        let relocation_addr = LittleEndian::read_u16(&bytes[24..26]);
+        if pages_in_file == 0 {
+            return Err(Error::MalformedHeader("file has no pages".into()));
+        }
+        if last_page_bytes > 512 {
+            return Err(Error::MalformedHeader(format!(
+                "last page has {last_page_bytes} bytes, pages are 512 bytes"
+            )));
+        }
+        if (header_size as usize) * 16 < HEADER_LEN {
+            return Err(Error::MalformedHeader(format!(
+                "header size of {header_size} paragraphs can't hold the header"
+            )));
+        }
+        if relocations_count > 0 && (relocation_addr as usize) < HEADER_LEN {
+            return Err(Error::MalformedHeader(format!(
+                "relocation table at 0x{relocation_addr:x} overlaps the header"
+            )));
+        }
 
-        // This should really be worked out using relocation_addr, but eh
+        let mut relocations = vec![];
         for n in 0..(relocations_count as usize) {
-            let n = n * 4;
+            let n = relocation_addr as usize + n * 4;
+            let Some(entry) = bytes.get(n..(n + 4)) else {
+                return Err(Error::TruncatedRelocations {
+                    expected: relocations_count,
+                    found: relocations.len(),
+                });
+            };
             relocations.push(Relocation {
-                offset: LittleEndian::read_u16(&bytes[(28 + n)..(30 + n)]),
-                segment: LittleEndian::read_u16(&bytes[(30 + n)..(32 + n)]),
+                offset: LittleEndian::read_u16(&entry[0..2]),
+                segment: LittleEndian::read_u16(&entry[2..4]),
             })
         }
 
-        Header {
-            last_page_bytes: LittleEndian::read_u16(&bytes[2..4]),
-            pages_in_file: LittleEndian::read_u16(&bytes[4..6]),
+        Ok(Header {
+            last_page_bytes,
+            pages_in_file,
             relocation_rows: relocations_count,
-            header_size: LittleEndian::read_u16(&bytes[8..10]),
+            header_size,
             min_allocation: LittleEndian::read_u16(&bytes[10..12]),
             max_allocation: LittleEndian::read_u16(&bytes[12..14]),
             initial_ss: LittleEndian::read_u16(&bytes[14..16]),
@@ -92,9 +248,93 @@ impl Header {
             checksum: LittleEndian::read_u16(&bytes[18..20]),
             initial_ip: LittleEndian::read_u16(&bytes[20..22]),
             initial_cs: LittleEndian::read_u16(&bytes[22..24]),
-            relocation_addr: LittleEndian::read_u16(&bytes[24..26]),
+            relocation_addr,
             relocation_table: relocations,
             overlay: LittleEndian::read_u16(&bytes[26..28]),
+        })
+    }
+
+    /// Header for a load image with the relocation table right after the fixed part.
+    /// Entry point and stack are relative to the start of the image
+    pub fn for_image(
+        image_len: usize,
+        relocations: Vec<Relocation>,
+        (initial_cs, initial_ip): (u16, u16),
+        (initial_ss, initial_sp): (u16, u16),
+        min_allocation: u16,
+    ) -> Self {
+        let header_size = (HEADER_LEN + relocations.len() * 4).div_ceil(16);
+        let file_size = header_size * 16 + image_len;
+        Header {
+            last_page_bytes: (file_size % 512) as u16,
+            pages_in_file: file_size.div_ceil(512) as u16,
+            relocation_rows: relocations.len() as u16,
+            header_size: header_size as u16,
+            min_allocation,
+            max_allocation: 0xffff,
+            initial_ss,
+            initial_sp,
+            checksum: 0,
+            initial_ip,
+            initial_cs,
+            relocation_addr: HEADER_LEN as u16,
+            relocation_table: relocations,
+            overlay: 0,
+        }
+    }
+
+    /// The header as it's stored in the file, padded to its size in paragraphs
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.header_len().max(HEADER_LEN)];
+        bytes[0..2].copy_from_slice(b"MZ");
+        let fields = [
+            self.last_page_bytes,
+            self.pages_in_file,
+            self.relocation_rows,
+            self.header_size,
+            self.min_allocation,
+            self.max_allocation,
+            self.initial_ss,
+            self.initial_sp,
+            self.checksum,
+            self.initial_ip,
+            self.initial_cs,
+            self.relocation_addr,
+            self.overlay,
+        ];
+        for (n, field) in fields.iter().enumerate() {
+            LittleEndian::write_u16(&mut bytes[(2 + n * 2)..], *field);
+        }
+
+        let table = self.relocation_addr as usize;
+        let table_end = table + self.relocation_table.len() * 4;
+        if bytes.len() < table_end {
+            bytes.resize(table_end, 0);
+        }
+        for (n, reloc) in self.relocation_table.iter().enumerate() {
+            LittleEndian::write_u16(&mut bytes[(table + n * 4)..], reloc.offset);
+            LittleEndian::write_u16(&mut bytes[(table + n * 4 + 2)..], reloc.segment);
+        }
+        bytes
+    }
+
+    /// Size of the header in bytes, the load image starts right after it
+    pub fn header_len(&self) -> usize {
+        self.header_size as usize * 16
+    }
+
+    pub fn overlay_number(&self) -> u16 {
+        self.overlay
+    }
+
+    /// Size of the executable as the header describes it, header included.
+    /// Anything in the file past this isn't part of the load image
+    pub fn file_size(&self) -> usize {
+        let pages = self.pages_in_file as usize * 512;
+        if self.last_page_bytes == 0 {
+            pages
+        } else {
+            pages - 512 + self.last_page_bytes as usize
         }
     }
 }
@@ -103,7 +343,11 @@ impl Header {
 mod tests {
     use byteorder::{ByteOrder, LittleEndian};
 
-    use crate::program::Header;
+    use crate::{
+        error::Error,
+        overlay::OverlayFormat,
+        program::{Header, Program, Relocation},
+    };
 
     #[test]
     fn parse_header() {
@@ -124,7 +368,7 @@ mod tests {
             0x00, 0x00, 0xA0, 0xF5, 0x00, 0x00, 0x6E, 0x05, 0x00, 0x10, 0x7D, 0x01, 0x00, 0x10,
         ];
 
-        let header = Header::new(&header);
+        let header = Header::new(&header).unwrap();
 
         assert_eq!(
             header.last_page_bytes,
@@ -150,11 +394,152 @@ mod tests {
         assert_eq!(header.overlay, LittleEndian::read_u16(&[0x00, 0x00]));
         assert!(header.relocation_table.len() == 42);
     }
+
+    #[test]
+    fn header_to_bytes() {
+        let relocations = vec![
+            Relocation {
+                offset: 0x0003,
+                segment: 0x0000,
+            },
+            Relocation {
+                offset: 0x000e,
+                segment: 0x0120,
+            },
+        ];
+        let header = Header::for_image(1000, relocations.clone(), (0x10, 0x20), (0x80, 0x100), 5);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), 48);
+
+        let parsed = Header::new(&bytes).unwrap();
+        assert_eq!(parsed.file_size(), 1048);
+        assert_eq!(parsed.header_len(), 48);
+        assert_eq!(parsed.relocation_table, relocations);
+        assert_eq!((parsed.initial_cs, parsed.initial_ip), (0x10, 0x20));
+        assert_eq!((parsed.initial_ss, parsed.initial_sp), (0x80, 0x100));
+        assert_eq!(parsed.min_allocation, 5);
+        assert_eq!(parsed.to_bytes(), bytes);
+    }
+
+    /// Minimal executable with the image following a one paragraph header,
+    /// relocations go in the header so they bump its size
+    fn synthetic_exe(image: &[u8], relocations: &[(u16, u16)], appended: &[u8]) -> Vec<u8> {
+        let header_paragraphs = (28 + relocations.len() * 4).div_ceil(16);
+        let mut exe = vec![0u8; header_paragraphs * 16];
+        let file_size = exe.len() + image.len();
+        exe[0..2].copy_from_slice(b"MZ");
+        LittleEndian::write_u16(&mut exe[2..4], (file_size % 512) as u16);
+        LittleEndian::write_u16(&mut exe[4..6], file_size.div_ceil(512) as u16);
+        LittleEndian::write_u16(&mut exe[6..8], relocations.len() as u16);
+        LittleEndian::write_u16(&mut exe[8..10], header_paragraphs as u16);
+        LittleEndian::write_u16(&mut exe[24..26], 28);
+        for (n, (segment, offset)) in relocations.iter().enumerate() {
+            LittleEndian::write_u16(&mut exe[(28 + n * 4)..], *offset);
+            LittleEndian::write_u16(&mut exe[(30 + n * 4)..], *segment);
+        }
+        exe.extend_from_slice(image);
+        exe.extend_from_slice(appended);
+        exe
+    }
+
+    #[test]
+    fn load_image_size() {
+        let exe = synthetic_exe(&[0x90; 40], &[], b"FBOV");
+        let program = Program::from_bytes("TEST.EXE", &exe, 0x1000).unwrap();
+        assert_eq!(program.data(), &[0x90; 40]);
+        assert_eq!(program.appended(), b"FBOV");
+        // Too short for the overlay header
+        assert_eq!(program.overlay_format(), OverlayFormat::Unknown);
+
+        let mut bad = exe.clone();
+        bad[0] = b'X';
+        assert!(matches!(
+            Program::from_bytes("TEST.EXE", &bad, 0x1000),
+            Err(Error::MalformedHeader(_))
+        ));
+
+        // Header claims more pages than the file has
+        let mut short = exe.clone();
+        short[4] = 2;
+        assert!(matches!(
+            Program::from_bytes("TEST.EXE", &short, 0x1000),
+            Err(Error::MalformedHeader(_))
+        ));
+    }
+
+    #[test]
+    fn relocations() {
+        let mut image = vec![0u8; 0x10010];
+        // Low byte carries into the high byte
+        image[0x0000..0x0002].copy_from_slice(&0x00ffu16.to_le_bytes());
+        // Wraps around past ffff
+        image[0x0012..0x0014].copy_from_slice(&0xf123u16.to_le_bytes());
+        // Word straddles a paragraph boundary
+        image[0x002f..0x0031].copy_from_slice(&0x0001u16.to_le_bytes());
+        // Same location written as two different segment:offset pairs, past 64K
+        image[0x10004..0x10006].copy_from_slice(&0x0010u16.to_le_bytes());
+        let relocations = [
+            (0x0000, 0x0000),
+            (0x0001, 0x0002),
+            (0x0002, 0x000f),
+            (0x1000, 0x0004),
+            (0x0fff, 0x0014),
+        ];
+        let exe = synthetic_exe(&image, &relocations, &[]);
+
+        let program = Program::from_bytes("TEST.EXE", &exe, 0x1f01).unwrap();
+        let data = program.data();
+        let word = |addr: usize| LittleEndian::read_u16(&data[addr..]);
+        assert_eq!(word(0x0000), 0x2000);
+        assert_eq!(word(0x0012), 0x1024);
+        assert_eq!(word(0x002f), 0x1f02);
+        // Both relocations applied to the same word
+        assert_eq!(word(0x10004), 0x3e12);
+
+        let patches = program.relocations();
+        assert_eq!(patches.len(), 5);
+        assert_eq!(patches[1].original, 0xf123);
+        assert_eq!(patches[1].relocated, 0x1024);
+        assert_eq!(patches[4].original, 0x1f11);
+        // Taking the relocations back out gives the file we loaded
+        assert_eq!(program.to_exe(program.data()), exe);
+
+        // A word that would end past the image
+        let exe = synthetic_exe(&[0; 16], &[(0x0000, 0x000f)], &[]);
+        assert!(matches!(
+            Program::from_bytes("TEST.EXE", &exe, 0x1000),
+            Err(Error::MalformedHeader(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert!(matches!(
+            Header::new(&[0x4D, 0x5A, 0x00]),
+            Err(Error::MalformedHeader(_))
+        ));
+
+        // Two relocations listed but only one in the file
+        let mut header = [0u8; 32];
+        header[0..2].copy_from_slice(b"MZ");
+        header[4] = 1;
+        header[6] = 2;
+        header[8] = 2;
+        header[24] = 28;
+        assert!(matches!(
+            Header::new(&header),
+            Err(Error::TruncatedRelocations {
+                expected: 2,
+                found: 1
+            })
+        ));
+    }
 }
 
 #[repr(C)]
 #[repr(packed)]
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 pub struct PSP {
     // Usually set to INT 0x20 (0xcd20) prog terminate
     exit_interrupt: u16,
@@ -169,26 +554,27 @@ pub struct PSP {
     com_bytes: u32,
     /// Terminate address used by INT 22, we need to jump to this addr on exit
     /// This forces a child program to return to it's parent program
-    term_addr: u32,
+    pub term_addr: u32,
     /// The Ctrl-Break exit address, a location of a subroutine for us to run
     /// when we encounter a Ctrl-Break
-    ctrl_break_addr: u32,
+    pub ctrl_break_addr: u32,
     /// Similar to the above. If we critically error, run the routine here
-    crit_err_addr: u32,
+    pub crit_err_addr: u32,
     /// Parent process's segment address
-    parent_addr: u16,
+    pub parent_addr: u16,
     /// File handle array for the process. It's completely undocumented for 2.x+
     /// /probably/ not in use for our case
     file_handle_array: [u8; 20],
     /// Segment address of the environment, or zero
-    env_segment_addr: u16,
-    stack_save: u32,
+    pub env_segment_addr: u16,
+    /// Parent's SS:SP while a child started with EXEC is running
+    pub stack_save: u32,
     /// File handle array size
     file_handle_size: u16,
     /// File handle array pointer
     file_handle_addr: u32,
     /// Pointer to previous PSP
-    prev_psp: u32,
+    pub prev_psp: u32,
     interim_flag: u8,
     truename_flag: u8,
     nn_flags: u16,
@@ -237,7 +623,7 @@ impl PSP {
             unopened_fcb_1: [0x0; 16],
             unopened_fcb_2: [0x0; 16],
             cmd_trail_chars: cmd.chars().count() as u8,
-            cmd_trail:  cmd_trail,
+            cmd_trail,
             stack_save: 0x0,
             interim_flag: 0x0,
             truename_flag: 0x0,
@@ -246,10 +632,21 @@ impl PSP {
             spacer_3: [0x0; 4],
         }
     }
+
+    /// Command tail as passed to EXEC, a length byte followed by the characters
+    pub fn set_command_tail(&mut self, tail: &[u8]) {
+        let len = tail.len().min(self.cmd_trail.len() - 1);
+        self.cmd_trail = [0x0; 127];
+        self.cmd_trail[..len].copy_from_slice(&tail[..len]);
+        self.cmd_trail[len] = 0x0D;
+        self.cmd_trail_chars = len as u8;
+    }
 }
 
 unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::core::slice::from_raw_parts((p as *const T) as *const u8, ::core::mem::size_of::<T>())
+    unsafe {
+        ::core::slice::from_raw_parts((p as *const T) as *const u8, ::core::mem::size_of::<T>())
+    }
 }
 
 impl<'a> From<&'a PSP> for &'a [u8] {
@@ -0,0 +1,267 @@
+use std::{cell::RefCell, path::Path, rc::Rc};
+
+use rhai::{Dynamic, EvalAltResult, FnPtr, NativeCallContext};
+
+use crate::{debugger::Debugger, engine::Catchpoint, error::Error, script};
+
+type RhaiResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Script callback for an interrupt
+struct Hook {
+    catchpoint: Catchpoint,
+    callback: FnPtr,
+}
+
+/// State the functions registered with Rhai share
+struct Host {
+    debugger: RefCell<Debugger<'static>>,
+    hooks: RefCell<Vec<Hook>>,
+}
+
+fn script_error(err: Error) -> Box<EvalAltResult> {
+    err.to_string().into()
+}
+
+impl Host {
+    fn reg(&self, name: &str) -> RhaiResult<i64> {
+        let cpu = self
+            .debugger
+            .borrow()
+            .engine
+            .read_cpu()
+            .map_err(script_error)?;
+        script::register(&cpu, name).ok_or_else(|| format!("unknown register '{name}'").into())
+    }
+
+    fn set_reg(&self, name: &str, value: i64) -> RhaiResult<()> {
+        let mut debugger = self.debugger.borrow_mut();
+        if !debugger
+            .engine
+            .write_register(name, value as u64)
+            .map_err(script_error)?
+        {
+            return Err(format!("unknown register '{name}'").into());
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: i64, len: usize) -> RhaiResult<Vec<u8>> {
+        let debugger = self.debugger.borrow();
+        debugger
+            .engine
+            .read_memory(addr as u64, len)
+            .map_err(script_error)
+    }
+
+    fn write(&self, addr: i64, data: &[u8]) -> RhaiResult<()> {
+        let mut debugger = self.debugger.borrow_mut();
+        debugger
+            .engine
+            .write_memory(addr as u64, data)
+            .map_err(script_error)
+    }
+
+    /// Run the emulator once, true if it stopped at a hooked interrupt and the
+    /// callbacks for it ran
+    fn resume(&self, ctx: &NativeCallContext, step: bool) -> RhaiResult<bool> {
+        let (num, ah) = {
+            let mut debugger = self.debugger.borrow_mut();
+            if debugger.engine.exited() {
+                return Ok(false);
+            }
+            let result = if step {
+                debugger.engine.step()
+            } else {
+                debugger.engine.cont()
+            };
+            debugger.forward_messages();
+            result.map_err(script_error)?;
+            let Some(num) = debugger.engine.caught() else {
+                return Ok(false);
+            };
+            let cpu = debugger.engine.read_cpu().map_err(script_error)?;
+            (num, script::register(&cpu, "ah").unwrap_or_default())
+        };
+
+        // Callbacks use the debugger themselves, so nothing may stay borrowed
+        let callbacks: Vec<FnPtr> = self
+            .hooks
+            .borrow()
+            .iter()
+            .filter(|hook| hook.catchpoint.matches(num as u32, ah as u64))
+            .map(|hook| hook.callback.clone())
+            .collect();
+        for callback in callbacks {
+            let _: Dynamic = callback.call_within_context(ctx, ())?;
+        }
+        Ok(true)
+    }
+
+    fn cont(&self, ctx: &NativeCallContext) -> RhaiResult<()> {
+        while self.resume(ctx, false)? {}
+        Ok(())
+    }
+
+    fn step(&self, ctx: &NativeCallContext) -> RhaiResult<()> {
+        if self.resume(ctx, true)? {
+            // The hooks ran between the INT and its service, finish the instruction
+            self.resume(ctx, true)?;
+        }
+        Ok(())
+    }
+
+    fn on_int(&self, num: i64, ah: Option<i64>, callback: FnPtr) {
+        let catchpoint = Catchpoint {
+            num: num as u8,
+            ah: ah.map(|ah| ah as u8),
+        };
+        self.debugger.borrow_mut().engine.add_catchpoint(catchpoint);
+        self.hooks.borrow_mut().push(Hook {
+            catchpoint,
+            callback,
+        });
+    }
+}
+
+/// Functions scripts use to drive the debugger
+fn register_functions(engine: &mut rhai::Engine, host: &Rc<Host>) {
+    let h = host.clone();
+    engine.register_fn("reg", move |name: &str| h.reg(name));
+    let h = host.clone();
+    engine.register_fn("set_reg", move |name: &str, value: i64| {
+        h.set_reg(name, value)
+    });
+    engine.register_fn("addr", |segment: i64, offset: i64| segment * 16 + offset);
+
+    let h = host.clone();
+    engine.register_fn("read_u8", move |addr: i64| {
+        h.read(addr, 1).map(|data| data[0] as i64)
+    });
+    let h = host.clone();
+    engine.register_fn("read_u16", move |addr: i64| {
+        h.read(addr, 2)
+            .map(|data| u16::from_le_bytes([data[0], data[1]]) as i64)
+    });
+    let h = host.clone();
+    engine.register_fn("read_string", move |addr: i64| {
+        h.debugger
+            .borrow()
+            .engine
+            .read_asciiz(addr as u64)
+            .map_err(script_error)
+    });
+    let h = host.clone();
+    engine.register_fn("write_u8", move |addr: i64, value: i64| {
+        h.write(addr, &[value as u8])
+    });
+    let h = host.clone();
+    engine.register_fn("write_u16", move |addr: i64, value: i64| {
+        h.write(addr, &(value as u16).to_le_bytes())
+    });
+
+    let h = host.clone();
+    engine.register_fn("step", move |ctx: NativeCallContext| h.step(&ctx));
+    let h = host.clone();
+    engine.register_fn("cont", move |ctx: NativeCallContext| h.cont(&ctx));
+    let h = host.clone();
+    engine.register_fn("exited", move || h.debugger.borrow().engine.exited());
+    let h = host.clone();
+    engine.register_fn("exit_status", move || {
+        h.debugger.borrow().engine.exit_status() as i64
+    });
+    let h = host.clone();
+    engine.register_fn("add_break", move |addr: i64| {
+        h.debugger.borrow_mut().engine.add_break(addr as u64)
+    });
+
+    let h = host.clone();
+    engine.register_fn("on_int", move |num: i64, callback: FnPtr| {
+        h.on_int(num, None, callback)
+    });
+    let h = host.clone();
+    engine.register_fn("on_int", move |num: i64, ah: i64, callback: FnPtr| {
+        h.on_int(num, Some(ah), callback)
+    });
+
+    let h = host.clone();
+    engine.register_fn("command", move |line: &str| {
+        h.debugger.borrow_mut().run_line(line).map_err(script_error)
+    });
+}
+
+/// Run a Rhai script against the debugger and hand the debugger back once it's done
+pub fn run_file(debugger: Debugger<'static>, path: &str) -> Result<Debugger<'static>, Error> {
+    let host = Rc::new(Host {
+        debugger: RefCell::new(debugger),
+        hooks: RefCell::new(Vec::new()),
+    });
+    let mut engine = rhai::Engine::new();
+    register_functions(&mut engine, &host);
+    let result = engine.run_file(path.into());
+
+    // The registered functions hold the other references
+    drop(engine);
+    let host = Rc::into_inner(host).expect("script functions outlived the script engine");
+    result.map_err(|err| Error::Script(format!("{path}: {err}")))?;
+    Ok(host.debugger.into_inner())
+}
+
+/// Scripts ending in .rhai are Rhai, everything else is debugger commands
+pub fn is_rhai(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("rhai"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use crate::{
+        debugger::Debugger,
+        engine::Engine,
+        program::Program,
+        rhai_script::{is_rhai, run_file},
+    };
+
+    #[test]
+    fn script_kind() {
+        assert!(is_rhai("hooks.rhai"));
+        assert!(is_rhai("dir/HOOKS.RHAI"));
+        assert!(!is_rhai("commands.dbg"));
+        assert!(!is_rhai("rhai"));
+    }
+
+    #[test]
+    fn hooks_against_a_program() {
+        let path = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let engine = Engine::new(Program::new(&path, 0x1000).unwrap()).unwrap();
+        let debugger = Debugger::with_output(engine, io::sink());
+
+        let script =
+            std::env::temp_dir().join(format!("unicorn_debugger_{}.rhai", std::process::id()));
+        fs::write(
+            &script,
+            r#"
+            let writes = 0;
+            on_int(0x21, 0x40, || {
+                writes += 1;
+                // Only the first 5 bytes of the message get written
+                set_reg("cx", 5);
+            });
+            add_break(addr(0x1000, 0x0f));
+            cont();
+            if exited() || reg("ip") != 0x0f { throw `not at print, ip ${reg("ip")}`; }
+            cont();
+            if writes != 1 { throw `${writes} writes`; }
+            if !exited() { throw "still running"; }
+            "#,
+        )
+        .unwrap();
+        let debugger = run_file(debugger, &script.to_string_lossy());
+        fs::remove_file(&script).unwrap();
+        let debugger = debugger.unwrap();
+        assert_eq!(debugger.engine.exit_status(), 2);
+        assert_eq!(debugger.engine.output(), b"Hello");
+    }
+}
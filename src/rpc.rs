@@ -0,0 +1,509 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::{Map, Value, json};
+
+use crate::{
+    engine::{Cpu, Engine, MEMORY_SIZE},
+    error::Error,
+};
+
+/// Error codes from the JSON-RPC 2.0 spec
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Server defined, the program couldn't be loaded or the emulator failed
+const ENGINE_ERROR: i64 = -32000;
+
+/// Instructions `get_trace` can go back
+const TRACE_LEN: usize = 1000;
+/// Most instructions one `step` runs, longer runs are what `continue` is for
+const MAX_STEPS: u64 = 1_000_000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        Self {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        Self {
+            code: ENGINE_ERROR,
+            message: err.to_string(),
+        }
+    }
+}
+
+fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn hex_decode(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn param<'p>(params: &'p Value, name: &str) -> Option<&'p Value> {
+    params.as_object().and_then(|params| params.get(name))
+}
+
+fn u64_param(params: &Value, name: &str) -> Result<u64, RpcError> {
+    param(params, name)
+        .ok_or_else(|| RpcError::invalid_params(format!("missing '{name}'")))?
+        .as_u64()
+        .ok_or_else(|| RpcError::invalid_params(format!("'{name}' must be a number")))
+}
+
+fn optional_u64_param(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    match param(params, name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(_) => u64_param(params, name).map(Some),
+    }
+}
+
+fn str_param<'p>(params: &'p Value, name: &str) -> Result<&'p str, RpcError> {
+    param(params, name)
+        .ok_or_else(|| RpcError::invalid_params(format!("missing '{name}'")))?
+        .as_str()
+        .ok_or_else(|| RpcError::invalid_params(format!("'{name}' must be a string")))
+}
+
+/// JSON-RPC 2.0 control of the emulator, one request or batch per line
+pub struct Server<'a, L> {
+    engine: Option<Engine<'a>>,
+    load: L,
+    /// How much of the program output the clients have seen
+    output_sent: usize,
+}
+
+impl<'a, L> Server<'a, L>
+where
+    L: FnMut(&str) -> Result<Engine<'a>, Error>,
+{
+    /// `load` is used for the load method, `engine` is a program loaded up front
+    pub fn new(load: L, engine: Option<Engine<'a>>) -> Self {
+        let mut server = Self {
+            engine: None,
+            load,
+            output_sent: 0,
+        };
+        if let Some(engine) = engine {
+            server.set_engine(engine);
+        }
+        server
+    }
+
+    /// Stdout belongs to the protocol, so the engine messages go in the results
+    fn set_engine(&mut self, mut engine: Engine<'a>) {
+        engine.collect_messages();
+        engine.set_trace(TRACE_LEN);
+        self.engine = Some(engine);
+        self.output_sent = 0;
+    }
+
+    fn engine(&mut self) -> Result<&mut Engine<'a>, RpcError> {
+        self.engine.as_mut().ok_or_else(|| RpcError {
+            code: ENGINE_ERROR,
+            message: "no program loaded".into(),
+        })
+    }
+
+    /// Where the program is and what it did since the last report
+    fn state(&mut self) -> Result<Value, RpcError> {
+        let output_sent = self.output_sent;
+        let engine = self.engine()?;
+        let cpu = engine.read_cpu()?;
+        let cs = cpu.register("cs").unwrap_or_default();
+        let ip = cpu.register("ip").unwrap_or_default();
+        let output = String::from_utf8_lossy(&engine.output()[output_sent..]).into_owned();
+        let output_len = engine.output().len();
+        let state = json!({
+            "cs": cs,
+            "ip": ip,
+            "address": cs * 16 + ip,
+            "exited": engine.exited(),
+            "exit_status": engine.exit_status(),
+            "exit_reason": engine.exit_reason().map(|reason| reason.to_string()),
+            "output": output,
+            "messages": engine.take_messages(),
+        });
+        self.output_sent = output_len;
+        Ok(state)
+    }
+
+    fn registers(cpu: &Cpu) -> Value {
+        let registers: Map<String, Value> = Cpu::REGISTERS
+            .iter()
+            .map(|name| (name.to_string(), json!(cpu.register(name))))
+            .collect();
+        Value::Object(registers)
+    }
+
+    fn trace(engine: &Engine, count: Option<u64>) -> Value {
+        let trace = engine.trace();
+        let skip = count.map_or(0, |count| trace.len().saturating_sub(count as usize));
+        let instructions: Vec<Value> = trace[skip..]
+            .iter()
+            .map(|fp| {
+                let text = engine
+                    .disassemble(fp.address())
+                    .map_or_else(|err| format!("({err})"), |(text, _)| text);
+                json!({
+                    "cs": fp.cs,
+                    "ip": fp.ip,
+                    "address": fp.address(),
+                    "instruction": text,
+                })
+            })
+            .collect();
+        json!({ "instructions": instructions })
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "load" => {
+                let path = str_param(params, "path")?;
+                let engine = (self.load)(path)?;
+                self.set_engine(engine);
+                self.state()
+            }
+            "step" => {
+                let count = optional_u64_param(params, "count")?.unwrap_or(1);
+                if count > MAX_STEPS {
+                    return Err(RpcError::invalid_params(format!(
+                        "'count' can be {MAX_STEPS} at most"
+                    )));
+                }
+                let engine = self.engine()?;
+                for _ in 0..count {
+                    if engine.exited() {
+                        break;
+                    }
+                    engine.step()?;
+                    // The program stopped at a catchpoint or fault, the client should look
+                    if engine.caught().is_some() || engine.caught_fault().is_some() {
+                        break;
+                    }
+                }
+                self.state()
+            }
+            "continue" => {
+                let engine = self.engine()?;
+                if !engine.exited() {
+                    engine.cont()?;
+                }
+                self.state()
+            }
+            "set_breakpoint" => {
+                let addr = u64_param(params, "address")?;
+                self.engine()?.add_break(addr);
+                Ok(json!({ "address": addr }))
+            }
+            "read_registers" => Ok(Self::registers(&self.engine()?.read_cpu()?)),
+            "read_memory" => {
+                let addr = u64_param(params, "address")?;
+                let len = u64_param(params, "length")?;
+                if addr.checked_add(len).is_none_or(|end| end > MEMORY_SIZE) {
+                    return Err(RpcError::invalid_params(format!(
+                        "memory ends at 0x{MEMORY_SIZE:x}"
+                    )));
+                }
+                let data = self.engine()?.read_memory(addr, len as usize)?;
+                Ok(json!({ "address": addr, "data": hex_encode(&data) }))
+            }
+            "write_memory" => {
+                let addr = u64_param(params, "address")?;
+                let data = hex_decode(str_param(params, "data")?)
+                    .ok_or_else(|| RpcError::invalid_params("'data' must be hex bytes"))?;
+                self.engine()?.write_memory(addr, &data)?;
+                Ok(json!({ "address": addr, "written": data.len() }))
+            }
+            "get_trace" => {
+                let count = optional_u64_param(params, "count")?;
+                Ok(Self::trace(self.engine()?, count))
+            }
+            _ => Err(RpcError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method '{method}'"),
+            }),
+        }
+    }
+
+    /// Response to one request, None for notifications
+    fn handle(&mut self, request: &Value) -> Option<Value> {
+        let id = request.get("id").cloned();
+        let method = request.get("method").and_then(Value::as_str);
+        let result = match method {
+            Some(method) if request.get("jsonrpc") == Some(&json!("2.0")) => {
+                let params = request.get("params").cloned().unwrap_or(Value::Null);
+                self.call(method, &params)
+            }
+            _ => Err(RpcError {
+                code: INVALID_REQUEST,
+                message: "not a JSON-RPC 2.0 request".into(),
+            }),
+        };
+        // Notifications get no answer, not even errors
+        if method.is_some() && id.is_none() {
+            return None;
+        }
+
+        let id = id.unwrap_or(Value::Null);
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": err.code, "message": err.message },
+            }),
+        })
+    }
+
+    /// Response line for a request line, None when there is nothing to send back
+    pub fn handle_line(&mut self, line: &str) -> Option<String> {
+        let response = match serde_json::from_str::<Value>(line) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                let responses: Vec<Value> = batch
+                    .iter()
+                    .filter_map(|request| self.handle(request))
+                    .collect();
+                if responses.is_empty() {
+                    return None;
+                }
+                Value::Array(responses)
+            }
+            Ok(Value::Array(_)) => self.handle(&Value::Null)?,
+            Ok(request) => self.handle(&request)?,
+            Err(err) => json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": PARSE_ERROR, "message": err.to_string() },
+            }),
+        };
+        Some(response.to_string())
+    }
+
+    /// Answer requests until the client closes its end
+    pub fn serve(&mut self, reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle_line(&line) {
+                writeln!(writer, "{response}")?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve clients of a Unix socket one after another, the program stays
+    /// loaded between connections
+    #[cfg(unix)]
+    pub fn serve_socket(&mut self, path: &std::path::Path) -> Result<(), Error> {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        for stream in listener.incoming() {
+            let stream = stream?;
+            self.serve(io::BufReader::new(stream.try_clone()?), stream)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use crate::{
+        engine::{Catchpoint, Engine},
+        error::Error,
+        program::Program,
+        rpc::{Server, hex_decode, hex_encode},
+    };
+
+    fn server() -> Server<'static, impl FnMut(&str) -> Result<Engine<'static>, Error>> {
+        Server::new(
+            |path: &str| Err(Error::MalformedHeader(format!("no '{path}' here"))),
+            None,
+        )
+    }
+
+    fn call(
+        server: &mut Server<'static, impl FnMut(&str) -> Result<Engine<'static>, Error>>,
+        line: &str,
+    ) -> Value {
+        serde_json::from_str(&server.handle_line(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn hex_data() {
+        assert_eq!(hex_encode(&[0xb8, 0x00, 0x4c]), "b8004c");
+        assert_eq!(hex_decode("B8004c"), Some(vec![0xb8, 0x00, 0x4c]));
+        assert_eq!(hex_decode("b80"), None);
+        assert_eq!(hex_decode("zz"), None);
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let mut server = server();
+        let response = call(&mut server, "{");
+        assert_eq!(response["error"]["code"], -32700);
+        assert_eq!(response["id"], Value::Null);
+
+        let response = call(&mut server, r#"{"jsonrpc":"2.0","id":1,"method":"fly"}"#);
+        assert_eq!(response["error"]["code"], -32601);
+        assert_eq!(response["id"], 1);
+
+        let response = call(&mut server, r#"{"id":2,"method":"step"}"#);
+        assert_eq!(response["error"]["code"], -32600);
+
+        let response = call(&mut server, r#"{"jsonrpc":"2.0","id":3,"method":"step"}"#);
+        assert_eq!(response["error"]["code"], -32000);
+        assert_eq!(response["error"]["message"], "no program loaded");
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":"a","method":"read_memory","params":{"address":"x"}}"#,
+        );
+        assert_eq!(response["error"]["code"], -32602);
+        assert_eq!(response["id"], "a");
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":4,"method":"load","params":{"path":"GAME.EXE"}}"#,
+        );
+        assert_eq!(response["error"]["code"], -32000);
+    }
+
+    #[test]
+    fn notifications_and_batches() {
+        let mut server = server();
+        assert_eq!(
+            server.handle_line(r#"{"jsonrpc":"2.0","method":"continue"}"#),
+            None
+        );
+
+        let response = call(
+            &mut server,
+            r#"[{"jsonrpc":"2.0","id":1,"method":"fly"},{"jsonrpc":"2.0","method":"fly"}]"#,
+        );
+        assert_eq!(
+            response,
+            json!([{
+                "jsonrpc": "2.0",
+                "id": 1,
+                "error": { "code": -32601, "message": "unknown method 'fly'" },
+            }])
+        );
+
+        let response = call(&mut server, "[]");
+        assert_eq!(response["error"]["code"], -32600);
+    }
+
+    #[test]
+    fn debug_session() {
+        let mut server = Server::new(|path: &str| Engine::new(Program::new(path, 0x1000)?), None);
+        let path = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let load = json!({"jsonrpc": "2.0", "id": 1, "method": "load", "params": {"path": path}});
+        let response = call(&mut server, &load.to_string());
+        assert_eq!(response["result"]["address"], 0x10000);
+        assert_eq!(response["result"]["exited"], false);
+
+        // The print subroutine
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":2,"method":"set_breakpoint","params":{"address":65551}}"#,
+        );
+        assert_eq!(response["result"]["address"], 0x1000f);
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":3,"method":"continue"}"#,
+        );
+        let state = &response["result"];
+        assert_eq!((&state["cs"], &state["ip"]), (&json!(0x1000), &json!(0x0f)));
+        assert_eq!(state["exited"], false);
+        assert_eq!(state["output"], "");
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":4,"method":"read_registers"}"#,
+        );
+        let registers = &response["result"];
+        assert_eq!(registers["ip"], 0x0f);
+        assert_eq!(registers["ds"], 0x1000);
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":5,"method":"continue"}"#,
+        );
+        let state = &response["result"];
+        assert_eq!(state["exited"], true);
+        assert_eq!(state["exit_status"], 2);
+        assert_eq!(state["output"], "Hello from DOS\r\n");
+    }
+
+    #[test]
+    fn bounded_requests() {
+        let path = format!("{}/asm/dos/HELLO.EXE", env!("CARGO_MANIFEST_DIR"));
+        let mut engine = Engine::new(Program::new(&path, 0x1000).unwrap()).unwrap();
+        engine.add_catchpoint(Catchpoint {
+            num: 0x21,
+            ah: None,
+        });
+        let mut server = Server::new(
+            |path: &str| Err(Error::MalformedHeader(format!("no '{path}' here"))),
+            Some(engine),
+        );
+
+        for params in [
+            r#"{"address":0,"length":1e15}"#,
+            r#"{"address":0,"length":1000000000000000}"#,
+            r#"{"address":18446744073709551615,"length":2}"#,
+            r#"{"address":1114096,"length":32}"#,
+        ] {
+            let request =
+                format!(r#"{{"jsonrpc":"2.0","id":1,"method":"read_memory","params":{params}}}"#);
+            let response = call(&mut server, &request);
+            assert_eq!(response["error"]["code"], -32602, "{params}");
+        }
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":2,"method":"read_memory","params":{"address":1114096,"length":16}}"#,
+        );
+        assert_eq!(response["result"]["data"].as_str().unwrap().len(), 32);
+
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":3,"method":"step","params":{"count":2000000}}"#,
+        );
+        assert_eq!(response["error"]["code"], -32602);
+
+        // The write in print is caught right after its INT
+        let response = call(
+            &mut server,
+            r#"{"jsonrpc":"2.0","id":4,"method":"step","params":{"count":1000}}"#,
+        );
+        let state = &response["result"];
+        assert_eq!(
+            (&state["ip"], &state["exited"]),
+            (&json!(0x1c), &json!(false))
+        );
+        assert_eq!(state["output"], "");
+    }
+}
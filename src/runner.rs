@@ -0,0 +1,153 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    debugger::{Check, Debugger},
+    engine::Engine,
+    error::Error,
+};
+
+/// Exit status when an assertion failed or a script couldn't run
+const FAILED_STATUS: i32 = 1;
+
+/// Program a test script runs, named in a `# program: NAME.EXE` line relative
+/// to the script, otherwise the executable next to it with the same name
+fn program_for(script: &Path, source: &str) -> PathBuf {
+    let dir = script.parent().unwrap_or(Path::new(""));
+    let named = source.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix('#')
+            .map(str::trim)
+            .and_then(|comment| comment.strip_prefix("program:"))
+            .map(str::trim)
+    });
+    if let Some(name) = named {
+        return dir.join(name);
+    }
+
+    let upper = script.with_extension("EXE");
+    if upper.exists() {
+        upper
+    } else {
+        script.with_extension("exe")
+    }
+}
+
+/// Run one script, the number of passed checks and the failed ones
+fn run_test<'a>(
+    script: &str,
+    load: &mut impl FnMut(&str) -> Result<Engine<'a>, Error>,
+) -> Result<(usize, Vec<Check>), Error> {
+    let source = fs::read_to_string(script)?;
+    let program = program_for(Path::new(script), &source);
+    println!("Running '{script}' against '{}'", program.display());
+
+    let mut debugger = Debugger::new(load(&program.to_string_lossy())?);
+    debugger.run_file(script)?;
+    let failed: Vec<Check> = debugger
+        .checks()
+        .iter()
+        .filter(|check| !check.passed)
+        .cloned()
+        .collect();
+    Ok((debugger.checks().len() - failed.len(), failed))
+}
+
+/// Run every script and print a summary, the status is 0 when all checks passed
+pub fn run_tests<'a>(
+    scripts: &[String],
+    mut load: impl FnMut(&str) -> Result<Engine<'a>, Error>,
+) -> i32 {
+    let mut failed_scripts = Vec::new();
+    for script in scripts {
+        match run_test(script, &mut load) {
+            Ok((passed, failed)) if failed.is_empty() => println!("'{script}': {passed} passed"),
+            Ok((passed, failed)) => {
+                println!("'{script}': {passed} passed, {} failed", failed.len());
+                for check in failed {
+                    println!("  {script}:{}: {}", check.line, check.description);
+                }
+                failed_scripts.push(script);
+            }
+            Err(err) => {
+                println!("'{script}': error: {err}");
+                failed_scripts.push(script);
+            }
+        }
+    }
+
+    println!(
+        "{} of {} scripts passed",
+        scripts.len() - failed_scripts.len(),
+        scripts.len()
+    );
+    if failed_scripts.is_empty() {
+        0
+    } else {
+        FAILED_STATUS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    use crate::{
+        engine::Engine,
+        program::Program,
+        runner::{program_for, run_test, run_tests},
+    };
+
+    #[test]
+    fn script_program() {
+        let script = Path::new("tests/list.dbg");
+        assert_eq!(
+            program_for(script, "# Lists files\n  #  program: ../TXLIST.EXE\nr\n"),
+            PathBuf::from("tests/../TXLIST.EXE")
+        );
+        assert_eq!(program_for(script, "r\n"), PathBuf::from("tests/list.exe"));
+    }
+
+    #[test]
+    fn pass_and_fail_counts() {
+        let dir =
+            std::env::temp_dir().join(format!("unicorn_debugger_runner_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let header = format!(
+            "# program: {}/asm/dos/HELLO.EXE\n",
+            env!("CARGO_MANIFEST_DIR")
+        );
+        let passing = dir.join("pass.dbg");
+        fs::write(
+            &passing,
+            format!("{header}r\nexpect-output \"Hello from DOS\"\nassert al == 2\n"),
+        )
+        .unwrap();
+        let failing = dir.join("fail.dbg");
+        fs::write(
+            &failing,
+            format!("{header}r\nassert al == 3, \"version 3\"\nassert al == 2\nexpect-output \"Goodbye\"\n"),
+        )
+        .unwrap();
+        let mut load = |path: &str| Engine::new(Program::new(path, 0x1000)?);
+
+        let (passed, failed) = run_test(&passing.to_string_lossy(), &mut load).unwrap();
+        assert_eq!((passed, failed.len()), (2, 0));
+
+        let (passed, failed) = run_test(&failing.to_string_lossy(), &mut load).unwrap();
+        assert_eq!(passed, 1);
+        let lines: Vec<usize> = failed.iter().map(|check| check.line).collect();
+        assert_eq!(lines, [3, 5]);
+
+        let scripts = [passing, failing].map(|script| script.to_string_lossy().into_owned());
+        assert_eq!(run_tests(&scripts[..1], &mut load), 0);
+        assert_eq!(run_tests(&scripts, &mut load), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
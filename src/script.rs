@@ -0,0 +1,388 @@
+use crate::{engine::Cpu, error::Error};
+
+/// Where expressions get their values from
+pub trait Env {
+    fn cpu(&self) -> Result<Cpu, Error>;
+    /// Word at a linear address
+    fn read_u16(&self, addr: u64) -> Result<u16, Error>;
+    fn variable(&self, name: &str) -> Option<i64>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    /// segment:offset, the linear address
+    Segment,
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    /// Operators from loosest to tightest binding, the parser climbs this table
+    const PRECEDENCE: [&'static [(&'static str, BinaryOp)]; 9] = [
+        &[(":", BinaryOp::Segment)],
+        &[("||", BinaryOp::Or)],
+        &[("&&", BinaryOp::And)],
+        &[
+            ("==", BinaryOp::Eq),
+            ("!=", BinaryOp::Ne),
+            ("<=", BinaryOp::Le),
+            (">=", BinaryOp::Ge),
+            ("<", BinaryOp::Lt),
+            (">", BinaryOp::Gt),
+        ],
+        &[("|", BinaryOp::BitOr)],
+        &[("^", BinaryOp::BitXor)],
+        &[("&", BinaryOp::BitAnd)],
+        &[
+            ("<<", BinaryOp::Shl),
+            (">>", BinaryOp::Shr),
+            ("+", BinaryOp::Add),
+            ("-", BinaryOp::Sub),
+        ],
+        &[
+            ("*", BinaryOp::Mul),
+            ("/", BinaryOp::Div),
+            ("%", BinaryOp::Rem),
+        ],
+    ];
+
+    fn apply(self, lhs: i64, rhs: i64) -> Result<i64, Error> {
+        let value = match self {
+            BinaryOp::Segment => lhs.wrapping_mul(16).wrapping_add(rhs),
+            BinaryOp::Or => (lhs != 0 || rhs != 0) as i64,
+            BinaryOp::And => (lhs != 0 && rhs != 0) as i64,
+            BinaryOp::Eq => (lhs == rhs) as i64,
+            BinaryOp::Ne => (lhs != rhs) as i64,
+            BinaryOp::Lt => (lhs < rhs) as i64,
+            BinaryOp::Le => (lhs <= rhs) as i64,
+            BinaryOp::Gt => (lhs > rhs) as i64,
+            BinaryOp::Ge => (lhs >= rhs) as i64,
+            BinaryOp::BitOr => lhs | rhs,
+            BinaryOp::BitXor => lhs ^ rhs,
+            BinaryOp::BitAnd => lhs & rhs,
+            BinaryOp::Shl => lhs.wrapping_shl(rhs as u32),
+            BinaryOp::Shr => lhs.wrapping_shr(rhs as u32),
+            BinaryOp::Add => lhs.wrapping_add(rhs),
+            BinaryOp::Sub => lhs.wrapping_sub(rhs),
+            BinaryOp::Mul => lhs.wrapping_mul(rhs),
+            BinaryOp::Div | BinaryOp::Rem if rhs == 0 => {
+                return Err(Error::Script("division by zero".into()));
+            }
+            BinaryOp::Div => lhs.wrapping_div(rhs),
+            BinaryOp::Rem => lhs.wrapping_rem(rhs),
+        };
+        Ok(value)
+    }
+}
+
+/// Numbers are hex like everywhere else in the debugger, names are registers
+/// or variables and `[addr]` reads a word from memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Name(String),
+    Memory(Box<Expr>),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+/// Value of a register by name, the 8 bit halves of ax-dx included
+pub fn register(cpu: &Cpu, name: &str) -> Option<i64> {
+    if let Some(value) = cpu.register(name) {
+        return Some(value as i64);
+    }
+    let (word, half) = match name.as_bytes() {
+        [reg @ b'a'..=b'd', half @ (b'l' | b'h')] => (format!("{}x", *reg as char), *half),
+        _ => return None,
+    };
+    let value = cpu.register(&word)? as i64;
+    Some(if half == b'l' {
+        value & 0xff
+    } else {
+        value >> 8
+    })
+}
+
+/// Whether `name` is a register, variables can't use these names
+pub fn is_register(name: &str) -> bool {
+    Cpu::REGISTERS.contains(&name) || matches!(name.as_bytes(), [b'a'..=b'd', b'l' | b'h'])
+}
+
+impl Expr {
+    pub fn eval(&self, env: &dyn Env) -> Result<i64, Error> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Name(name) => {
+                if is_register(name) {
+                    return Ok(register(&env.cpu()?, name).unwrap_or_default());
+                }
+                env.variable(name)
+                    .ok_or_else(|| Error::Script(format!("undefined variable '{name}'")))
+            }
+            Expr::Memory(addr) => Ok(env.read_u16(addr.eval(env)? as u64)? as i64),
+            Expr::Neg(expr) => Ok(expr.eval(env)?.wrapping_neg()),
+            Expr::Not(expr) => Ok((expr.eval(env)? == 0) as i64),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(env)?;
+                // Short circuit so `x != 0 && [x] == 1` doesn't read memory at 0
+                match (op, lhs) {
+                    (BinaryOp::And, 0) => return Ok(0),
+                    (BinaryOp::Or, lhs) if lhs != 0 => return Ok(1),
+                    _ => {}
+                }
+                op.apply(lhs, rhs.eval(env)?)
+            }
+        }
+    }
+
+    /// Parse a whole expression, errors carry the 0 based position in `text`
+    pub fn parse(text: &str) -> Result<Self, (usize, String)> {
+        let mut parser = ExprParser { text, pos: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err((parser.pos, format!("unexpected '{}'", &text[parser.pos..])));
+        }
+        Ok(expr)
+    }
+}
+
+struct ExprParser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl ExprParser<'_> {
+    fn rest(&self) -> &str {
+        &self.text[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, (usize, String)> {
+        if level == BinaryOp::PRECEDENCE.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        'operators: loop {
+            self.skip_whitespace();
+            for (symbol, op) in BinaryOp::PRECEDENCE[level] {
+                // Don't take the < of << or the & of &&
+                let longer = BinaryOp::PRECEDENCE
+                    .iter()
+                    .flat_map(|level| level.iter())
+                    .any(|(other, _)| other.len() > symbol.len() && self.rest().starts_with(other));
+                if !longer && self.eat(symbol) {
+                    let rhs = self.binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'operators;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, (usize, String)> {
+        if self.eat("-") {
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, (usize, String)> {
+        self.skip_whitespace();
+        let start = self.pos;
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            if !self.eat(")") {
+                return Err((self.pos, "expected ')'".into()));
+            }
+            return Ok(expr);
+        }
+        if self.eat("[") {
+            let addr = self.binary(0)?;
+            if !self.eat("]") {
+                return Err((self.pos, "expected ']'".into()));
+            }
+            return Ok(Expr::Memory(Box::new(addr)));
+        }
+
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest().len());
+        let text = self.text;
+        let word = &text[self.pos..self.pos + len];
+        let Some(first) = word.chars().next() else {
+            return Err((start, "expected a value".into()));
+        };
+        self.pos += len;
+        if first.is_ascii_digit() {
+            let digits = word.strip_prefix("0x").unwrap_or(word);
+            i64::from_str_radix(digits, 16)
+                .map(Expr::Number)
+                .map_err(|_| (start, format!("cannot parse number '{word}'")))
+        } else {
+            Ok(Expr::Name(word.into()))
+        }
+    }
+}
+
+/// Piece of an echo line, `{expr}` is replaced by the value in hex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Part {
+    Text(String),
+    Expr(Expr),
+}
+
+/// Split echo text into literal text and `{expr}` parts, `{{` and `}}` are literal braces
+pub fn parse_interpolation(text: &str) -> Result<Vec<Part>, (usize, String)> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().is_some_and(|(_, next)| *next == '{') => {
+                chars.next();
+                literal.push('{');
+            }
+            '}' if chars.peek().is_some_and(|(_, next)| *next == '}') => {
+                chars.next();
+                literal.push('}');
+            }
+            '{' => {
+                let Some(end) = text[idx..].find('}') else {
+                    return Err((idx, "expected '}' after the expression".into()));
+                };
+                let expr = Expr::parse(&text[idx + 1..idx + end])
+                    .map_err(|(pos, message)| (idx + 1 + pos, message))?;
+                if !literal.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut literal)));
+                }
+                parts.push(Part::Expr(expr));
+                while chars.peek().is_some_and(|(next, _)| *next <= idx + end) {
+                    chars.next();
+                }
+            }
+            _ => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(Part::Text(literal));
+    }
+    Ok(parts)
+}
+
+/// Put the values into an interpolated line
+pub fn interpolate(parts: &[Part], env: &dyn Env) -> Result<String, Error> {
+    let mut line = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => line.push_str(text),
+            Part::Expr(expr) => line.push_str(&format!("{:x}", expr.eval(env)?)),
+        }
+    }
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        engine::Cpu,
+        error::Error,
+        script::{Env, Expr, Part, interpolate, parse_interpolation},
+    };
+
+    struct TestEnv {
+        variables: HashMap<String, i64>,
+    }
+
+    impl Env for TestEnv {
+        fn cpu(&self) -> Result<Cpu, Error> {
+            Ok(Cpu::default())
+        }
+
+        fn read_u16(&self, addr: u64) -> Result<u16, Error> {
+            Ok(addr as u16 ^ 0xffff)
+        }
+
+        fn variable(&self, name: &str) -> Option<i64> {
+            self.variables.get(name).copied()
+        }
+    }
+
+    #[test]
+    fn expressions() {
+        let env = TestEnv {
+            variables: HashMap::from([("count".into(), 3)]),
+        };
+        let eval = |text: &str| Expr::parse(text).unwrap().eval(&env).unwrap();
+        assert_eq!(eval("count + 2 * 10"), 0x23);
+        assert_eq!(eval("(count + 2) * 10"), 0x50);
+        assert_eq!(eval("1 << 4 | 1"), 0x11);
+        assert_eq!(eval("count >= 3 && count != 4"), 1);
+        assert_eq!(eval("!count || -1 < 0"), 1);
+        assert_eq!(eval("[10:2]"), 0xfefd);
+        assert_eq!(eval("0x10 - 0ff"), -0xef);
+        assert_eq!(eval("ax + al"), 0);
+
+        assert_eq!(Expr::parse("1 +").unwrap_err().0, 3);
+        assert_eq!(Expr::parse("(1").unwrap_err().0, 2);
+        assert_eq!(Expr::parse("1 2").unwrap_err().0, 2);
+        let err = Expr::parse("missing").unwrap().eval(&env).unwrap_err();
+        assert!(matches!(err, Error::Script(_)));
+        let err = Expr::parse("1 / 0").unwrap().eval(&env).unwrap_err();
+        assert!(matches!(err, Error::Script(_)));
+    }
+
+    #[test]
+    fn interpolation() {
+        let env = TestEnv {
+            variables: HashMap::from([("x".into(), 0x2a)]),
+        };
+        let parts = parse_interpolation("x={x} {{x}} next={x + 1}").unwrap();
+        assert_eq!(parts[0], Part::Text("x=".into()));
+        assert_eq!(
+            interpolate(&parts, &env).unwrap(),
+            "x=2a {x} next=2b".to_string()
+        );
+        assert_eq!(parse_interpolation("x={x").unwrap_err().0, 2);
+        assert_eq!(parse_interpolation("x={x +}").unwrap_err().0, 6);
+    }
+}
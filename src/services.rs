@@ -0,0 +1,226 @@
+use std::{fmt::Display, rc::Rc};
+
+use unicorn_engine::Unicorn;
+
+use crate::{
+    engine::{Cpu, EngineData},
+    error::Error,
+};
+
+/// Emulates one interrupt service, `Cpu` holds the registers at the INT instruction
+pub type Handler = Rc<dyn Fn(&mut Unicorn<EngineData>, &Cpu) -> Result<(), Error>>;
+
+/// Which calls a handler answers, `ah` and `al` match any value when None
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServiceKey {
+    pub int: u8,
+    pub ah: Option<u8>,
+    pub al: Option<u8>,
+}
+
+impl ServiceKey {
+    pub fn int(int: u8) -> Self {
+        Self {
+            int,
+            ah: None,
+            al: None,
+        }
+    }
+
+    pub fn ah(int: u8, ah: u8) -> Self {
+        Self {
+            int,
+            ah: Some(ah),
+            al: None,
+        }
+    }
+
+    pub fn ax(int: u8, ah: u8, al: u8) -> Self {
+        Self {
+            int,
+            ah: Some(ah),
+            al: Some(al),
+        }
+    }
+
+    fn matches(&self, int: u8, ah: u8, al: u8) -> bool {
+        self.int == int
+            && self.ah.is_none_or(|key| key == ah)
+            && self.al.is_none_or(|key| key == al)
+    }
+
+    /// Keys naming AH and AL win over keys naming only AH, those over the whole interrupt
+    fn specificity(&self) -> u8 {
+        self.ah.is_some() as u8 + self.al.is_some() as u8
+    }
+}
+
+impl Display for ServiceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "INT {:02X}h", self.int)?;
+        if let Some(ah) = self.ah {
+            write!(f, "/{ah:02X}h")?;
+        }
+        if let Some(al) = self.al {
+            write!(f, "/{al:02X}h")?;
+        }
+        Ok(())
+    }
+}
+
+/// How faithfully a service is emulated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    Implemented,
+    /// Answers with fixed values so programs keep going
+    Stubbed,
+    /// Registered over a built in handler for the same key
+    Overridden,
+}
+
+impl Display for Support {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Support::Implemented => "implemented",
+            Support::Stubbed => "stubbed",
+            Support::Overridden => "overridden",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Service {
+    pub key: ServiceKey,
+    /// Name used when tracing calls and listing services
+    pub name: String,
+    pub support: Support,
+    pub handler: Handler,
+}
+
+/// Dispatches INT instructions to the service registered for the interrupt and AX
+pub struct ServiceRegistry {
+    services: Vec<Service>,
+}
+
+impl ServiceRegistry {
+    /// Registry without any services, every interrupt is unimplemented
+    pub fn empty() -> Self {
+        Self {
+            services: Vec::new(),
+        }
+    }
+
+    /// Register a service, services registered later take priority over ones
+    /// with the same key so the built in handlers can be overridden
+    pub fn register(
+        &mut self,
+        key: ServiceKey,
+        name: impl Into<String>,
+        support: Support,
+        handler: impl Fn(&mut Unicorn<EngineData>, &Cpu) -> Result<(), Error> + 'static,
+    ) {
+        let support = if self.services.iter().any(|service| service.key == key) {
+            Support::Overridden
+        } else {
+            support
+        };
+        self.services.push(Service {
+            key,
+            name: name.into(),
+            support,
+            handler: Rc::new(handler),
+        });
+    }
+
+    /// The service handling this call, the most specific key wins
+    pub fn find(&self, int: u8, ah: u8, al: u8) -> Option<&Service> {
+        self.services
+            .iter()
+            .rev()
+            .filter(|service| service.key.matches(int, ah, al))
+            .reduce(|best, service| {
+                if service.key.specificity() > best.key.specificity() {
+                    service
+                } else {
+                    best
+                }
+            })
+    }
+
+    /// Whether any service is registered for the interrupt, otherwise the whole
+    /// interrupt is unknown rather than the function in AH
+    pub fn handles(&self, int: u8) -> bool {
+        self.services.iter().any(|service| service.key.int == int)
+    }
+
+    /// The services in effect sorted by key, overridden handlers are left out
+    pub fn list(&self) -> Vec<&Service> {
+        let mut services: Vec<&Service> = Vec::new();
+        for service in self.services.iter().rev() {
+            if !services.iter().any(|listed| listed.key == service.key) {
+                services.push(service);
+            }
+        }
+        services.sort_by_key(|service| service.key);
+        services
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::{ServiceKey, ServiceRegistry, Support};
+
+    #[test]
+    fn lookup_and_overrides() {
+        let mut services = ServiceRegistry::empty();
+        services.register(
+            ServiceKey::int(0x20),
+            "terminate",
+            Support::Implemented,
+            |_, _| Ok(()),
+        );
+        services.register(
+            ServiceKey::ah(0x21, 0x4a),
+            "resize",
+            Support::Implemented,
+            |_, _| Ok(()),
+        );
+        services.register(
+            ServiceKey::ax(0x21, 0x4a, 0x01),
+            "query",
+            Support::Stubbed,
+            |_, _| Ok(()),
+        );
+
+        assert_eq!(services.find(0x20, 0x4c, 0).unwrap().name, "terminate");
+        assert_eq!(services.find(0x21, 0x4a, 0x00).unwrap().name, "resize");
+        assert_eq!(services.find(0x21, 0x4a, 0x01).unwrap().name, "query");
+        assert!(services.find(0x21, 0x4b, 0x00).is_none());
+        assert!(services.handles(0x21));
+        assert!(!services.handles(0x10));
+
+        // Overriding the AH handler keeps the more specific AL handler in front
+        services.register(
+            ServiceKey::ah(0x21, 0x4a),
+            "mine",
+            Support::Implemented,
+            |_, _| Ok(()),
+        );
+        assert_eq!(services.find(0x21, 0x4a, 0x00).unwrap().name, "mine");
+        assert_eq!(services.find(0x21, 0x4a, 0x01).unwrap().name, "query");
+
+        let listed: Vec<_> = services
+            .list()
+            .iter()
+            .map(|service| (service.key.to_string(), service.support))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("INT 20h".to_string(), Support::Implemented),
+                ("INT 21h/4Ah".to_string(), Support::Overridden),
+                ("INT 21h/4Ah/01h".to_string(), Support::Stubbed),
+            ]
+        );
+    }
+}
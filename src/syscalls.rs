@@ -0,0 +1,188 @@
+use crate::drive::DosError;
+
+/// Registers and memory a logged call is decoded from
+pub trait CallState {
+    /// 16 bit registers and the 8 bit halves of ax-dx
+    fn reg(&self, name: &str) -> u64;
+
+    /// ASCIIZ string at `segment`:`offset`, both given as register names
+    fn string(&self, segment: &str, offset: &str) -> String;
+
+    fn carry(&self) -> bool;
+}
+
+/// Name and arguments of a call, read before the service runs
+pub fn describe_call(int: u8, state: &dyn CallState) -> String {
+    if int != 0x21 {
+        return format!("INT{int:02X}");
+    }
+    let ah = state.reg("ah");
+    let path = || format!("{:?}", state.string("ds", "dx"));
+    let handle = || format!("handle={}", state.reg("bx"));
+    let args = match ah {
+        0x00 => "terminate".into(),
+        0x1a => format!("set_dta {:04x}:{:04x}", state.reg("ds"), state.reg("dx")),
+        0x25 => format!(
+            "set_vector int={:02x} handler={:04x}:{:04x}",
+            state.reg("al"),
+            state.reg("ds"),
+            state.reg("dx")
+        ),
+        0x31 => format!(
+            "keep code={} paragraphs={:x}",
+            state.reg("al"),
+            state.reg("dx")
+        ),
+        0x35 => format!("get_vector int={:02x}", state.reg("al")),
+        0x39 => format!("mkdir {}", path()),
+        0x3a => format!("rmdir {}", path()),
+        0x3b => format!("chdir {}", path()),
+        0x3c => format!("create {} attr={:02x}", path(), state.reg("cx")),
+        0x3d => {
+            let mode = match state.reg("al") & 0x07 {
+                0 => "ro",
+                1 => "wo",
+                2 => "rw",
+                _ => "?",
+            };
+            format!("open {} mode={mode}", path())
+        }
+        0x3e => format!("close {}", handle()),
+        0x3f => format!("read {} count={}", handle(), state.reg("cx")),
+        0x40 => format!("write {} count={}", handle(), state.reg("cx")),
+        0x41 => format!("delete {}", path()),
+        0x42 => {
+            let whence = match state.reg("al") {
+                0 => "start",
+                1 => "current",
+                2 => "end",
+                _ => "?",
+            };
+            let offset = ((state.reg("cx") << 16) | state.reg("dx")) as i32;
+            format!("seek {} offset={offset} from={whence}", handle())
+        }
+        0x43 if state.reg("al") == 0 => format!("get_attributes {}", path()),
+        0x43 => format!("set_attributes {} attr={:02x}", path(), state.reg("cx")),
+        0x44 => format!("ioctl {} function={:x}", handle(), state.reg("al")),
+        0x4a => format!(
+            "resize segment={:04x} paragraphs={:x}",
+            state.reg("es"),
+            state.reg("bx")
+        ),
+        0x4b => format!("exec {}", path()),
+        0x4c => format!("exit code={}", state.reg("al")),
+        0x4e => format!("find_first {} attr={:02x}", path(), state.reg("cx")),
+        0x4f => "find_next".into(),
+        0x56 => format!("rename {} {:?}", path(), state.string("es", "di")),
+        0x57 if state.reg("al") == 0 => format!("get_timestamp {}", handle()),
+        0x57 => format!("set_timestamp {}", handle()),
+        _ => format!("ax={:04x}", state.reg("ax")),
+    };
+    format!("INT21/{ah:02X} {args}")
+}
+
+/// What a call returned, read after the service ran
+pub fn describe_result(int: u8, ah: u8, state: &dyn CallState) -> String {
+    if int != 0x21 {
+        return format!("ax={:04x}", state.reg("ax"));
+    }
+    // These report errors with the carry flag
+    if matches!(
+        ah,
+        0x39..=0x44 | 0x47 | 0x4a | 0x4b | 0x4e | 0x4f | 0x56 | 0x57
+    ) && state.carry()
+    {
+        let code = state.reg("ax") as u16;
+        return match DosError::from_code(code) {
+            Some(err) => format!("error {code} ({err:?})"),
+            None => format!("error {code}"),
+        };
+    }
+    match ah {
+        0x3c | 0x3d => format!("handle {}", state.reg("ax")),
+        0x3f | 0x40 => format!("{} bytes", state.reg("ax")),
+        0x42 => format!("position {}", (state.reg("dx") << 16) | state.reg("ax")),
+        0x43 => format!("attr={:02x}", state.reg("cx")),
+        0x35 => format!("{:04x}:{:04x}", state.reg("es"), state.reg("bx")),
+        0x19 => format!("drive {}", state.reg("al")),
+        0x30 => format!("version {}.{}", state.reg("al"), state.reg("ah")),
+        0x4d => format!("code={:04x}", state.reg("ax")),
+        _ => "ok".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::syscalls::{CallState, describe_call, describe_result};
+
+    struct State {
+        regs: HashMap<&'static str, u64>,
+        carry: bool,
+    }
+
+    impl CallState for State {
+        fn reg(&self, name: &str) -> u64 {
+            let ax = self.regs.get("ax").copied().unwrap_or_default();
+            match name {
+                "ah" => ax >> 8,
+                "al" => ax & 0xff,
+                _ => self.regs.get(name).copied().unwrap_or_default(),
+            }
+        }
+
+        fn string(&self, _segment: &str, offset: &str) -> String {
+            match offset {
+                "dx" => "DATA.TXT".into(),
+                _ => "NEW.TXT".into(),
+            }
+        }
+
+        fn carry(&self) -> bool {
+            self.carry
+        }
+    }
+
+    fn state(regs: &[(&'static str, u64)], carry: bool) -> State {
+        State {
+            regs: regs.iter().copied().collect(),
+            carry,
+        }
+    }
+
+    #[test]
+    fn decode_calls() {
+        let open = state(&[("ax", 0x3d00)], false);
+        assert_eq!(
+            describe_call(0x21, &open),
+            "INT21/3D open \"DATA.TXT\" mode=ro"
+        );
+        let opened = state(&[("ax", 5)], false);
+        assert_eq!(describe_result(0x21, 0x3d, &opened), "handle 5");
+        let failed = state(&[("ax", 2)], true);
+        assert_eq!(
+            describe_result(0x21, 0x3d, &failed),
+            "error 2 (FileNotFound)"
+        );
+
+        let seek = state(
+            &[("ax", 0x4202), ("bx", 5), ("cx", 0xffff), ("dx", 0xfff0)],
+            false,
+        );
+        assert_eq!(
+            describe_call(0x21, &seek),
+            "INT21/42 seek handle=5 offset=-16 from=end"
+        );
+        let rename = state(&[("ax", 0x5600)], false);
+        assert_eq!(
+            describe_call(0x21, &rename),
+            "INT21/56 rename \"DATA.TXT\" \"NEW.TXT\""
+        );
+        assert_eq!(
+            describe_call(0x21, &state(&[("ax", 0x0900)], false)),
+            "INT21/09 ax=0900"
+        );
+        assert_eq!(describe_call(0x11, &open), "INT11");
+    }
+}
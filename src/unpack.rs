@@ -0,0 +1,264 @@
+use std::{fmt::Display, fs, path::Path};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::{
+    engine::{Engine, EntryPoint},
+    error::Error,
+    program::{Header, Relocation},
+};
+
+/// Load segment of the first run, the second one is `SEGMENT_DELTA` higher
+const FIRST_SEGMENT: u64 = 0x1000;
+/// A whole number of 256 paragraphs so relocated words only differ in the high byte
+const SEGMENT_DELTA: u64 = 0x100;
+
+/// Executable compressors whose stubs we recognise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packer {
+    Lzexe,
+    Pklite,
+    Exepack,
+}
+
+impl Display for Packer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Packer::Lzexe => write!(f, "LZEXE"),
+            Packer::Pklite => write!(f, "PKLITE"),
+            Packer::Exepack => write!(f, "EXEPACK"),
+        }
+    }
+}
+
+/// Look for the marks the packers leave in the executable file
+pub fn detect(bytes: &[u8]) -> Option<Packer> {
+    let header = Header::new(bytes).ok()?;
+
+    // LZEXE puts its version right after the fixed part of the header
+    if matches!(bytes.get(0x1c..0x20), Some(b"LZ09" | b"LZ91")) {
+        return Some(Packer::Lzexe);
+    }
+
+    // PKLITE has its copyright in the header, with varying case
+    let start = bytes.len().min(0x1c);
+    let end = bytes.len().min(0x60).min(header.header_len());
+    if bytes[start..end]
+        .windows(6)
+        .any(|window| window.eq_ignore_ascii_case(b"PKLITE"))
+    {
+        return Some(Packer::Pklite);
+    }
+
+    // EXEPACK starts the entry segment with its own header ending in "RB",
+    // the stub code follows it
+    let entry = header.header_len() + header.initial_cs as usize * 16;
+    let signature = entry + header.initial_ip as usize - 2;
+    if header.initial_ip >= 2 && bytes.get(signature..signature + 2) == Some(b"RB") {
+        return Some(Packer::Exepack);
+    }
+
+    None
+}
+
+/// Words that differ by the load segment delta between two images of the same
+/// program loaded at different segments are the ones that need relocating
+pub fn find_relocations(first: &[u8], second: &[u8], delta: u16) -> Vec<Relocation> {
+    let mut relocations = Vec::new();
+    let mut addr = 0;
+    while addr + 1 < first.len().min(second.len()) {
+        let a = LittleEndian::read_u16(&first[addr..]);
+        let b = LittleEndian::read_u16(&second[addr..]);
+        if b.wrapping_sub(a) == delta && a != b {
+            relocations.push(Relocation {
+                offset: (addr & 0xf) as u16,
+                segment: (addr >> 4) as u16,
+            });
+            addr += 2;
+        } else {
+            addr += 1;
+        }
+    }
+    relocations
+}
+
+/// Segment and offset of a far pointer in the executable
+type SegmentOffset = (u16, u16);
+
+/// What `unpack` found and wrote, the caller decides how to report it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unpacked {
+    pub packer: Packer,
+    /// Bytes of unpacked code in the executable
+    pub image_len: usize,
+    pub relocations: usize,
+    /// CS:IP relative to the load segment
+    pub entry: SegmentOffset,
+}
+
+/// Entry point and stack of the unpacked program relative to the first load segment,
+/// the code can't start below the program
+fn relative_entry(entry: &EntryPoint) -> Result<(SegmentOffset, SegmentOffset), Error> {
+    if entry.cs < FIRST_SEGMENT {
+        return Err(Error::Unpack(format!(
+            "the original entry point {:04x}:{:04x} is below the load segment {FIRST_SEGMENT:04x}",
+            entry.cs, entry.ip
+        )));
+    }
+    Ok((
+        ((entry.cs - FIRST_SEGMENT) as u16, entry.ip as u16),
+        (entry.ss.wrapping_sub(FIRST_SEGMENT) as u16, entry.sp as u16),
+    ))
+}
+
+/// Run the stub and copy the unpacked image out of memory, trailing zeros are
+/// left to the minimum allocation like the linker would
+fn unpacked_image(engine: &mut Engine) -> Result<(EntryPoint, Vec<u8>), Error> {
+    let start = engine.program().start() * 16;
+    let Some(entry) = engine.run_to_entry()? else {
+        return Err(Error::Unpack(
+            "the stub stopped before jumping to the original entry point".into(),
+        ));
+    };
+    let mut image = engine.read_memory(start, (entry.stub - start) as usize)?;
+    let len = image
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    image.truncate(len);
+    Ok((entry, image))
+}
+
+/// Unpack the program twice at different load segments and write an executable
+/// with the original entry point and a relocation table rebuilt from the differences
+pub fn unpack<'a>(
+    mut load: impl FnMut(u64) -> Result<Engine<'a>, Error>,
+    output: &Path,
+) -> Result<Unpacked, Error> {
+    let mut first = load(FIRST_SEGMENT)?;
+    let program = first.program();
+    let packer = detect(&fs::read(program.path())?).ok_or_else(|| {
+        Error::Unpack(format!(
+            "no LZEXE, PKLITE or EXEPACK stub in '{}'",
+            program.path().display()
+        ))
+    })?;
+
+    let (entry, image) = unpacked_image(&mut first)?;
+    let (entry_point, stack) = relative_entry(&entry)?;
+    let (second_entry, second_image) = unpacked_image(&mut load(FIRST_SEGMENT + SEGMENT_DELTA)?)?;
+    if second_entry.cs.wrapping_sub(SEGMENT_DELTA) != entry.cs
+        || second_entry.ip != entry.ip
+        || second_image.len() != image.len()
+    {
+        return Err(Error::Unpack(
+            "the stub unpacks differently depending on the load segment".into(),
+        ));
+    }
+
+    let relocations = find_relocations(&image, &second_image, SEGMENT_DELTA as u16);
+    let relocation_count = relocations.len();
+    // Keep the memory the packed program asked for
+    let min_allocation = program
+        .paragraphs()
+        .saturating_sub(image.len().div_ceil(16) as u32)
+        .min(0xffff) as u16;
+    let header = Header::for_image(image.len(), relocations, entry_point, stack, min_allocation);
+
+    let mut exe = header.to_bytes();
+    exe.extend_from_slice(&image);
+    fs::write(output, exe)?;
+    Ok(Unpacked {
+        packer,
+        image_len: image.len(),
+        relocations: relocation_count,
+        entry: entry_point,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::{
+        engine::EntryPoint,
+        error::Error,
+        program::Relocation,
+        unpack::{Packer, detect, find_relocations, relative_entry},
+    };
+
+    fn header(initial_cs: u16, initial_ip: u16) -> Vec<u8> {
+        let mut exe = vec![0u8; 0x200];
+        exe[0..2].copy_from_slice(b"MZ");
+        LittleEndian::write_u16(&mut exe[4..6], 1);
+        LittleEndian::write_u16(&mut exe[8..10], 4);
+        LittleEndian::write_u16(&mut exe[20..22], initial_ip);
+        LittleEndian::write_u16(&mut exe[22..24], initial_cs);
+        exe
+    }
+
+    #[test]
+    fn detect_packers() {
+        let plain = header(0, 0);
+        assert_eq!(detect(&plain), None);
+
+        let mut lzexe = plain.clone();
+        lzexe[0x1c..0x20].copy_from_slice(b"LZ91");
+        assert_eq!(detect(&lzexe), Some(Packer::Lzexe));
+
+        let mut pklite = plain.clone();
+        pklite[0x1e..0x2a].copy_from_slice(b"PKlite Copr.");
+        assert_eq!(detect(&pklite), Some(Packer::Pklite));
+
+        // Header is 0x40 bytes, the entry segment starts 0x100 into the image
+        let mut exepack = header(0x10, 0x12);
+        exepack[0x40 + 0x100 + 0x10..0x40 + 0x100 + 0x12].copy_from_slice(b"RB");
+        assert_eq!(detect(&exepack), Some(Packer::Exepack));
+    }
+
+    #[test]
+    fn relocations_from_two_loads() {
+        let mut first = vec![0x90u8; 0x40];
+        let mut second = first.clone();
+        // Two relocations next to each other and one far away
+        for (addr, value) in [(0x04, 0x1000u16), (0x06, 0x10ff), (0x31, 0x1234)] {
+            LittleEndian::write_u16(&mut first[addr..], value);
+            LittleEndian::write_u16(&mut second[addr..], value + 0x100);
+        }
+        assert_eq!(
+            find_relocations(&first, &second, 0x100),
+            vec![
+                Relocation {
+                    offset: 0x4,
+                    segment: 0
+                },
+                Relocation {
+                    offset: 0x6,
+                    segment: 0
+                },
+                Relocation {
+                    offset: 0x1,
+                    segment: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn entry_relative_to_the_load_segment() {
+        let mut entry = EntryPoint {
+            cs: 0x1234,
+            ip: 0x10,
+            ss: 0x0ff0,
+            sp: 0x200,
+            stub: 0x20000,
+        };
+        assert_eq!(
+            relative_entry(&entry).unwrap(),
+            ((0x234, 0x10), (0xfff0, 0x200))
+        );
+
+        entry.cs = 0x0ff0;
+        assert!(matches!(relative_entry(&entry), Err(Error::Unpack(_))));
+    }
+}